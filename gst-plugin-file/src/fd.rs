@@ -0,0 +1,190 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::raw::{c_int, c_short};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+use gst_plugin_simple::error::*;
+use gst_plugin_simple::Unlocker;
+
+use gst;
+
+// Descriptor of an fd://N URI
+pub fn parse_uri(uri: &Url) -> Option<RawFd> {
+    if uri.scheme() != "fd" || (uri.path() != "" && uri.path() != "/") {
+        return None;
+    }
+
+    uri.host_str()
+        .and_then(|host| host.parse::<RawFd>().ok())
+        .and_then(|fd| if fd >= 0 { Some(fd) } else { None })
+}
+
+pub fn validate_uri(uri: &Url) -> Result<(), UriError> {
+    match parse_uri(uri) {
+        Some(_) => Ok(()),
+        None => Err(UriError::new(
+            gst::URIError::BadUri,
+            format!("Unsupported fd URI '{}'", uri.as_str()),
+        )),
+    }
+}
+
+pub fn build_uri(fd: RawFd) -> Option<Url> {
+    Url::parse(&format!("fd://{}", fd)).ok()
+}
+
+// Duplicates the descriptor so that closing the file leaves the original one, e.g. stdin
+// or stdout, open
+pub fn dup(fd: RawFd) -> io::Result<File> {
+    let file = unsafe { File::from_raw_fd(fd) };
+    let res = file.try_clone();
+    let _ = file.into_raw_fd();
+
+    res
+}
+
+// Current position if the descriptor is a regular file, pipes and sockets can't seek
+pub fn seekable_position(file: &mut File) -> Option<u64> {
+    match file.metadata() {
+        Ok(ref metadata) if metadata.file_type().is_file() => {
+            file.seek(SeekFrom::Current(0)).ok()
+        }
+        _ => None,
+    }
+}
+
+// Just enough of poll(2), pipe(2) and fcntl(2) for Poll
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short,
+}
+
+const POLLIN: c_short = 0x1;
+const POLLOUT: c_short = 0x4;
+
+const F_SETFD: c_int = 2;
+const FD_CLOEXEC: c_int = 1;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+type NfdsT = ::std::os::raw::c_ulong;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+type NfdsT = ::std::os::raw::c_uint;
+
+extern "C" {
+    fn poll(fds: *mut PollFd, nfds: NfdsT, timeout: c_int) -> c_int;
+    fn pipe(fds: *mut c_int) -> c_int;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+}
+
+// Amount of data that can be written to a pipe without blocking once it is writable
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const PIPE_BUF: usize = 4096;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub const PIPE_BUF: usize = 512;
+
+#[derive(Debug)]
+struct Control {
+    // Whether the wakeup byte was written and not read back yet
+    flushing: Mutex<bool>,
+    reader: File,
+    writer: File,
+}
+
+// Waits for a descriptor to become readable or writable together with a wakeup pipe, so that
+// unlocking can interrupt blocking on e.g. a pipe or a terminal. Like GstPoll in the C elements
+#[derive(Debug, Clone)]
+pub struct Poll(Arc<Control>);
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        let mut fds = [-1 as c_int; 2];
+        if unsafe { pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        // Otherwise the pipe would be inherited by child processes of the application.
+        // pipe2() would do this atomically but does not exist everywhere
+        for fd in &fds {
+            if unsafe { fcntl(*fd, F_SETFD, FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Poll(Arc::new(Control {
+            flushing: Mutex::new(false),
+            reader: reader,
+            writer: writer,
+        })))
+    }
+
+    // Returns Ok(false) if unlocked while waiting
+    pub fn wait_readable(&self, fd: RawFd) -> io::Result<bool> {
+        self.wait(fd, POLLIN)
+    }
+
+    // Returns Ok(false) if unlocked while waiting
+    pub fn wait_writable(&self, fd: RawFd) -> io::Result<bool> {
+        self.wait(fd, POLLOUT)
+    }
+
+    fn wait(&self, fd: RawFd, events: c_short) -> io::Result<bool> {
+        let mut fds = [
+            PollFd {
+                fd: fd,
+                events: events,
+                revents: 0,
+            },
+            PollFd {
+                fd: self.0.reader.as_raw_fd(),
+                events: POLLIN,
+                revents: 0,
+            },
+        ];
+
+        loop {
+            if unsafe { poll(fds.as_mut_ptr(), fds.len() as NfdsT, -1) } >= 0 {
+                break;
+            }
+
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+
+        // Errors and hangups of the descriptor are reported by the following read or write
+        Ok(fds[1].revents == 0)
+    }
+}
+
+impl Unlocker for Poll {
+    fn unlock(&self) {
+        let mut flushing = self.0.flushing.lock().unwrap();
+        if !*flushing {
+            *flushing = (&self.0.writer).write_all(&[0]).is_ok();
+        }
+    }
+
+    fn unlock_stop(&self) {
+        let mut flushing = self.0.flushing.lock().unwrap();
+        if *flushing {
+            let mut byte = [0];
+            let _ = (&self.0.reader).read_exact(&mut byte);
+            *flushing = false;
+        }
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::fs::File;
+use std::i32;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use url::Url;
+
+use gst_plugin::error::*;
+use gst_plugin_simple::error::*;
+use gst_plugin_simple::sink::*;
+use gst_plugin_simple::{Unlocker, UriValidator};
+
+use glib;
+use gst;
+use gst::prelude::*;
+
+use gobject_subclass::object::*;
+
+use fd;
+
+// stdout
+const DEFAULT_FD: i32 = 1;
+
+pub static PROPERTIES: [Property; 1] = [Property::Int(
+    "fd",
+    "File Descriptor",
+    "File descriptor to write to if no URI is set",
+    (0, i32::MAX),
+    DEFAULT_FD,
+    PropertyMutability::ReadWrite,
+)];
+
+#[derive(Debug)]
+enum StreamingState {
+    Stopped,
+    // Written directly without a Writer: pipes can't be synced, buffering would delay data for
+    // the reading process, and writes have to be split up to be interruptible
+    Started {
+        file: File,
+        position: u64,
+        // Position of the descriptor when starting if it is seekable, byte segments are
+        // relative to it
+        base: Option<u64>,
+    },
+}
+
+#[derive(Debug)]
+pub struct FdSink {
+    streaming_state: StreamingState,
+    cat: gst::DebugCategory,
+    fd: i32,
+    // Writes can't be interrupted without it, only missing if no pipe could be created
+    poll: Option<fd::Poll>,
+}
+
+impl FdSink {
+    pub fn new(_sink: &BaseSink) -> FdSink {
+        FdSink {
+            streaming_state: StreamingState::Stopped,
+            cat: gst::DebugCategory::new(
+                "rsfdsink",
+                gst::DebugColorFlags::empty(),
+                "Rust file descriptor sink",
+            ),
+            fd: DEFAULT_FD,
+            poll: fd::Poll::new().ok(),
+        }
+    }
+
+    pub fn new_boxed(sink: &BaseSink) -> Box<SinkImpl> {
+        Box::new(FdSink::new(sink))
+    }
+}
+
+impl SinkImpl for FdSink {
+    fn uri_validator(&self) -> Box<UriValidator> {
+        Box::new(fd::validate_uri)
+    }
+
+    fn unlocker(&self) -> Option<Box<Unlocker>> {
+        self.poll
+            .as_ref()
+            .map(|poll| Box::new(poll.clone()) as Box<Unlocker>)
+    }
+
+    fn default_uri(&self) -> Option<Url> {
+        fd::build_uri(self.fd)
+    }
+
+    fn start(&mut self, sink: &BaseSink, uri: Url) -> Result<(), gst::ErrorMessage> {
+        if let StreamingState::Started { .. } = self.streaming_state {
+            return Err(gst_error_msg!(
+                gst::LibraryError::Failed,
+                ["Sink already started"]
+            ));
+        }
+
+        let fd = try!(fd::parse_uri(&uri).ok_or_else(|| {
+            gst_error!(self.cat, obj: sink, "Unsupported fd URI '{}'", uri.as_str());
+            gst_error_msg!(
+                gst::LibraryError::Failed,
+                ["Unsupported fd URI '{}'", uri.as_str()]
+            )
+        }));
+
+        let mut file = try!(fd::dup(fd).or_else(|err| {
+            gst_error!(
+                self.cat,
+                obj: sink,
+                "Could not use file descriptor {}: {}",
+                fd,
+                err.to_string()
+            );
+            Err(gst_error_msg!(
+                gst::ResourceError::OpenWrite,
+                [
+                    "Could not use file descriptor {} for writing: {}",
+                    fd,
+                    err.to_string()
+                ]
+            ))
+        }));
+
+        let base = fd::seekable_position(&mut file);
+
+        gst_debug!(
+            self.cat,
+            obj: sink,
+            "Using file descriptor {} (seekable: {})",
+            fd,
+            base.is_some()
+        );
+
+        self.streaming_state = StreamingState::Started {
+            file: file,
+            position: 0,
+            base: base,
+        };
+
+        Ok(())
+    }
+
+    fn stop(&mut self, _sink: &BaseSink) -> Result<(), gst::ErrorMessage> {
+        self.streaming_state = StreamingState::Stopped;
+
+        Ok(())
+    }
+
+    fn render(&mut self, sink: &BaseSink, buffer: &gst::BufferRef) -> Result<(), FlowError> {
+        let cat = self.cat;
+        let poll = self.poll.clone();
+
+        gst_trace!(cat, obj: sink, "Rendering {:?}", buffer);
+
+        let (file, position, base) = match self.streaming_state {
+            StreamingState::Started {
+                ref mut file,
+                ref mut position,
+                base,
+            } => (file, position, base),
+            StreamingState::Stopped => {
+                return Err(FlowError::Error(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Not started yet"]
+                )));
+            }
+        };
+
+        let map = match buffer.map_readable() {
+            None => {
+                return Err(FlowError::Error(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Failed to map buffer"]
+                )));
+            }
+            Some(map) => map,
+        };
+
+        let mut data = map.as_slice();
+        while !data.is_empty() {
+            if let Some(ref poll) = poll {
+                match poll.wait_writable(file.as_raw_fd()) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        gst_debug!(cat, obj: sink, "Unlocked while waiting to write");
+                        return Err(FlowError::Flushing);
+                    }
+                    Err(err) => {
+                        gst_error!(cat, obj: sink, "Failed to wait for writing: {:?}", err);
+                        return Err(FlowError::Error(gst_error_msg!(
+                            gst::ResourceError::Write,
+                            ["Failed to wait for writing: {}", err]
+                        )));
+                    }
+                }
+            }
+
+            // Writing more than PIPE_BUF to a writable pipe could block again
+            let len = match base {
+                Some(_) => data.len(),
+                None => cmp::min(data.len(), fd::PIPE_BUF),
+            };
+
+            match file.write(&data[..len]) {
+                Ok(0) => {
+                    gst_error!(cat, obj: sink, "Failed to write any data");
+                    return Err(FlowError::Error(gst_error_msg!(
+                        gst::ResourceError::Write,
+                        ["Failed to write any data"]
+                    )));
+                }
+                Ok(written) => {
+                    data = &data[written..];
+                    *position += written as u64;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => {
+                    gst_error!(cat, obj: sink, "Failed to write: {}", err);
+                    return Err(FlowError::Error(gst_error_msg!(
+                        gst::ResourceError::Write,
+                        ["Failed to write: {}", err]
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn segment(
+        &mut self,
+        sink: &BaseSink,
+        segment: &gst::FormattedSegment<gst::format::Bytes>,
+    ) -> Result<(), gst::ErrorMessage> {
+        let cat = self.cat;
+
+        let (file, position, base) = match self.streaming_state {
+            StreamingState::Started {
+                ref mut file,
+                ref mut position,
+                base,
+            } => (file, position, base),
+            StreamingState::Stopped => {
+                return Err(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Not started yet"]
+                ));
+            }
+        };
+
+        let start = match segment.get_start().0 {
+            Some(start) if start != *position => start,
+            _ => return Ok(()),
+        };
+
+        // Upstream was told that seeking is not possible, so just continue writing
+        let base = match base {
+            Some(base) => base,
+            None => {
+                gst_warning!(cat, obj: sink, "Can't seek to {} in a pipe", start);
+                return Ok(());
+            }
+        };
+
+        gst_debug!(cat, obj: sink, "Seeking to {}", start);
+
+        try!(file.seek(SeekFrom::Start(base + start)).or_else(|err| {
+            gst_error!(cat, obj: sink, "Failed to seek to {}: {}", start, err);
+            Err(gst_error_msg!(
+                gst::ResourceError::Seek,
+                ["Failed to seek to {}: {}", start, err]
+            ))
+        }));
+        *position = start;
+
+        Ok(())
+    }
+
+    fn is_seekable(&self, _sink: &BaseSink) -> bool {
+        match self.streaming_state {
+            StreamingState::Started { base, .. } => base.is_some(),
+            StreamingState::Stopped => false,
+        }
+    }
+
+    fn get_position(&self, _sink: &BaseSink) -> Option<u64> {
+        match self.streaming_state {
+            StreamingState::Started { position, .. } => Some(position),
+            StreamingState::Stopped => None,
+        }
+    }
+
+    fn set_property(&mut self, _sink: &BaseSink, property: &Property, value: &glib::Value) {
+        match *property {
+            Property::Int("fd", ..) => {
+                self.fd = value.get().unwrap();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _sink: &BaseSink, property: &Property) -> Result<glib::Value, ()> {
+        match *property {
+            Property::Int("fd", ..) => Ok(self.fd.to_value()),
+            _ => unimplemented!(),
+        }
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs::File;
+use std::i32;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+use gst_plugin::error::*;
+use gst_plugin_simple::error::*;
+use gst_plugin_simple::source::*;
+use gst_plugin_simple::{PropertyHandler, Unlocker, UriValidator};
+
+use glib;
+use gst;
+use gst::prelude::*;
+
+use gobject_subclass::object::*;
+
+use fd;
+
+// stdin
+const DEFAULT_FD: i32 = 0;
+
+pub static PROPERTIES: [Property; 1] = [Property::Int(
+    "fd",
+    "File Descriptor",
+    "File descriptor to read from if no URI is set",
+    (0, i32::MAX),
+    DEFAULT_FD,
+    PropertyMutability::ReadWrite,
+)];
+
+#[derive(Debug)]
+enum StreamingState {
+    Stopped,
+    Started {
+        file: File,
+        position: u64,
+        // Position of the descriptor when starting if it is seekable, offsets are relative
+        // to it
+        base: Option<u64>,
+    },
+}
+
+// The fd property is kept outside the source so that it can be accessed while the source is
+// waiting for data
+#[derive(Debug, Clone)]
+struct SharedFd(Arc<Mutex<i32>>);
+
+impl PropertyHandler<BaseSrc> for SharedFd {
+    fn set_property(&self, _src: &BaseSrc, property: &Property, value: &glib::Value) {
+        match *property {
+            Property::Int("fd", ..) => {
+                *self.0.lock().unwrap() = value.get().unwrap();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _src: &BaseSrc, property: &Property) -> Result<glib::Value, ()> {
+        match *property {
+            Property::Int("fd", ..) => Ok(self.0.lock().unwrap().to_value()),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OpenFd {
+    // Duplicate of the descriptor the source reads from, for polling and the size
+    file: Arc<File>,
+    base: Option<u64>,
+}
+
+// Waits for data and answers queries without locking the source
+#[derive(Debug, Clone)]
+struct Stream {
+    cat: gst::DebugCategory,
+    fd: Arc<Mutex<Option<OpenFd>>>,
+    // Reads can't be interrupted without it, only missing if no pipe could be created
+    poll: Option<fd::Poll>,
+}
+
+impl StreamHandler for Stream {
+    fn wait(&self, src: &BaseSrc, offset: u64) -> Result<(), FlowError> {
+        let poll = match self.poll {
+            Some(ref poll) => poll,
+            None => return Ok(()),
+        };
+
+        // Not locked while polling, the descriptor stays open until it is done
+        let file = match *self.fd.lock().unwrap() {
+            Some(OpenFd { ref file, .. }) => file.clone(),
+            None => return Ok(()),
+        };
+
+        match poll.wait_readable(file.as_raw_fd()) {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                gst_debug!(self.cat, obj: src, "Unlocked while waiting for data");
+                Err(FlowError::Flushing)
+            }
+            Err(err) => {
+                gst_error!(self.cat, obj: src, "Failed to wait for data: {:?}", err);
+                Err(FlowError::Error(gst_error_msg!(
+                    gst::ResourceError::Read,
+                    ["Failed to wait for data at {}: {}", offset, err.to_string()]
+                )))
+            }
+        }
+    }
+
+    // Pull mode is only offered by the base class for seekable sources, so pipes are
+    // automatically push-only
+    fn is_seekable(&self, _src: &BaseSrc) -> bool {
+        match *self.fd.lock().unwrap() {
+            Some(OpenFd { base, .. }) => base.is_some(),
+            None => false,
+        }
+    }
+
+    fn get_size(&self, _src: &BaseSrc) -> Option<u64> {
+        match *self.fd.lock().unwrap() {
+            Some(OpenFd {
+                ref file,
+                base: Some(base),
+            }) => file.metadata()
+                .ok()
+                .map(|m| m.len().saturating_sub(base)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FdSrc {
+    streaming_state: StreamingState,
+    cat: gst::DebugCategory,
+    fd: SharedFd,
+    stream: Stream,
+}
+
+impl FdSrc {
+    pub fn new(_src: &BaseSrc) -> FdSrc {
+        let cat = gst::DebugCategory::new(
+            "rsfdsrc",
+            gst::DebugColorFlags::empty(),
+            "Rust file descriptor source",
+        );
+
+        FdSrc {
+            streaming_state: StreamingState::Stopped,
+            cat: cat,
+            fd: SharedFd(Arc::new(Mutex::new(DEFAULT_FD))),
+            stream: Stream {
+                cat: cat,
+                fd: Arc::new(Mutex::new(None)),
+                poll: fd::Poll::new().ok(),
+            },
+        }
+    }
+
+    pub fn new_boxed(src: &BaseSrc) -> Box<SourceImpl> {
+        Box::new(FdSrc::new(src))
+    }
+}
+
+impl SourceImpl for FdSrc {
+    fn uri_validator(&self) -> Box<UriValidator> {
+        Box::new(fd::validate_uri)
+    }
+
+    fn unlocker(&self) -> Option<Box<Unlocker>> {
+        self.stream
+            .poll
+            .as_ref()
+            .map(|poll| Box::new(poll.clone()) as Box<Unlocker>)
+    }
+
+    fn property_handler(&self) -> Option<Box<PropertyHandler<BaseSrc>>> {
+        Some(Box::new(self.fd.clone()))
+    }
+
+    fn stream_handler(&self) -> Option<Box<StreamHandler>> {
+        Some(Box::new(self.stream.clone()))
+    }
+
+    fn default_uri(&self) -> Option<Url> {
+        fd::build_uri(*self.fd.0.lock().unwrap())
+    }
+
+    fn is_seekable(&self, src: &BaseSrc) -> bool {
+        self.stream.is_seekable(src)
+    }
+
+    fn get_size(&self, src: &BaseSrc) -> Option<u64> {
+        self.stream.get_size(src)
+    }
+
+    fn start(&mut self, src: &BaseSrc, uri: Url) -> Result<(), gst::ErrorMessage> {
+        if let StreamingState::Started { .. } = self.streaming_state {
+            return Err(gst_error_msg!(
+                gst::LibraryError::Failed,
+                ["Source already started"]
+            ));
+        }
+
+        let fd = try!(fd::parse_uri(&uri).ok_or_else(|| {
+            gst_error!(self.cat, obj: src, "Unsupported fd URI '{}'", uri.as_str());
+            gst_error_msg!(
+                gst::LibraryError::Failed,
+                ["Unsupported fd URI '{}'", uri.as_str()]
+            )
+        }));
+
+        let mut file = try!(fd::dup(fd).or_else(|err| {
+            gst_error!(
+                self.cat,
+                obj: src,
+                "Could not use file descriptor {}: {}",
+                fd,
+                err.to_string()
+            );
+            Err(gst_error_msg!(
+                gst::ResourceError::OpenRead,
+                [
+                    "Could not use file descriptor {} for reading: {}",
+                    fd,
+                    err.to_string()
+                ]
+            ))
+        }));
+
+        let base = fd::seekable_position(&mut file);
+
+        let shared_file = try!(file.try_clone().or_else(|err| {
+            gst_error!(self.cat, obj: src, "Could not duplicate file descriptor: {}", err);
+            Err(gst_error_msg!(
+                gst::ResourceError::OpenRead,
+                ["Could not duplicate file descriptor {}: {}", fd, err.to_string()]
+            ))
+        }));
+
+        gst_debug!(
+            self.cat,
+            obj: src,
+            "Using file descriptor {} (seekable: {})",
+            fd,
+            base.is_some()
+        );
+
+        self.streaming_state = StreamingState::Started {
+            file: file,
+            position: 0,
+            base: base,
+        };
+
+        *self.stream.fd.lock().unwrap() = Some(OpenFd {
+            file: Arc::new(shared_file),
+            base: base,
+        });
+
+        Ok(())
+    }
+
+    fn stop(&mut self, _src: &BaseSrc) -> Result<(), gst::ErrorMessage> {
+        self.streaming_state = StreamingState::Stopped;
+        *self.stream.fd.lock().unwrap() = None;
+
+        Ok(())
+    }
+
+    fn fill(
+        &mut self,
+        src: &BaseSrc,
+        offset: u64,
+        _: u32,
+        buffer: &mut gst::BufferRef,
+    ) -> Result<(), FlowError> {
+        let cat = self.cat;
+
+        let (file, position, base) = match self.streaming_state {
+            StreamingState::Started {
+                ref mut file,
+                ref mut position,
+                base,
+            } => (file, position, base),
+            StreamingState::Stopped => {
+                return Err(FlowError::Error(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Not started yet"]
+                )));
+            }
+        };
+
+        if *position != offset {
+            let base = try!(base.ok_or_else(|| {
+                gst_error!(cat, obj: src, "Can't seek to {} in a pipe", offset);
+                FlowError::Error(gst_error_msg!(
+                    gst::ResourceError::Seek,
+                    ["Can't seek to {} in a non-seekable file descriptor", offset]
+                ))
+            }));
+
+            try!(file.seek(SeekFrom::Start(base + offset)).or_else(|err| {
+                gst_error!(cat, obj: src, "Failed to seek to {}: {:?}", offset, err);
+                Err(FlowError::Error(gst_error_msg!(
+                    gst::ResourceError::Seek,
+                    ["Failed to seek to {}: {}", offset, err.to_string()]
+                )))
+            }));
+            *position = offset;
+        }
+
+        let size = {
+            let mut map = match buffer.map_writable() {
+                None => {
+                    return Err(FlowError::Error(gst_error_msg!(
+                        gst::LibraryError::Failed,
+                        ["Failed to map buffer"]
+                    )));
+                }
+                Some(map) => map,
+            };
+
+            let data = map.as_mut_slice();
+
+            // Data is available after Stream::wait(), so this does not block
+            loop {
+                match file.read(data) {
+                    Ok(size) => break size,
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        gst_error!(cat, obj: src, "Failed to read: {:?}", err);
+                        return Err(FlowError::Error(gst_error_msg!(
+                            gst::ResourceError::Read,
+                            ["Failed to read at {}: {}", offset, err.to_string()]
+                        )));
+                    }
+                }
+            }
+        };
+
+        // Without a size the base class can't know where the stream ends
+        if size == 0 {
+            gst_debug!(cat, obj: src, "End of file at {}", offset);
+            return Err(FlowError::Eos);
+        }
+
+        *position += size as u64;
+
+        buffer.set_size(size);
+
+        Ok(())
+    }
+
+    fn seek(&mut self, _src: &BaseSrc, _: u64, _: Option<u64>) -> Result<(), gst::ErrorMessage> {
+        Ok(())
+    }
+}
@@ -7,28 +7,161 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::{u32, u64};
 use url::Url;
 
-use std::io::Write;
-
 use gst_plugin::error::*;
 use gst_plugin_simple::error::*;
 use gst_plugin_simple::sink::*;
 use gst_plugin_simple::UriValidator;
 
+use glib;
 use gst;
+use gst::prelude::*;
+
+use gobject_subclass::object::*;
+
+use writer::{BufferMode, FsyncMode, Writer, WriterSettings};
+
+const DEFAULT_APPEND: bool = false;
+const DEFAULT_BUFFER_MODE: BufferMode = BufferMode::Unbuffered;
+const DEFAULT_BUFFER_SIZE: u32 = 65536;
+const DEFAULT_FSYNC_MODE: FsyncMode = FsyncMode::Never;
+const DEFAULT_FSYNC_BYTES: u64 = 0;
+const DEFAULT_FSYNC_INTERVAL: u32 = 0;
+const DEFAULT_ASYNC_WRITE: bool = false;
+const DEFAULT_ATOMIC: bool = false;
+const DEFAULT_KEEP_PARTIAL: bool = false;
+
+pub static PROPERTIES: [Property; 9] = [
+    Property::Boolean(
+        "append",
+        "Append",
+        "Append to an already existing file",
+        DEFAULT_APPEND,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "buffer-mode",
+        "Buffering Mode",
+        "Buffering mode for writing (unbuffered, full, line)",
+        Some("unbuffered"),
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "buffer-size",
+        "Buffering Size",
+        "Size of the buffer in bytes for full and line buffering",
+        (1, u32::MAX),
+        DEFAULT_BUFFER_SIZE,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "fsync",
+        "Fsync",
+        "When to sync the file to disk (never, eos, periodic)",
+        Some("never"),
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt64(
+        "fsync-bytes",
+        "Fsync Bytes",
+        "Sync after this many bytes in periodic mode (0 = disabled)",
+        (0, u64::MAX),
+        DEFAULT_FSYNC_BYTES,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "fsync-interval",
+        "Fsync Interval",
+        "Sync after this many seconds in periodic mode (0 = disabled)",
+        (0, u32::MAX),
+        DEFAULT_FSYNC_INTERVAL,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "async-write",
+        "Asynchronous Write",
+        "Write from a separate thread with a bounded queue",
+        DEFAULT_ASYNC_WRITE,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "atomic",
+        "Atomic",
+        "Write into a temporary file that is synced and renamed to the location on EOS",
+        DEFAULT_ATOMIC,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "keep-partial",
+        "Keep Partial",
+        "Keep the temporary file if stopped before EOS in atomic mode",
+        DEFAULT_KEEP_PARTIAL,
+        PropertyMutability::ReadWrite,
+    ),
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    append: bool,
+    atomic: bool,
+    keep_partial: bool,
+    writer: WriterSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            append: DEFAULT_APPEND,
+            atomic: DEFAULT_ATOMIC,
+            keep_partial: DEFAULT_KEEP_PARTIAL,
+            writer: WriterSettings {
+                buffer_mode: DEFAULT_BUFFER_MODE,
+                buffer_size: DEFAULT_BUFFER_SIZE as usize,
+                fsync_mode: DEFAULT_FSYNC_MODE,
+                fsync_bytes: DEFAULT_FSYNC_BYTES,
+                fsync_interval: DEFAULT_FSYNC_INTERVAL,
+                async_write: DEFAULT_ASYNC_WRITE,
+            },
+        }
+    }
+}
 
 #[derive(Debug)]
 enum StreamingState {
     Stopped,
-    Started { file: File, position: u64 },
+    Started {
+        writer: Writer,
+        position: u64,
+        // Size of the file before appending, byte segments are relative to it
+        base: u64,
+        location: PathBuf,
+        // Temporary file in atomic mode, None once renamed to the location
+        temp: Option<PathBuf>,
+    },
+}
+
+// Hidden file next to the location, so that nothing picks it up by its name or extension
+fn temp_path(location: &Path) -> PathBuf {
+    let name = location
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    location.with_file_name(format!(".{}.{}.part", name, process::id()))
 }
 
 #[derive(Debug)]
 pub struct FileSink {
     streaming_state: StreamingState,
     cat: gst::DebugCategory,
+    settings: Settings,
 }
 
 impl FileSink {
@@ -40,6 +173,7 @@ impl FileSink {
                 gst::DebugColorFlags::empty(),
                 "Rust file source",
             ),
+            settings: Settings::default(),
         }
     }
 
@@ -82,35 +216,116 @@ impl SinkImpl for FileSink {
             ))
         }));
 
-        let file = try!(File::create(location.as_path()).or_else(|err| {
-            gst_error!(
-                self.cat,
-                obj: sink,
-                "Could not open file for writing: {}",
-                err.to_string()
-            );
-            Err(gst_error_msg!(
-                gst::ResourceError::OpenWrite,
-                [
-                    "Could not open file for writing '{}': {}",
-                    location.to_str().unwrap_or("Non-UTF8 path"),
-                    err.to_string(),
-                ]
-            ))
-        }));
+        let append = self.settings.append;
+        let temp = if self.settings.atomic {
+            Some(temp_path(&location))
+        } else {
+            None
+        };
 
-        gst_debug!(self.cat, obj: sink, "Opened file {:?}", file);
+        // Appending starts from a copy of the existing file
+        if let Some(ref temp) = temp {
+            if append && location.exists() {
+                try!(fs::copy(&location, temp).or_else(|err| {
+                    gst_error!(
+                        self.cat,
+                        obj: sink,
+                        "Could not copy file for appending: {}",
+                        err.to_string()
+                    );
+                    Err(gst_error_msg!(
+                        gst::ResourceError::OpenWrite,
+                        [
+                            "Could not copy '{}' for appending: {}",
+                            location.to_str().unwrap_or("Non-UTF8 path"),
+                            err.to_string(),
+                        ]
+                    ))
+                }));
+            }
+        }
+
+        let path = temp.as_ref().unwrap_or(&location).clone();
+        let mut file = try!(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(!append)
+                .open(path.as_path())
+                .and_then(|mut file| if append {
+                    file.seek(SeekFrom::End(0)).map(|_| file)
+                } else {
+                    Ok(file)
+                })
+                .or_else(|err| {
+                    gst_error!(
+                        self.cat,
+                        obj: sink,
+                        "Could not open file for writing: {}",
+                        err.to_string()
+                    );
+                    Err(gst_error_msg!(
+                        gst::ResourceError::OpenWrite,
+                        [
+                            "Could not open file for writing '{}': {}",
+                            path.to_str().unwrap_or("Non-UTF8 path"),
+                            err.to_string(),
+                        ]
+                    ))
+                })
+        );
+
+        let base = if append {
+            file.seek(SeekFrom::Current(0)).unwrap_or(0)
+        } else {
+            0
+        };
+
+        gst_debug!(self.cat, obj: sink, "Opened file {:?} at {}", file, base);
+
+        // Otherwise the location could point at an empty or partial file after a power cut
+        let mut writer_settings = self.settings.writer;
+        if temp.is_some() && writer_settings.fsync_mode == FsyncMode::Never {
+            writer_settings.fsync_mode = FsyncMode::Eos;
+        }
 
         self.streaming_state = StreamingState::Started {
-            file: file,
+            writer: Writer::new(file, &writer_settings),
             position: 0,
+            base: base,
+            location: location,
+            temp: temp,
         };
 
         Ok(())
     }
 
-    fn stop(&mut self, _sink: &BaseSink) -> Result<(), gst::ErrorMessage> {
-        self.streaming_state = StreamingState::Stopped;
+    fn stop(&mut self, sink: &BaseSink) -> Result<(), gst::ErrorMessage> {
+        let streaming_state = mem::replace(&mut self.streaming_state, StreamingState::Stopped);
+
+        if let StreamingState::Started { writer, temp, .. } = streaming_state {
+            let res = writer.close();
+
+            // Not renamed yet, so there was no EOS
+            if let Some(temp) = temp {
+                if self.settings.keep_partial {
+                    gst_warning!(self.cat, obj: sink, "Keeping partial file {:?}", temp);
+                } else {
+                    gst_debug!(self.cat, obj: sink, "Removing partial file {:?}", temp);
+                    if let Err(err) = fs::remove_file(&temp) {
+                        gst_warning!(self.cat, obj: sink, "Failed to remove {:?}: {}", temp, err);
+                    }
+                }
+            }
+
+            try!(res.or_else(|err| {
+                gst_error!(self.cat, obj: sink, "Failed to close: {}", err);
+                Err(gst_error_msg!(
+                    gst::ResourceError::Close,
+                    ["Failed to close: {}", err]
+                ))
+            }));
+        }
 
         Ok(())
     }
@@ -121,11 +336,12 @@ impl SinkImpl for FileSink {
 
         gst_trace!(cat, obj: sink, "Rendering {:?}", buffer);
 
-        let (file, position) = match *streaming_state {
+        let (writer, position) = match *streaming_state {
             StreamingState::Started {
-                ref mut file,
+                ref mut writer,
                 ref mut position,
-            } => (file, position),
+                ..
+            } => (writer, position),
             StreamingState::Stopped => {
                 return Err(FlowError::Error(gst_error_msg!(
                     gst::LibraryError::Failed,
@@ -134,18 +350,7 @@ impl SinkImpl for FileSink {
             }
         };
 
-        let map = match buffer.map_readable() {
-            None => {
-                return Err(FlowError::Error(gst_error_msg!(
-                    gst::LibraryError::Failed,
-                    ["Failed to map buffer"]
-                )));
-            }
-            Some(map) => map,
-        };
-        let data = map.as_slice();
-
-        try!(file.write_all(data).or_else(|err| {
+        try!(writer.write(buffer).or_else(|err| {
             gst_error!(cat, obj: sink, "Failed to write: {}", err);
             Err(FlowError::Error(gst_error_msg!(
                 gst::ResourceError::Write,
@@ -153,8 +358,178 @@ impl SinkImpl for FileSink {
             )))
         }));
 
-        *position += data.len() as u64;
+        *position += buffer.get_size() as u64;
+
+        Ok(())
+    }
+
+    fn eos(&mut self, sink: &BaseSink) -> Result<(), gst::ErrorMessage> {
+        let cat = self.cat;
+
+        if let StreamingState::Started {
+            ref mut writer,
+            ref location,
+            ref mut temp,
+            ..
+        } = self.streaming_state
+        {
+            gst_debug!(cat, obj: sink, "Flushing on EOS");
+
+            try!(writer.finish().or_else(|err| {
+                gst_error!(cat, obj: sink, "Failed to flush: {}", err);
+                Err(gst_error_msg!(
+                    gst::ResourceError::Sync,
+                    ["Failed to flush: {}", err]
+                ))
+            }));
+
+            if let Some(path) = temp.take() {
+                gst_debug!(cat, obj: sink, "Renaming {:?} to {:?}", path, location);
+
+                // The temporary file is left behind for stop to handle
+                let res = fs::rename(&path, location);
+                if let Err(err) = res {
+                    gst_error!(cat, obj: sink, "Failed to rename: {}", err);
+                    *temp = Some(path);
+                    return Err(gst_error_msg!(
+                        gst::ResourceError::Write,
+                        [
+                            "Failed to rename to '{}': {}",
+                            location.to_str().unwrap_or("Non-UTF8 path"),
+                            err.to_string(),
+                        ]
+                    ));
+                }
+
+                // Makes the rename itself survive a power cut
+                let dir = match location.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                try!(File::open(dir).and_then(|file| file.sync_all()).or_else(|err| {
+                    gst_error!(cat, obj: sink, "Failed to sync directory {:?}: {}", dir, err);
+                    Err(gst_error_msg!(
+                        gst::ResourceError::Sync,
+                        [
+                            "Failed to sync directory '{}': {}",
+                            dir.to_str().unwrap_or("Non-UTF8 path"),
+                            err.to_string(),
+                        ]
+                    ))
+                }));
+            }
+        }
 
         Ok(())
     }
+
+    fn segment(
+        &mut self,
+        sink: &BaseSink,
+        segment: &gst::FormattedSegment<gst::format::Bytes>,
+    ) -> Result<(), gst::ErrorMessage> {
+        let cat = self.cat;
+
+        let (writer, position, base) = match self.streaming_state {
+            StreamingState::Started {
+                ref mut writer,
+                ref mut position,
+                base,
+                ..
+            } => (writer, position, base),
+            StreamingState::Stopped => {
+                return Err(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Not started yet"]
+                ));
+            }
+        };
+
+        let start = match segment.get_start().0 {
+            Some(start) if start != *position => start,
+            _ => return Ok(()),
+        };
+
+        gst_debug!(cat, obj: sink, "Seeking to {}", start);
+
+        try!(writer.seek(base + start).or_else(|err| {
+            gst_error!(cat, obj: sink, "Failed to seek to {}: {}", start, err);
+            Err(gst_error_msg!(
+                gst::ResourceError::Seek,
+                ["Failed to seek to {}: {}", start, err]
+            ))
+        }));
+        *position = start;
+
+        Ok(())
+    }
+
+    fn is_seekable(&self, _sink: &BaseSink) -> bool {
+        true
+    }
+
+    fn get_position(&self, _sink: &BaseSink) -> Option<u64> {
+        match self.streaming_state {
+            StreamingState::Started { position, .. } => Some(position),
+            StreamingState::Stopped => None,
+        }
+    }
+
+    fn set_property(&mut self, sink: &BaseSink, property: &Property, value: &glib::Value) {
+        match *property {
+            Property::Boolean("append", ..) => {
+                self.settings.append = value.get().unwrap();
+            }
+            Property::String("buffer-mode", ..) => {
+                let mode = value.get::<String>().unwrap_or_else(|| "unbuffered".into());
+                match BufferMode::from_str(&mode) {
+                    Some(mode) => self.settings.writer.buffer_mode = mode,
+                    None => gst_warning!(self.cat, obj: sink, "Invalid buffer mode {}", mode),
+                }
+            }
+            Property::UInt("buffer-size", ..) => {
+                self.settings.writer.buffer_size = value.get::<u32>().unwrap() as usize;
+            }
+            Property::String("fsync", ..) => {
+                let mode = value.get::<String>().unwrap_or_else(|| "never".into());
+                match FsyncMode::from_str(&mode) {
+                    Some(mode) => self.settings.writer.fsync_mode = mode,
+                    None => gst_warning!(self.cat, obj: sink, "Invalid fsync mode {}", mode),
+                }
+            }
+            Property::UInt64("fsync-bytes", ..) => {
+                self.settings.writer.fsync_bytes = value.get().unwrap();
+            }
+            Property::UInt("fsync-interval", ..) => {
+                self.settings.writer.fsync_interval = value.get().unwrap();
+            }
+            Property::Boolean("async-write", ..) => {
+                self.settings.writer.async_write = value.get().unwrap();
+            }
+            Property::Boolean("atomic", ..) => {
+                self.settings.atomic = value.get().unwrap();
+            }
+            Property::Boolean("keep-partial", ..) => {
+                self.settings.keep_partial = value.get().unwrap();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _sink: &BaseSink, property: &Property) -> Result<glib::Value, ()> {
+        let settings = &self.settings.writer;
+
+        match *property {
+            Property::Boolean("append", ..) => Ok(self.settings.append.to_value()),
+            Property::String("buffer-mode", ..) => Ok(settings.buffer_mode.as_str().to_value()),
+            Property::UInt("buffer-size", ..) => Ok((settings.buffer_size as u32).to_value()),
+            Property::String("fsync", ..) => Ok(settings.fsync_mode.as_str().to_value()),
+            Property::UInt64("fsync-bytes", ..) => Ok(settings.fsync_bytes.to_value()),
+            Property::UInt("fsync-interval", ..) => Ok(settings.fsync_interval.to_value()),
+            Property::Boolean("async-write", ..) => Ok(settings.async_write.to_value()),
+            Property::Boolean("atomic", ..) => Ok(self.settings.atomic.to_value()),
+            Property::Boolean("keep-partial", ..) => Ok(self.settings.keep_partial.to_value()),
+            _ => unimplemented!(),
+        }
+    }
 }
@@ -6,39 +6,282 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use memmap::Mmap;
+use std::cmp;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
-use std::u64;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::{u32, u64};
 use url::Url;
 
 use gst_plugin::error::*;
 use gst_plugin_simple::error::*;
 use gst_plugin_simple::source::*;
-use gst_plugin_simple::UriValidator;
+use gst_plugin_simple::{PropertyHandler, Unlocker, UriValidator};
 
+use glib;
 use gst;
+use gst::prelude::*;
+
+use gobject_subclass::object::*;
+
+const DEFAULT_USE_MMAP: bool = false;
+const DEFAULT_FOLLOW: bool = false;
+const DEFAULT_FOLLOW_INTERVAL: u32 = 100;
+const DEFAULT_FOLLOW_TIMEOUT: u32 = 0;
+
+pub static PROPERTIES: [Property; 4] = [
+    Property::Boolean(
+        "use-mmap",
+        "Use mmap",
+        "Map the file into memory and output buffers pointing into the mapping. \
+         Only safe for files that nobody truncates while they are mapped: accessing \
+         pages past the new end of the file raises SIGBUS and kills the process",
+        DEFAULT_USE_MMAP,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "follow",
+        "Follow",
+        "Wait for the file to grow at its end instead of stopping (disables mmap)",
+        DEFAULT_FOLLOW,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "follow-interval",
+        "Follow Interval",
+        "Interval in milliseconds for checking if the file has grown",
+        (1, u32::MAX),
+        DEFAULT_FOLLOW_INTERVAL,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "follow-timeout",
+        "Follow Timeout",
+        "Stop after the file has not grown for this many milliseconds (0 = never)",
+        (0, u32::MAX),
+        DEFAULT_FOLLOW_TIMEOUT,
+        PropertyMutability::ReadWrite,
+    ),
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    use_mmap: bool,
+    follow: bool,
+    follow_interval: u32,
+    follow_timeout: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            use_mmap: DEFAULT_USE_MMAP,
+            follow: DEFAULT_FOLLOW,
+            follow_interval: DEFAULT_FOLLOW_INTERVAL,
+            follow_timeout: DEFAULT_FOLLOW_TIMEOUT,
+        }
+    }
+}
+
+// Interrupts waiting for the file to grow when unlocking
+#[derive(Debug, Default, Clone)]
+struct Waiter(Arc<(Mutex<bool>, Condvar)>);
+
+impl Waiter {
+    // Returns false if unlocked while waiting
+    fn wait(&self, timeout: Duration) -> bool {
+        let &(ref lock, ref cond) = &*self.0;
+        let deadline = Instant::now() + timeout;
+
+        let mut flushing = lock.lock().unwrap();
+        loop {
+            if *flushing {
+                return false;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            flushing = cond.wait_timeout(flushing, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl Unlocker for Waiter {
+    fn unlock(&self) {
+        let &(ref lock, ref cond) = &*self.0;
+        *lock.lock().unwrap() = true;
+        cond.notify_all();
+    }
+
+    fn unlock_stop(&self) {
+        *(self.0).0.lock().unwrap() = false;
+    }
+}
+
+// Properties are kept outside the source so that they can be accessed while it is waiting
+// for the file to grow, they are taken over when starting
+#[derive(Debug, Default, Clone)]
+struct SharedSettings(Arc<Mutex<Settings>>);
+
+impl PropertyHandler<BaseSrc> for SharedSettings {
+    fn set_property(&self, _src: &BaseSrc, property: &Property, value: &glib::Value) {
+        let mut settings = self.0.lock().unwrap();
+
+        match *property {
+            Property::Boolean("use-mmap", ..) => {
+                settings.use_mmap = value.get().unwrap();
+            }
+            Property::Boolean("follow", ..) => {
+                settings.follow = value.get().unwrap();
+            }
+            Property::UInt("follow-interval", ..) => {
+                settings.follow_interval = value.get().unwrap();
+            }
+            Property::UInt("follow-timeout", ..) => {
+                settings.follow_timeout = value.get().unwrap();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _src: &BaseSrc, property: &Property) -> Result<glib::Value, ()> {
+        let settings = self.0.lock().unwrap();
+
+        match *property {
+            Property::Boolean("use-mmap", ..) => Ok(settings.use_mmap.to_value()),
+            Property::Boolean("follow", ..) => Ok(settings.follow.to_value()),
+            Property::UInt("follow-interval", ..) => Ok(settings.follow_interval.to_value()),
+            Property::UInt("follow-timeout", ..) => Ok(settings.follow_timeout.to_value()),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OpenFile {
+    // Duplicate of the descriptor the source reads from, only used for the size
+    file: File,
+    // Still waiting for the file to grow, the size is not final
+    following: bool,
+    follow_interval: u32,
+    follow_timeout: u32,
+}
+
+// Waits for the file to grow and answers queries without locking the source
+#[derive(Debug, Clone)]
+struct Follower {
+    cat: gst::DebugCategory,
+    file: Arc<Mutex<Option<OpenFile>>>,
+    waiter: Waiter,
+}
+
+impl StreamHandler for Follower {
+    fn wait(&self, src: &BaseSrc, offset: u64) -> Result<(), FlowError> {
+        // Start of waiting for the file to grow
+        let mut waiting_since = None;
+
+        loop {
+            let interval = {
+                let mut file = self.file.lock().unwrap();
+                let file = match *file {
+                    Some(ref mut file) if file.following => file,
+                    _ => return Ok(()),
+                };
+
+                // Errors are reported by the read in fill()
+                match file.file.metadata() {
+                    Ok(ref metadata) if metadata.len() <= offset => (),
+                    _ => return Ok(()),
+                }
+
+                let since = *waiting_since.get_or_insert_with(Instant::now);
+                if file.follow_timeout > 0
+                    && since.elapsed() >= Duration::from_millis(file.follow_timeout as u64)
+                {
+                    gst_debug!(self.cat, obj: src, "File did not grow anymore, stopping");
+                    file.following = false;
+                    return Err(FlowError::Eos);
+                }
+
+                file.follow_interval
+            };
+
+            if !self.waiter.wait(Duration::from_millis(interval as u64)) {
+                gst_debug!(self.cat, obj: src, "Flushing");
+                return Err(FlowError::Flushing);
+            }
+        }
+    }
+
+    fn is_seekable(&self, _src: &BaseSrc) -> bool {
+        true
+    }
+
+    fn get_size(&self, _src: &BaseSrc) -> Option<u64> {
+        match *self.file.lock().unwrap() {
+            // Otherwise the base class would stop at the current size without calling fill
+            Some(OpenFile {
+                following: true, ..
+            }) => None,
+            Some(OpenFile { ref file, .. }) => file.metadata().ok().map(|m| m.len()),
+            None => None,
+        }
+    }
+}
 
 #[derive(Debug)]
 enum StreamingState {
     Stopped,
-    Started { file: File, position: u64 },
+    Started {
+        file: File,
+        position: u64,
+        // Shared with all buffers pointing into it
+        mmap: Option<Arc<Mmap>>,
+    },
+}
+
+// Part of the mapping, keeps it alive as long as the buffer exists
+struct MappedSlice {
+    mmap: Arc<Mmap>,
+    offset: usize,
+    size: usize,
+}
+
+impl AsRef<[u8]> for MappedSlice {
+    fn as_ref(&self) -> &[u8] {
+        &self.mmap[self.offset..self.offset + self.size]
+    }
 }
 
 #[derive(Debug)]
 pub struct FileSrc {
     streaming_state: StreamingState,
     cat: gst::DebugCategory,
+    settings: SharedSettings,
+    follower: Follower,
 }
 
 impl FileSrc {
     pub fn new(_src: &BaseSrc) -> FileSrc {
+        let cat = gst::DebugCategory::new(
+            "rsfilesrc",
+            gst::DebugColorFlags::empty(),
+            "Rust file source",
+        );
+
         FileSrc {
             streaming_state: StreamingState::Stopped,
-            cat: gst::DebugCategory::new(
-                "rsfilesrc",
-                gst::DebugColorFlags::empty(),
-                "Rust file source",
-            ),
+            cat: cat,
+            settings: SharedSettings::default(),
+            follower: Follower {
+                cat: cat,
+                file: Arc::new(Mutex::new(None)),
+                waiter: Waiter::default(),
+            },
         }
     }
 
@@ -47,7 +290,7 @@ impl FileSrc {
     }
 }
 
-fn validate_uri(uri: &Url) -> Result<(), UriError> {
+pub fn validate_uri(uri: &Url) -> Result<(), UriError> {
     let _ = try!(uri.to_file_path().or_else(|_| Err(UriError::new(
         gst::URIError::UnsupportedProtocol,
         format!("Unsupported file URI '{}'", uri.as_str()),
@@ -60,16 +303,24 @@ impl SourceImpl for FileSrc {
         Box::new(validate_uri)
     }
 
-    fn is_seekable(&self, _src: &BaseSrc) -> bool {
-        true
+    fn unlocker(&self) -> Option<Box<Unlocker>> {
+        Some(Box::new(self.follower.waiter.clone()))
     }
 
-    fn get_size(&self, _src: &BaseSrc) -> Option<u64> {
-        if let StreamingState::Started { ref file, .. } = self.streaming_state {
-            file.metadata().ok().map(|m| m.len())
-        } else {
-            None
-        }
+    fn property_handler(&self) -> Option<Box<PropertyHandler<BaseSrc>>> {
+        Some(Box::new(self.settings.clone()))
+    }
+
+    fn stream_handler(&self) -> Option<Box<StreamHandler>> {
+        Some(Box::new(self.follower.clone()))
+    }
+
+    fn is_seekable(&self, src: &BaseSrc) -> bool {
+        self.follower.is_seekable(src)
+    }
+
+    fn get_size(&self, src: &BaseSrc) -> Option<u64> {
+        self.follower.get_size(src)
     }
 
     fn start(&mut self, src: &BaseSrc, uri: Url) -> Result<(), gst::ErrorMessage> {
@@ -112,16 +363,57 @@ impl SourceImpl for FileSrc {
 
         gst_debug!(self.cat, obj: src, "Opened file {:?}", file);
 
+        let settings = *self.settings.0.lock().unwrap();
+
+        let size_file = try!(file.try_clone().or_else(|err| {
+            gst_error!(self.cat, obj: src, "Could not duplicate file: {}", err);
+            Err(gst_error_msg!(
+                gst::ResourceError::OpenRead,
+                ["Could not duplicate file: {}", err.to_string()]
+            ))
+        }));
+
+        // Empty files can't be mapped, reading them just gives EOS
+        let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+        let mmap = if settings.use_mmap && !settings.follow && len > 0 {
+            // SAFETY: The mapping and all buffers pointing into it are only valid as long as
+            // the file is not truncated or modified by anybody else. Truncation makes reads
+            // from the dropped pages raise SIGBUS, which is why this is opt-in and documented
+            // on the use-mmap property. The mapping itself is kept alive by the Arc for as long
+            // as any buffer references it.
+            match unsafe { Mmap::map(&file) } {
+                Ok(mmap) => {
+                    gst_debug!(self.cat, obj: src, "Mapped {} bytes", mmap.len());
+                    Some(Arc::new(mmap))
+                }
+                Err(err) => {
+                    gst_warning!(self.cat, obj: src, "Failed to map file: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
         self.streaming_state = StreamingState::Started {
             file: file,
             position: 0,
+            mmap: mmap,
         };
 
+        *self.follower.file.lock().unwrap() = Some(OpenFile {
+            file: size_file,
+            following: settings.follow,
+            follow_interval: settings.follow_interval,
+            follow_timeout: settings.follow_timeout,
+        });
+
         Ok(())
     }
 
     fn stop(&mut self, _src: &BaseSrc) -> Result<(), gst::ErrorMessage> {
         self.streaming_state = StreamingState::Stopped;
+        *self.follower.file.lock().unwrap() = None;
 
         Ok(())
     }
@@ -134,12 +426,12 @@ impl SourceImpl for FileSrc {
         buffer: &mut gst::BufferRef,
     ) -> Result<(), FlowError> {
         let cat = self.cat;
-        let streaming_state = &mut self.streaming_state;
 
-        let (file, position) = match *streaming_state {
+        let (file, position) = match self.streaming_state {
             StreamingState::Started {
                 ref mut file,
                 ref mut position,
+                ..
             } => (file, position),
             StreamingState::Stopped => {
                 return Err(FlowError::Error(gst_error_msg!(
@@ -160,6 +452,7 @@ impl SourceImpl for FileSrc {
             *position = offset;
         }
 
+        // When following, the file already grew past the offset in Follower::wait()
         let size = {
             let mut map = match buffer.map_writable() {
                 None => {
@@ -171,9 +464,7 @@ impl SourceImpl for FileSrc {
                 Some(map) => map,
             };
 
-            let data = map.as_mut_slice();
-
-            try!(file.read(data).or_else(|err| {
+            try!(file.read(map.as_mut_slice()).or_else(|err| {
                 gst_error!(cat, obj: src, "Failed to read: {:?}", err);
                 Err(FlowError::Error(gst_error_msg!(
                     gst::ResourceError::Read,
@@ -182,6 +473,12 @@ impl SourceImpl for FileSrc {
             }))
         };
 
+        // Only if the file was truncated in the meantime
+        if size == 0 {
+            gst_debug!(cat, obj: src, "End of file at {}", offset);
+            return Err(FlowError::Eos);
+        }
+
         *position += size as u64;
 
         buffer.set_size(size);
@@ -189,6 +486,41 @@ impl SourceImpl for FileSrc {
         Ok(())
     }
 
+    fn create(
+        &mut self,
+        src: &BaseSrc,
+        offset: u64,
+        length: u32,
+    ) -> Option<Result<gst::Buffer, FlowError>> {
+        let mmap = match self.streaming_state {
+            StreamingState::Started {
+                mmap: Some(ref mmap),
+                ..
+            } => mmap,
+            _ => return None,
+        };
+
+        if offset >= mmap.len() as u64 {
+            return Some(Err(FlowError::Eos));
+        }
+
+        let size = cmp::min(length as u64, mmap.len() as u64 - offset) as usize;
+        gst_trace!(self.cat, obj: src, "Mapped buffer at {} with size {}", offset, size);
+
+        let mut buffer = gst::Buffer::from_slice(MappedSlice {
+            mmap: mmap.clone(),
+            offset: offset as usize,
+            size: size,
+        }).unwrap();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_offset(offset);
+            buffer.set_offset_end(offset + size as u64);
+        }
+
+        Some(Ok(buffer))
+    }
+
     fn seek(&mut self, _src: &BaseSrc, _: u64, _: Option<u64>) -> Result<(), gst::ErrorMessage> {
         Ok(())
     }
@@ -8,21 +8,40 @@
 
 #![crate_type = "cdylib"]
 
+extern crate glib;
+extern crate chrono;
+extern crate glob;
+extern crate gobject_subclass;
 #[macro_use]
 extern crate gst_plugin;
 extern crate gst_plugin_simple;
 #[macro_use]
 extern crate gstreamer as gst;
+extern crate memmap;
 extern crate url;
 
 use gst_plugin_simple::sink::*;
 use gst_plugin_simple::source::*;
 
+#[cfg(unix)]
+mod fd;
+#[cfg(unix)]
+mod fdsink;
+#[cfg(unix)]
+mod fdsrc;
 mod filesink;
 mod filesrc;
+mod splitfilesink;
+mod splitfilesrc;
+mod writer;
 
+#[cfg(unix)]
+use fdsink::FdSink;
+#[cfg(unix)]
+use fdsrc::FdSrc;
 use filesink::FileSink;
 use filesrc::FileSrc;
+use splitfilesrc::SplitFileSrc;
 
 fn plugin_init(plugin: &gst::Plugin) -> bool {
     source_register(
@@ -37,6 +56,23 @@ fn plugin_init(plugin: &gst::Plugin) -> bool {
             create_instance: FileSrc::new_boxed,
             protocols: vec!["file".into()],
             push_only: false,
+            properties: &filesrc::PROPERTIES,
+        },
+    );
+
+    source_register(
+        plugin,
+        SourceInfo {
+            name: "rssplitfilesrc".into(),
+            long_name: "Split File Source".into(),
+            description: "Reads a sequence of local files as one stream".into(),
+            classification: "Source/File".into(),
+            author: "Sebastian Dröge <sebastian@centricular.com>".into(),
+            rank: 0,
+            create_instance: SplitFileSrc::new_boxed,
+            protocols: vec!["file".into()],
+            push_only: false,
+            properties: &splitfilesrc::PROPERTIES,
         },
     );
 
@@ -51,6 +87,43 @@ fn plugin_init(plugin: &gst::Plugin) -> bool {
             rank: 256 + 100,
             create_instance: FileSink::new_boxed,
             protocols: vec!["file".into()],
+            properties: &filesink::PROPERTIES,
+        },
+    );
+
+    splitfilesink::register(plugin);
+
+    #[cfg(unix)]
+    source_register(
+        plugin,
+        SourceInfo {
+            name: "rsfdsrc".into(),
+            long_name: "File Descriptor Source".into(),
+            description: "Reads from a file descriptor".into(),
+            classification: "Source/File".into(),
+            author: "Sebastian Dröge <sebastian@centricular.com>".into(),
+            rank: 256 + 100,
+            create_instance: FdSrc::new_boxed,
+            protocols: vec!["fd".into()],
+            // Pull mode is offered depending on the descriptor being seekable
+            push_only: false,
+            properties: &fdsrc::PROPERTIES,
+        },
+    );
+
+    #[cfg(unix)]
+    sink_register(
+        plugin,
+        SinkInfo {
+            name: "rsfdsink".into(),
+            long_name: "File Descriptor Sink".into(),
+            description: "Writes to a file descriptor".into(),
+            classification: "Sink/File".into(),
+            author: "Sebastian Dröge <sebastian@centricular.com>".into(),
+            rank: 256 + 100,
+            create_instance: FdSink::new_boxed,
+            protocols: vec!["fd".into()],
+            properties: &fdsink::PROPERTIES,
         },
     );
 
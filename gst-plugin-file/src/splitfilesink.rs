@@ -0,0 +1,636 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::u64;
+
+use glib;
+use gst;
+use gst::prelude::*;
+
+use gst_plugin::base_sink::*;
+use gst_plugin::element::*;
+
+use gobject_subclass::object::*;
+
+use writer::{BufferMode, FsyncMode, Writer, WriterSettings};
+
+const DEFAULT_LOCATION: &'static str = "%05d";
+const DEFAULT_MAX_SIZE_BYTES: u64 = 0;
+const DEFAULT_MAX_SIZE_TIME: u64 = 0;
+const DEFAULT_NEXT_FILE: NextFile = NextFile::MaxSize;
+
+// Closed files are synced so that they survive power cuts
+const WRITER_SETTINGS: WriterSettings = WriterSettings {
+    buffer_mode: BufferMode::Full,
+    buffer_size: 65536,
+    fsync_mode: FsyncMode::Eos,
+    fsync_bytes: 0,
+    fsync_interval: 0,
+    async_write: false,
+};
+
+static PROPERTIES: [Property; 4] = [
+    Property::String(
+        "location",
+        "File Location",
+        "Location pattern of the files, the first %d with a width (e.g. %05d) is required and \
+         replaced by the file index, all other directives including %d are formatted with \
+         strftime",
+        Some(DEFAULT_LOCATION),
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt64(
+        "max-size-bytes",
+        "Maximum Size",
+        "Start a new file before exceeding this many bytes (0 = disabled)",
+        (0, u64::MAX),
+        DEFAULT_MAX_SIZE_BYTES,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt64(
+        "max-size-time",
+        "Maximum Duration",
+        "Start a new file after this duration in nanoseconds (0 = disabled)",
+        (0, u64::MAX),
+        DEFAULT_MAX_SIZE_TIME,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "next-file",
+        "Next File",
+        "When to start a new file (max-size, key-frame). With key-frame the maximum size \
+         conditions are checked at key frames only, without them every key frame starts a \
+         new file",
+        Some("max-size"),
+        PropertyMutability::ReadWrite,
+    ),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NextFile {
+    MaxSize,
+    KeyFrame,
+}
+
+impl NextFile {
+    fn from_str(s: &str) -> Option<NextFile> {
+        match s {
+            "max-size" => Some(NextFile::MaxSize),
+            "key-frame" => Some(NextFile::KeyFrame),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match *self {
+            NextFile::MaxSize => "max-size",
+            NextFile::KeyFrame => "key-frame",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    location: String,
+    max_size_bytes: u64,
+    max_size_time: u64,
+    next_file: NextFile,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            location: DEFAULT_LOCATION.into(),
+            max_size_bytes: DEFAULT_MAX_SIZE_BYTES,
+            max_size_time: DEFAULT_MAX_SIZE_TIME,
+            next_file: DEFAULT_NEXT_FILE,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OpenFile {
+    writer: Writer,
+    location: PathBuf,
+    size: u64,
+    first_timestamp: Option<u64>,
+    last_timestamp: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    file: Option<OpenFile>,
+    // Index of the next file
+    index: u32,
+    // Written at the beginning of every file after the first
+    streamheader: Vec<gst::Buffer>,
+}
+
+struct SplitFileSink {
+    cat: gst::DebugCategory,
+    settings: Mutex<Settings>,
+    state: Mutex<Option<State>>,
+}
+
+// Replaces the first %Nd or %0Nd by the index and formats the remaining directives with
+// strftime. A plain %d is left for strftime so that it can still be used for the day
+fn format_location(pattern: &str, index: u32) -> Result<String, String> {
+    let mut formatted = String::new();
+    let mut chars = pattern.chars().peekable();
+    let mut replaced = false;
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            formatted.push(c);
+            continue;
+        }
+
+        let mut spec = String::new();
+        while let Some(&c) = chars.peek() {
+            if !c.is_digit(10) {
+                break;
+            }
+            spec.push(c);
+            chars.next();
+        }
+
+        if !replaced && !spec.is_empty() && chars.peek() == Some(&'d') {
+            chars.next();
+            replaced = true;
+            let width = spec.parse::<usize>().unwrap_or(0);
+            if spec.starts_with('0') {
+                formatted.push_str(&format!("{:01$}", index, width));
+            } else {
+                formatted.push_str(&format!("{:1$}", index, width));
+            }
+        } else {
+            // Left for strftime, including "%%"
+            formatted.push('%');
+            formatted.push_str(&spec);
+            if let Some(c) = chars.next() {
+                formatted.push(c);
+            }
+        }
+    }
+
+    // Otherwise all files would end up with the same name
+    if !replaced {
+        return Err(format!(
+            "Location pattern '{}' has no file index like %05d",
+            pattern
+        ));
+    }
+
+    if StrftimeItems::new(&formatted).any(|item| item == Item::Error) {
+        return Err(format!("Invalid location pattern '{}'", pattern));
+    }
+
+    Ok(Local::now()
+        .format_with_items(StrftimeItems::new(&formatted))
+        .to_string())
+}
+
+impl SplitFileSink {
+    fn new(_sink: &BaseSink) -> Self {
+        Self {
+            cat: gst::DebugCategory::new(
+                "rssplitfilesink",
+                gst::DebugColorFlags::empty(),
+                "Rust file splitting sink",
+            ),
+            settings: Mutex::new(Default::default()),
+            state: Mutex::new(None),
+        }
+    }
+
+    fn class_init(klass: &mut BaseSinkClass) {
+        klass.set_metadata(
+            "Split File Sink",
+            "Sink/File",
+            "Writes into a sequence of local files",
+            "Sebastian Dröge <sebastian@centricular.com>",
+        );
+
+        let caps = gst::Caps::new_any();
+        let sink_pad_template = gst::PadTemplate::new(
+            "sink",
+            gst::PadDirection::Sink,
+            gst::PadPresence::Always,
+            &caps,
+        );
+        klass.add_pad_template(sink_pad_template);
+
+        klass.install_properties(&PROPERTIES);
+    }
+
+    fn init(element: &BaseSink) -> Box<BaseSinkImpl<BaseSink>> {
+        let imp = Self::new(element);
+        Box::new(imp)
+    }
+
+    fn open_file(
+        &self,
+        element: &BaseSink,
+        state: &mut State,
+        settings: &Settings,
+    ) -> Result<(), gst::ErrorMessage> {
+        let location = try!(format_location(&settings.location, state.index).map_err(|err| {
+            gst_error_msg!(gst::ResourceError::Settings, ["{}", err])
+        }));
+        let location = PathBuf::from(location);
+
+        let file = try!(File::create(&location).or_else(|err| {
+            gst_error!(self.cat, obj: element, "Could not open file for writing: {}", err);
+            Err(gst_error_msg!(
+                gst::ResourceError::OpenWrite,
+                [
+                    "Could not open file for writing '{}': {}",
+                    location.to_str().unwrap_or("Non-UTF8 path"),
+                    err.to_string(),
+                ]
+            ))
+        }));
+
+        gst_debug!(self.cat, obj: element, "Opened file {:?}", location);
+
+        let mut file = OpenFile {
+            writer: Writer::new(file, &WRITER_SETTINGS),
+            location: location,
+            size: 0,
+            first_timestamp: None,
+            last_timestamp: None,
+        };
+
+        if state.index > 0 {
+            for header in &state.streamheader {
+                try!(self.write(&mut file, header));
+            }
+        }
+
+        state.file = Some(file);
+        state.index += 1;
+
+        Ok(())
+    }
+
+    fn write(&self, file: &mut OpenFile, buffer: &gst::BufferRef) -> Result<(), gst::ErrorMessage> {
+        try!(file.writer.write(buffer).or_else(|err| {
+            Err(gst_error_msg!(
+                gst::ResourceError::Write,
+                [
+                    "Failed to write to '{}': {}",
+                    file.location.to_str().unwrap_or("Non-UTF8 path"),
+                    err.to_string()
+                ]
+            ))
+        }));
+        file.size += buffer.get_size() as u64;
+
+        Ok(())
+    }
+
+    // Syncs the file and posts a message with its location and timestamps
+    fn close_file(&self, element: &BaseSink, state: &mut State) -> Result<(), gst::ErrorMessage> {
+        let OpenFile {
+            mut writer,
+            location,
+            size,
+            first_timestamp,
+            last_timestamp,
+        } = match state.file.take() {
+            None => return Ok(()),
+            Some(file) => file,
+        };
+
+        let res = writer.finish();
+        try!(res.and_then(|_| writer.close()).or_else(|err| {
+            Err(gst_error_msg!(
+                gst::ResourceError::Close,
+                [
+                    "Failed to close '{}': {}",
+                    location.to_str().unwrap_or("Non-UTF8 path"),
+                    err.to_string()
+                ]
+            ))
+        }));
+
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Closed file {:?} with size {}",
+            location,
+            size
+        );
+
+        let mut s = gst::Structure::new(
+            "splitfilesink-file-closed",
+            &[
+                ("location", &location.to_string_lossy().into_owned()),
+                ("index", &(state.index - 1)),
+                ("size", &size),
+            ],
+        );
+        if let Some(first_timestamp) = first_timestamp {
+            s.set("first-timestamp", &first_timestamp);
+        }
+        if let Some(last_timestamp) = last_timestamp {
+            s.set("last-timestamp", &last_timestamp);
+        }
+
+        let _ = element.post_message(&gst::Message::new_element(s).src(Some(element)).build());
+
+        Ok(())
+    }
+
+    fn needs_new_file(
+        &self,
+        file: &OpenFile,
+        settings: &Settings,
+        buffer: &gst::BufferRef,
+    ) -> bool {
+        let flags = buffer.get_flags();
+        if file.size == 0 || flags.contains(gst::BufferFlags::HEADER) {
+            return false;
+        }
+
+        let size_reached = settings.max_size_bytes > 0
+            && file.size + buffer.get_size() as u64 > settings.max_size_bytes;
+        let time_reached = settings.max_size_time > 0 && match (
+            file.first_timestamp,
+            buffer.get_pts().0,
+        ) {
+            (Some(first), Some(pts)) => pts.saturating_sub(first) >= settings.max_size_time,
+            _ => false,
+        };
+
+        match settings.next_file {
+            NextFile::MaxSize => size_reached || time_reached,
+            NextFile::KeyFrame => {
+                let limits = settings.max_size_bytes > 0 || settings.max_size_time > 0;
+                !flags.contains(gst::BufferFlags::DELTA_UNIT)
+                    && (!limits || size_reached || time_reached)
+            }
+        }
+    }
+}
+
+impl ObjectImpl<BaseSink> for SplitFileSink {
+    fn set_property(&self, obj: &glib::Object, id: u32, value: &glib::Value) {
+        let prop = &PROPERTIES[id as usize];
+        let element = obj.downcast_ref::<BaseSink>().unwrap();
+
+        match *prop {
+            Property::String("location", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.location = value.get().unwrap_or_else(|| DEFAULT_LOCATION.into());
+            }
+            Property::UInt64("max-size-bytes", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.max_size_bytes = value.get().unwrap();
+            }
+            Property::UInt64("max-size-time", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                settings.max_size_time = value.get().unwrap();
+            }
+            Property::String("next-file", ..) => {
+                let mut settings = self.settings.lock().unwrap();
+                let next_file = value.get::<String>().unwrap_or_else(|| "max-size".into());
+                match NextFile::from_str(&next_file) {
+                    Some(next_file) => settings.next_file = next_file,
+                    None => {
+                        gst_warning!(self.cat, obj: element, "Invalid next-file {}", next_file)
+                    }
+                }
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _obj: &glib::Object, id: u32) -> Result<glib::Value, ()> {
+        let prop = &PROPERTIES[id as usize];
+
+        match *prop {
+            Property::String("location", ..) => {
+                let settings = self.settings.lock().unwrap();
+                Ok(settings.location.to_value())
+            }
+            Property::UInt64("max-size-bytes", ..) => {
+                let settings = self.settings.lock().unwrap();
+                Ok(settings.max_size_bytes.to_value())
+            }
+            Property::UInt64("max-size-time", ..) => {
+                let settings = self.settings.lock().unwrap();
+                Ok(settings.max_size_time.to_value())
+            }
+            Property::String("next-file", ..) => {
+                let settings = self.settings.lock().unwrap();
+                Ok(settings.next_file.as_str().to_value())
+            }
+            _ => unimplemented!(),
+        }
+    }
+}
+
+impl ElementImpl<BaseSink> for SplitFileSink {}
+
+impl BaseSinkImpl<BaseSink> for SplitFileSink {
+    fn start(&self, element: &BaseSink) -> bool {
+        gst_debug!(self.cat, obj: element, "Starting");
+        *self.state.lock().unwrap() = Some(State::default());
+
+        true
+    }
+
+    fn stop(&self, element: &BaseSink) -> bool {
+        gst_debug!(self.cat, obj: element, "Stopping");
+
+        let mut state = match self.state.lock().unwrap().take() {
+            None => return true,
+            Some(state) => state,
+        };
+
+        if let Err(ref msg) = self.close_file(element, &mut state) {
+            element.post_error_message(msg);
+            return false;
+        }
+
+        true
+    }
+
+    fn set_caps(&self, element: &BaseSink, caps: &gst::CapsRef) -> bool {
+        let streamheader = caps.get_structure(0)
+            .and_then(|s| s.get::<gst::Array>("streamheader"))
+            .map(|headers| {
+                headers
+                    .as_slice()
+                    .iter()
+                    .filter_map(|v| v.get::<gst::Buffer>())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_else(Vec::new);
+
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Got {} stream headers",
+            streamheader.len()
+        );
+
+        if let Some(ref mut state) = *self.state.lock().unwrap() {
+            state.streamheader = streamheader;
+        }
+
+        true
+    }
+
+    fn render(&self, element: &BaseSink, buffer: &gst::BufferRef) -> gst::FlowReturn {
+        let settings = self.settings.lock().unwrap().clone();
+        let mut state_guard = self.state.lock().unwrap();
+        let state = match *state_guard {
+            None => return gst::FlowReturn::Error,
+            Some(ref mut state) => state,
+        };
+
+        gst_trace!(self.cat, obj: element, "Rendering {:?}", buffer);
+
+        let new_file = match state.file {
+            None => true,
+            Some(ref file) => self.needs_new_file(file, &settings, buffer),
+        };
+
+        if new_file {
+            let res = match self.close_file(element, state) {
+                Ok(()) => self.open_file(element, state, &settings),
+                Err(err) => Err(err),
+            };
+            if let Err(ref msg) = res {
+                gst_error!(self.cat, obj: element, "Failed to start new file: {:?}", msg);
+                element.post_error_message(msg);
+                return gst::FlowReturn::Error;
+            }
+        }
+
+        let file = state.file.as_mut().unwrap();
+        if let Err(ref msg) = self.write(file, buffer) {
+            gst_error!(self.cat, obj: element, "Failed to write: {:?}", msg);
+            element.post_error_message(msg);
+            return gst::FlowReturn::Error;
+        }
+
+        if let Some(pts) = buffer.get_pts().0 {
+            if file.first_timestamp.is_none() {
+                file.first_timestamp = Some(pts);
+            }
+            file.last_timestamp = Some(pts + buffer.get_duration().0.unwrap_or(0));
+        }
+
+        gst::FlowReturn::Ok
+    }
+
+    fn event(&self, element: &BaseSink, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        if let EventView::Eos(..) = event.view() {
+            if let Some(ref mut state) = *self.state.lock().unwrap() {
+                if let Err(ref msg) = self.close_file(element, state) {
+                    element.post_error_message(msg);
+                    return false;
+                }
+            }
+        }
+
+        element.parent_event(event)
+    }
+
+    fn query(&self, element: &BaseSink, query: &mut gst::QueryRef) -> bool {
+        use gst::QueryView;
+
+        match query.view_mut() {
+            // Earlier data might already be in a previous file
+            QueryView::Seeking(ref mut q) if q.get_format() == gst::Format::Bytes => {
+                q.set(false, gst::format::Bytes(None), gst::format::Bytes(None));
+                return true;
+            }
+            _ => (),
+        }
+
+        element.parent_query(query)
+    }
+}
+
+struct SplitFileSinkStatic;
+
+impl ImplTypeStatic<BaseSink> for SplitFileSinkStatic {
+    fn get_name(&self) -> &str {
+        "SplitFileSink"
+    }
+
+    fn new(&self, element: &BaseSink) -> Box<BaseSinkImpl<BaseSink>> {
+        SplitFileSink::init(element)
+    }
+
+    fn class_init(&self, klass: &mut BaseSinkClass) {
+        SplitFileSink::class_init(klass);
+    }
+}
+
+pub fn register(plugin: &gst::Plugin) {
+    let splitfilesink_static = SplitFileSinkStatic;
+    let type_ = register_type(splitfilesink_static);
+    gst::Element::register(plugin, "rssplitfilesink", 0, type_);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_location_zero_padded() {
+        assert_eq!(format_location("out-%05d.ts", 42), Ok("out-00042.ts".into()));
+        assert_eq!(format_location("out-%05d.ts", 123456), Ok("out-123456.ts".into()));
+    }
+
+    #[test]
+    fn test_format_location_width() {
+        assert_eq!(format_location("out-%3d.ts", 7), Ok("out-  7.ts".into()));
+    }
+
+    #[test]
+    fn test_format_location_day_and_index() {
+        // A plain %d is the day of the month
+        let location = format_location("%d-%03d.ts", 7).unwrap();
+        assert_eq!(location.len(), "01-007.ts".len());
+        assert!(location[..2].chars().all(|c| c.is_digit(10)));
+        assert!(location.ends_with("-007.ts"));
+    }
+
+    #[test]
+    fn test_format_location_percent() {
+        assert_eq!(format_location("100%%-%03d.ts", 7), Ok("100%-007.ts".into()));
+        // An escaped percent sign doesn't start the index
+        assert!(format_location("%%05d.ts", 7).is_err());
+    }
+
+    #[test]
+    fn test_format_location_no_index() {
+        assert!(format_location("out.ts", 7).is_err());
+        assert!(format_location("out-%d.ts", 7).is_err());
+    }
+
+    #[test]
+    fn test_format_location_invalid_directive() {
+        assert!(format_location("%Q-%03d.ts", 7).is_err());
+        assert!(format_location("%03d.ts%", 7).is_err());
+    }
+}
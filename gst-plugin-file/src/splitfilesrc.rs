@@ -0,0 +1,366 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use glob;
+use std::cmp;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use url::Url;
+
+use gst_plugin::error::*;
+use gst_plugin_simple::error::*;
+use gst_plugin_simple::source::*;
+use gst_plugin_simple::UriValidator;
+
+use glib;
+use gst;
+use gst::prelude::*;
+
+use gobject_subclass::object::*;
+
+use filesrc::validate_uri;
+
+pub static PROPERTIES: [Property; 1] = [Property::Boxed(
+    "locations",
+    "Locations",
+    "List of file URIs to read in order, used instead of the URI if not empty",
+    Vec::<String>::static_type,
+    PropertyMutability::ReadWrite,
+)];
+
+#[derive(Debug)]
+struct Part {
+    file: File,
+    path: PathBuf,
+    // Offset of the first byte of the file in the whole stream
+    start: u64,
+    size: u64,
+    // Read position inside the file
+    position: u64,
+}
+
+#[derive(Debug)]
+enum StreamingState {
+    Stopped,
+    Started { parts: Vec<Part>, size: u64 },
+}
+
+// Reads a sequence of files as if they were one file
+#[derive(Debug)]
+pub struct SplitFileSrc {
+    streaming_state: StreamingState,
+    cat: gst::DebugCategory,
+    locations: Vec<String>,
+}
+
+impl SplitFileSrc {
+    pub fn new(_src: &BaseSrc) -> SplitFileSrc {
+        SplitFileSrc {
+            streaming_state: StreamingState::Stopped,
+            cat: gst::DebugCategory::new(
+                "rssplitfilesrc",
+                gst::DebugColorFlags::empty(),
+                "Rust split file source",
+            ),
+            locations: Vec::new(),
+        }
+    }
+
+    pub fn new_boxed(src: &BaseSrc) -> Box<SourceImpl> {
+        Box::new(SplitFileSrc::new(src))
+    }
+
+    fn to_path(&self, src: &BaseSrc, uri: &Url) -> Result<PathBuf, gst::ErrorMessage> {
+        uri.to_file_path().or_else(|_| {
+            gst_error!(
+                self.cat,
+                obj: src,
+                "Unsupported file URI '{}'",
+                uri.as_str()
+            );
+            Err(gst_error_msg!(
+                gst::LibraryError::Failed,
+                ["Unsupported file URI '{}'", uri.as_str()]
+            ))
+        })
+    }
+
+    // Either the configured locations or the files matching the URI's path as glob pattern
+    fn get_paths(&self, src: &BaseSrc, uri: &Url) -> Result<Vec<PathBuf>, gst::ErrorMessage> {
+        if !self.locations.is_empty() {
+            let mut paths = Vec::new();
+            for location in &self.locations {
+                let uri = try!(Url::parse(location).or_else(|err| {
+                    Err(gst_error_msg!(
+                        gst::ResourceError::NotFound,
+                        ["Failed to parse URI '{}': {}", location, err.to_string()]
+                    ))
+                }));
+                paths.push(try!(self.to_path(src, &uri)));
+            }
+
+            return Ok(paths);
+        }
+
+        let pattern = try!(self.to_path(src, uri));
+        let pattern = try!(pattern.to_str().ok_or_else(|| {
+            gst_error_msg!(
+                gst::ResourceError::NotFound,
+                ["Non-UTF8 path {:?}", pattern]
+            )
+        }));
+
+        let paths = try!(glob::glob(pattern).or_else(|err| {
+            Err(gst_error_msg!(
+                gst::ResourceError::NotFound,
+                ["Invalid pattern '{}': {}", pattern, err.to_string()]
+            ))
+        }));
+
+        // Matches are sorted alphabetically, unreadable directories are skipped
+        let paths = paths.filter_map(|path| path.ok()).collect::<Vec<_>>();
+        if paths.is_empty() {
+            return Err(gst_error_msg!(
+                gst::ResourceError::NotFound,
+                ["No files matching '{}'", pattern]
+            ));
+        }
+
+        Ok(paths)
+    }
+}
+
+impl SourceImpl for SplitFileSrc {
+    fn uri_validator(&self) -> Box<UriValidator> {
+        Box::new(validate_uri)
+    }
+
+    fn default_uri(&self) -> Option<Url> {
+        self.locations
+            .first()
+            .and_then(|location| Url::parse(location).ok())
+    }
+
+    fn is_seekable(&self, _src: &BaseSrc) -> bool {
+        true
+    }
+
+    fn get_size(&self, _src: &BaseSrc) -> Option<u64> {
+        match self.streaming_state {
+            StreamingState::Started { size, .. } => Some(size),
+            StreamingState::Stopped => None,
+        }
+    }
+
+    fn start(&mut self, src: &BaseSrc, uri: Url) -> Result<(), gst::ErrorMessage> {
+        if let StreamingState::Started { .. } = self.streaming_state {
+            return Err(gst_error_msg!(
+                gst::LibraryError::Failed,
+                ["Source already started"]
+            ));
+        }
+
+        let paths = try!(self.get_paths(src, &uri));
+
+        let mut parts = Vec::with_capacity(paths.len());
+        let mut size = 0;
+        for path in paths {
+            let file = try!(File::open(path.as_path()).or_else(|err| {
+                gst_error!(
+                    self.cat,
+                    obj: src,
+                    "Could not open file for reading: {}",
+                    err.to_string()
+                );
+                Err(gst_error_msg!(
+                    gst::ResourceError::OpenRead,
+                    [
+                        "Could not open file for reading '{}': {}",
+                        path.to_str().unwrap_or("Non-UTF8 path"),
+                        err.to_string(),
+                    ]
+                ))
+            }));
+
+            let len = try!(file.metadata().map(|m| m.len()).or_else(|err| {
+                Err(gst_error_msg!(
+                    gst::ResourceError::OpenRead,
+                    [
+                        "Could not get size of '{}': {}",
+                        path.to_str().unwrap_or("Non-UTF8 path"),
+                        err.to_string(),
+                    ]
+                ))
+            }));
+
+            gst_debug!(
+                self.cat,
+                obj: src,
+                "Opened file {:?} with size {} at offset {}",
+                path,
+                len,
+                size
+            );
+
+            parts.push(Part {
+                file: file,
+                path: path,
+                start: size,
+                size: len,
+                position: 0,
+            });
+            size += len;
+        }
+
+        self.streaming_state = StreamingState::Started {
+            parts: parts,
+            size: size,
+        };
+
+        Ok(())
+    }
+
+    fn stop(&mut self, _src: &BaseSrc) -> Result<(), gst::ErrorMessage> {
+        self.streaming_state = StreamingState::Stopped;
+
+        Ok(())
+    }
+
+    fn fill(
+        &mut self,
+        src: &BaseSrc,
+        offset: u64,
+        _: u32,
+        buffer: &mut gst::BufferRef,
+    ) -> Result<(), FlowError> {
+        let cat = self.cat;
+
+        let (parts, total) = match self.streaming_state {
+            StreamingState::Started {
+                ref mut parts,
+                size,
+            } => (parts, size),
+            StreamingState::Stopped => {
+                return Err(FlowError::Error(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Not started yet"]
+                )));
+            }
+        };
+
+        if offset >= total {
+            return Err(FlowError::Eos);
+        }
+
+        // Last file starting at or before the offset
+        let mut index = match parts.binary_search_by(|part| part.start.cmp(&offset)) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+
+        let size = {
+            let mut map = match buffer.map_writable() {
+                None => {
+                    return Err(FlowError::Error(gst_error_msg!(
+                        gst::LibraryError::Failed,
+                        ["Failed to map buffer"]
+                    )));
+                }
+                Some(map) => map,
+            };
+
+            let data = map.as_mut_slice();
+
+            // Reads continue into the following files until the buffer is full
+            let mut filled = 0;
+            while filled < data.len() && index < parts.len() {
+                let part = &mut parts[index];
+                let part_offset = offset + filled as u64 - part.start;
+
+                if part_offset >= part.size {
+                    index += 1;
+                    continue;
+                }
+
+                if part.position != part_offset {
+                    try!(
+                        part.file
+                            .seek(SeekFrom::Start(part_offset))
+                            .or_else(|err| {
+                                gst_error!(
+                                    cat,
+                                    obj: src,
+                                    "Failed to seek to {} in {:?}: {:?}",
+                                    part_offset,
+                                    part.path,
+                                    err
+                                );
+                                Err(FlowError::Error(gst_error_msg!(
+                                    gst::ResourceError::Seek,
+                                    ["Failed to seek to {}: {}", offset, err.to_string()]
+                                )))
+                            })
+                    );
+                    part.position = part_offset;
+                }
+
+                let len = cmp::min((data.len() - filled) as u64, part.size - part_offset);
+                let data = &mut data[filled..filled + len as usize];
+                let size = try!(part.file.read(data).or_else(|err| {
+                    gst_error!(cat, obj: src, "Failed to read {:?}: {:?}", part.path, err);
+                    Err(FlowError::Error(gst_error_msg!(
+                        gst::ResourceError::Read,
+                        ["Failed to read at {}: {}", offset, err.to_string()]
+                    )))
+                }));
+
+                // The offsets of all following files would be wrong otherwise
+                if size == 0 {
+                    gst_error!(cat, obj: src, "File {:?} got truncated", part.path);
+                    return Err(FlowError::Error(gst_error_msg!(
+                        gst::ResourceError::Read,
+                        [
+                            "File '{}' got truncated",
+                            part.path.to_str().unwrap_or("Non-UTF8 path")
+                        ]
+                    )));
+                }
+
+                part.position += size as u64;
+                filled += size;
+            }
+
+            filled
+        };
+
+        buffer.set_size(size);
+
+        Ok(())
+    }
+
+    fn seek(&mut self, _src: &BaseSrc, _: u64, _: Option<u64>) -> Result<(), gst::ErrorMessage> {
+        Ok(())
+    }
+
+    fn set_property(&mut self, _src: &BaseSrc, property: &Property, value: &glib::Value) {
+        match *property {
+            Property::Boxed("locations", ..) => {
+                self.locations = value.get().unwrap_or_else(Vec::new);
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _src: &BaseSrc, property: &Property) -> Result<glib::Value, ()> {
+        match *property {
+            Property::Boxed("locations", ..) => Ok(self.locations.to_value()),
+            _ => unimplemented!(),
+        }
+    }
+}
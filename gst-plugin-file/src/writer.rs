@@ -0,0 +1,335 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs::File;
+use std::io::{self, BufWriter, LineWriter, Seek, SeekFrom, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gst;
+
+// Maximum number of buffers queued for the writer thread
+const ASYNC_QUEUE_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferMode {
+    Unbuffered,
+    Full,
+    Line,
+}
+
+impl BufferMode {
+    pub fn from_str(s: &str) -> Option<BufferMode> {
+        match s {
+            "unbuffered" => Some(BufferMode::Unbuffered),
+            "full" => Some(BufferMode::Full),
+            "line" => Some(BufferMode::Line),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BufferMode::Unbuffered => "unbuffered",
+            BufferMode::Full => "full",
+            BufferMode::Line => "line",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncMode {
+    Never,
+    Eos,
+    // After a number of bytes and/or an interval, and on EOS
+    Periodic,
+}
+
+impl FsyncMode {
+    pub fn from_str(s: &str) -> Option<FsyncMode> {
+        match s {
+            "never" => Some(FsyncMode::Never),
+            "eos" => Some(FsyncMode::Eos),
+            "periodic" => Some(FsyncMode::Periodic),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FsyncMode::Never => "never",
+            FsyncMode::Eos => "eos",
+            FsyncMode::Periodic => "periodic",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriterSettings {
+    pub buffer_mode: BufferMode,
+    pub buffer_size: usize,
+    pub fsync_mode: FsyncMode,
+    // 0 disables syncing after a number of bytes
+    pub fsync_bytes: u64,
+    // In seconds, 0 disables syncing after an interval
+    pub fsync_interval: u32,
+    pub async_write: bool,
+}
+
+#[derive(Debug)]
+enum Output {
+    Unbuffered(File),
+    Full(BufWriter<File>),
+    Line(LineWriter<File>),
+}
+
+#[derive(Debug)]
+struct FileWriter {
+    output: Output,
+    fsync_mode: FsyncMode,
+    fsync_bytes: u64,
+    fsync_interval: Option<Duration>,
+    // Bytes written since the last sync
+    unsynced: u64,
+    last_sync: Instant,
+}
+
+impl FileWriter {
+    fn new(file: File, settings: &WriterSettings) -> FileWriter {
+        let output = match settings.buffer_mode {
+            BufferMode::Unbuffered => Output::Unbuffered(file),
+            BufferMode::Full => Output::Full(BufWriter::with_capacity(settings.buffer_size, file)),
+            BufferMode::Line => {
+                Output::Line(LineWriter::with_capacity(settings.buffer_size, file))
+            }
+        };
+
+        FileWriter {
+            output: output,
+            fsync_mode: settings.fsync_mode,
+            fsync_bytes: settings.fsync_bytes,
+            fsync_interval: if settings.fsync_interval > 0 {
+                Some(Duration::from_secs(settings.fsync_interval as u64))
+            } else {
+                None
+            },
+            unsynced: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    fn file_mut(&mut self) -> &mut File {
+        match self.output {
+            Output::Unbuffered(ref mut file) => file,
+            Output::Full(ref mut writer) => writer.get_mut(),
+            Output::Line(ref mut writer) => writer.get_mut(),
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        try!(match self.output {
+            Output::Unbuffered(ref mut file) => file.write_all(data),
+            Output::Full(ref mut writer) => writer.write_all(data),
+            Output::Line(ref mut writer) => writer.write_all(data),
+        });
+        self.unsynced += data.len() as u64;
+
+        if self.fsync_mode != FsyncMode::Periodic {
+            return Ok(());
+        }
+
+        let bytes_reached = self.fsync_bytes > 0 && self.unsynced >= self.fsync_bytes;
+        let interval_reached = self.fsync_interval
+            .map(|interval| self.last_sync.elapsed() >= interval)
+            .unwrap_or(false);
+        if bytes_reached || interval_reached {
+            try!(self.sync());
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.output {
+            Output::Unbuffered(ref mut file) => file.flush(),
+            Output::Full(ref mut writer) => writer.flush(),
+            Output::Line(ref mut writer) => writer.flush(),
+        }
+    }
+
+    fn seek(&mut self, position: u64) -> io::Result<()> {
+        try!(self.flush());
+        self.file_mut().seek(SeekFrom::Start(position)).map(|_| ())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        try!(self.flush());
+        try!(self.file_mut().sync_all());
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.fsync_mode == FsyncMode::Never {
+            self.flush()
+        } else {
+            self.sync()
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Write(gst::Buffer),
+    Seek(u64),
+    Finish(mpsc::Sender<io::Result<()>>),
+}
+
+// Runs in its own thread until the sender is gone or writing failed
+fn run_writer(mut writer: FileWriter, receiver: mpsc::Receiver<Command>) -> io::Result<()> {
+    for command in receiver.iter() {
+        match command {
+            Command::Write(buffer) => {
+                let map = try!(buffer.map_readable().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Other, "Failed to map buffer")
+                }));
+                try!(writer.write_all(map.as_slice()));
+            }
+            Command::Seek(position) => try!(writer.seek(position)),
+            Command::Finish(reply) => {
+                let res = writer.finish();
+                let failed = res.is_err();
+                let _ = reply.send(res);
+                if failed {
+                    return Err(io::Error::new(io::ErrorKind::Other, "Failed to finish"));
+                }
+            }
+        }
+    }
+
+    writer.flush()
+}
+
+#[derive(Debug)]
+struct AsyncWriter {
+    sender: Option<mpsc::SyncSender<Command>>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl AsyncWriter {
+    fn send(&mut self, command: Command) -> io::Result<()> {
+        let sent = match self.sender {
+            Some(ref sender) => sender.send(command).is_ok(),
+            None => false,
+        };
+
+        if sent {
+            return Ok(());
+        }
+
+        // The thread only stops early because of an error
+        match self.join() {
+            Err(err) => Err(err),
+            Ok(()) => Err(io::Error::new(io::ErrorKind::Other, "Writer thread stopped")),
+        }
+    }
+
+    fn join(&mut self) -> io::Result<()> {
+        self.sender = None;
+
+        match self.thread.take() {
+            None => Ok(()),
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "Writer panicked"))),
+        }
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+#[derive(Debug)]
+enum Inner {
+    Direct(FileWriter),
+    // Writes happen on a separate thread, at most ASYNC_QUEUE_SIZE buffers are queued
+    Async(AsyncWriter),
+}
+
+// Writes buffers to a file according to the buffering, syncing and threading settings
+#[derive(Debug)]
+pub struct Writer {
+    inner: Inner,
+}
+
+impl Writer {
+    pub fn new(file: File, settings: &WriterSettings) -> Writer {
+        let writer = FileWriter::new(file, settings);
+
+        if !settings.async_write {
+            return Writer {
+                inner: Inner::Direct(writer),
+            };
+        }
+
+        let (sender, receiver) = mpsc::sync_channel(ASYNC_QUEUE_SIZE);
+        let thread = thread::spawn(move || run_writer(writer, receiver));
+
+        Writer {
+            inner: Inner::Async(AsyncWriter {
+                sender: Some(sender),
+                thread: Some(thread),
+            }),
+        }
+    }
+
+    pub fn write(&mut self, buffer: &gst::BufferRef) -> io::Result<()> {
+        match self.inner {
+            Inner::Direct(ref mut writer) => {
+                let map = try!(buffer.map_readable().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Other, "Failed to map buffer")
+                }));
+                writer.write_all(map.as_slice())
+            }
+            Inner::Async(ref mut writer) => writer.send(Command::Write(buffer.to_owned())),
+        }
+    }
+
+    pub fn seek(&mut self, position: u64) -> io::Result<()> {
+        match self.inner {
+            Inner::Direct(ref mut writer) => writer.seek(position),
+            Inner::Async(ref mut writer) => writer.send(Command::Seek(position)),
+        }
+    }
+
+    // Flushes everything and syncs unless the fsync mode is never, waits for queued buffers
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.inner {
+            Inner::Direct(ref mut writer) => writer.finish(),
+            Inner::Async(ref mut writer) => {
+                let (sender, receiver) = mpsc::channel();
+                try!(writer.send(Command::Finish(sender)));
+                receiver.recv().unwrap_or_else(|_| writer.join())
+            }
+        }
+    }
+
+    // Flushes all pending data without syncing
+    pub fn close(self) -> io::Result<()> {
+        match self.inner {
+            Inner::Direct(mut writer) => writer.flush(),
+            Inner::Async(mut writer) => writer.join(),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (C) 2018 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate glib;
+use glib::prelude::*;
+
+extern crate gstreamer as gst;
+use gst::prelude::*;
+
+extern crate url;
+use url::Url;
+
+use std::env;
+use std::fs;
+use std::process;
+
+fn init() {
+    use std::sync::{Once, ONCE_INIT};
+    static INIT: Once = ONCE_INIT;
+
+    INIT.call_once(|| {
+        gst::init().unwrap();
+
+        #[cfg(debug_assertions)]
+        {
+            use std::path::Path;
+
+            let mut path = Path::new("target/debug");
+            if !path.exists() {
+                path = Path::new("../target/debug");
+            }
+
+            gst::Registry::get().scan_path(path);
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            use std::path::Path;
+
+            let mut path = Path::new("target/release");
+            if !path.exists() {
+                path = Path::new("../target/release");
+            }
+
+            gst::Registry::get().scan_path(path);
+        }
+    });
+}
+
+fn read(srcpad: &gst::Pad, offset: u64, size: u32) -> Result<Vec<u8>, gst::FlowReturn> {
+    let buffer = srcpad.get_range(offset, size)?;
+    let map = buffer.map_readable().unwrap();
+    Ok(map.as_slice().to_vec())
+}
+
+#[test]
+fn test_read_across_files() {
+    init();
+
+    // The empty file in the middle starts at the same offset as the one after it
+    let dir = env::temp_dir().join(format!("rssplitfilesrc-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let contents: [&[u8]; 3] = [b"0123", b"", b"456789"];
+    let locations = contents
+        .iter()
+        .enumerate()
+        .map(|(i, data)| {
+            let path = dir.join(format!("part{}", i));
+            fs::write(&path, data).unwrap();
+            Url::from_file_path(&path).unwrap().into_string()
+        })
+        .collect::<Vec<_>>();
+
+    let src = gst::ElementFactory::make("rssplitfilesrc", None).unwrap();
+    src.set_property("locations", &locations).unwrap();
+
+    let srcpad = src.get_static_pad("src").unwrap();
+    srcpad.activate_mode(gst::PadMode::Pull, true).unwrap();
+
+    assert_eq!(read(&srcpad, 0, 10), Ok(b"0123456789".to_vec()));
+    assert_eq!(read(&srcpad, 3, 1), Ok(b"3".to_vec()));
+    // Spans the empty file
+    assert_eq!(read(&srcpad, 2, 4), Ok(b"2345".to_vec()));
+    // Starts exactly at the end of the first file
+    assert_eq!(read(&srcpad, 4, 3), Ok(b"456".to_vec()));
+    // Backwards into the first file again
+    assert_eq!(read(&srcpad, 1, 5), Ok(b"12345".to_vec()));
+    // Short read at the end of the last file
+    assert_eq!(read(&srcpad, 8, 5), Ok(b"89".to_vec()));
+    assert_eq!(read(&srcpad, 10, 1), Err(gst::FlowReturn::Eos));
+
+    srcpad.activate_mode(gst::PadMode::Pull, false).unwrap();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
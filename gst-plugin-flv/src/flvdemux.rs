@@ -7,6 +7,7 @@
 // except according to those terms.
 
 use std::cmp;
+use std::i32;
 use std::io::{Cursor, Write};
 
 use nom;
@@ -20,13 +21,28 @@ use gst_plugin::element::*;
 use gst_plugin::error::*;
 use gst_plugin_simple::demuxer::*;
 
+use glib;
 use gst;
+use gst::prelude::*;
+
+use gobject_subclass::object::*;
 
 use num_rational::Rational32;
 
 const AUDIO_STREAM_ID: u32 = 0;
 const VIDEO_STREAM_ID: u32 = 1;
 
+const DEFAULT_MAX_ERRORS: i32 = 10;
+
+pub static PROPERTIES: [Property; 1] = [Property::Int(
+    "max-errors",
+    "Max Errors",
+    "Maximum number of corrupted tags to skip before erroring out (-1 = unlimited)",
+    (-1, i32::MAX),
+    DEFAULT_MAX_ERRORS,
+    PropertyMutability::ReadWrite,
+)];
+
 #[derive(Debug)]
 enum State {
     Stopped,
@@ -37,6 +53,8 @@ enum State {
         skip_left: u32,
     },
     Streaming,
+    // Looking for the next valid tag after corrupted data
+    Resyncing,
 }
 
 #[derive(Debug)]
@@ -52,6 +70,11 @@ struct StreamingState {
 
     aac_sequence_header: Option<gst::Buffer>,
     avc_sequence_header: Option<gst::Buffer>,
+
+    // Tags have changed and need to be sent downstream
+    global_tags_pending: bool,
+    audio_tags_pending: bool,
+    video_tags_pending: bool,
 }
 
 impl StreamingState {
@@ -66,6 +89,22 @@ impl StreamingState {
             metadata: None,
             aac_sequence_header: None,
             avc_sequence_header: None,
+            global_tags_pending: true,
+            audio_tags_pending: false,
+            video_tags_pending: false,
+        }
+    }
+
+    fn get_global_tags(&self) -> gst::TagList {
+        match self.metadata {
+            Some(ref metadata) => metadata.to_tags(),
+            None => {
+                let mut tags = gst::TagList::new();
+                tags.get_mut()
+                    .unwrap()
+                    .add::<gst::tags::ContainerFormat>(&"Flash Video", gst::TagMergeMode::Replace);
+                tags
+            }
         }
     }
 }
@@ -139,6 +178,35 @@ impl AudioFormat {
         changed
     }
 
+    fn to_tags(&self) -> gst::TagList {
+        let codec = match self.format {
+            flavors::SoundFormat::PCM_NE | flavors::SoundFormat::PCM_LE => "Raw PCM",
+            flavors::SoundFormat::ADPCM => "Shockwave ADPCM",
+            flavors::SoundFormat::MP3 | flavors::SoundFormat::MP3_8KHZ => {
+                "MPEG-1 Layer 3 (MP3)"
+            }
+            flavors::SoundFormat::NELLYMOSER_16KHZ_MONO
+            | flavors::SoundFormat::NELLYMOSER_8KHZ_MONO
+            | flavors::SoundFormat::NELLYMOSER => "Nellymoser Asao",
+            flavors::SoundFormat::PCM_ALAW => "A-Law",
+            flavors::SoundFormat::PCM_ULAW => "Mu-Law",
+            flavors::SoundFormat::AAC => "MPEG-4 AAC",
+            flavors::SoundFormat::SPEEX => "Speex",
+            flavors::SoundFormat::DEVICE_SPECIFIC => "Device specific",
+        };
+
+        let mut tags = gst::TagList::new();
+        {
+            let tags = tags.get_mut().unwrap();
+            tags.add::<gst::tags::AudioCodec>(&codec, gst::TagMergeMode::Replace);
+            if let Some(bitrate) = self.bitrate {
+                tags.add::<gst::tags::Bitrate>(&bitrate, gst::TagMergeMode::Replace);
+            }
+        }
+
+        tags
+    }
+
     fn to_caps(&self) -> Option<gst::Caps> {
         let mut caps = match self.format {
             flavors::SoundFormat::MP3 | flavors::SoundFormat::MP3_8KHZ => Some(
@@ -310,6 +378,31 @@ impl VideoFormat {
         changed
     }
 
+    fn to_tags(&self) -> gst::TagList {
+        let codec = match self.format {
+            flavors::CodecId::SORENSON_H263 => "Sorenson Spark Video",
+            flavors::CodecId::SCREEN => "Flash Screen Video",
+            flavors::CodecId::VP6 => "On2 VP6 Video",
+            flavors::CodecId::VP6A => "On2 VP6 Video with alpha channel",
+            flavors::CodecId::SCREEN2 => "Flash Screen Video 2",
+            flavors::CodecId::H264 => "H.264 / AVC",
+            flavors::CodecId::H263 => "H.263",
+            flavors::CodecId::MPEG4Part2 => "MPEG-4 Video",
+            flavors::CodecId::JPEG => "JPEG",
+        };
+
+        let mut tags = gst::TagList::new();
+        {
+            let tags = tags.get_mut().unwrap();
+            tags.add::<gst::tags::VideoCodec>(&codec, gst::TagMergeMode::Replace);
+            if let Some(bitrate) = self.bitrate {
+                tags.add::<gst::tags::Bitrate>(&bitrate, gst::TagMergeMode::Replace);
+            }
+        }
+
+        tags
+    }
+
     fn to_caps(&self) -> Option<gst::Caps> {
         let mut caps = match self.format {
             flavors::CodecId::SORENSON_H263 => Some(gst::Caps::new_simple(
@@ -390,8 +483,12 @@ struct Metadata {
     creation_date: Option<String>,
     creator: Option<String>,
     title: Option<String>,
-    metadata_creator: Option<String>, /* TODO: seek_table: _,
-                                       * filepositions / times metadata arrays */
+    artist: Option<String>,
+    encoder: Option<String>,
+    metadata_creator: Option<String>,
+
+    // Keyframe times (nanoseconds) and byte offsets of their tags
+    keyframes: Vec<(u64, u64)>,
 
     audio_bitrate: Option<u32>,
 
@@ -411,7 +508,10 @@ impl Metadata {
             creation_date: None,
             creator: None,
             title: None,
+            artist: None,
+            encoder: None,
             metadata_creator: None,
+            keyframes: Vec::new(),
             audio_bitrate: None,
             video_width: None,
             video_height: None,
@@ -443,6 +543,12 @@ impl Metadata {
                 ("title", &flavors::ScriptDataValue::String(title)) => {
                     metadata.title = Some(String::from(title));
                 }
+                ("artist", &flavors::ScriptDataValue::String(artist)) => {
+                    metadata.artist = Some(String::from(artist));
+                }
+                ("encoder", &flavors::ScriptDataValue::String(encoder)) => {
+                    metadata.encoder = Some(String::from(encoder));
+                }
                 ("metadatacreator", &flavors::ScriptDataValue::String(creator)) => {
                     metadata.metadata_creator = Some(String::from(creator));
                 }
@@ -469,6 +575,10 @@ impl Metadata {
                 ("videodatarate", &flavors::ScriptDataValue::Number(datarate)) => {
                     metadata.video_bitrate = Some((datarate * 1024.0) as u32);
                 }
+                ("keyframes", &flavors::ScriptDataValue::Object(ref objects))
+                | ("keyframes", &flavors::ScriptDataValue::ECMAArray(ref objects)) => {
+                    metadata.keyframes = Metadata::parse_keyframes(objects);
+                }
                 _ => {}
             }
         }
@@ -479,12 +589,143 @@ impl Metadata {
 
         metadata
     }
+
+    // Creation dates are usually in ctime() format, e.g. "Mon Jan 25 10:09:53 2010"
+    fn parse_date_time(date: &str) -> Option<gst::DateTime> {
+        if let Some(date_time) = gst::DateTime::new_from_iso8601_string(date) {
+            return Some(date_time);
+        }
+
+        let fields = date.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return None;
+        }
+
+        let month = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ].iter()
+            .position(|m| *m == fields[1])? as i32 + 1;
+        let day = fields[2].parse::<i32>().ok()?;
+        let year = fields[4].parse::<i32>().ok()?;
+
+        let time = fields[3]
+            .split(':')
+            .map(|v| v.parse::<i32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        if time.len() != 3 {
+            return None;
+        }
+
+        Some(gst::DateTime::new(
+            0.0,
+            year,
+            month,
+            day,
+            time[0],
+            time[1],
+            f64::from(time[2]),
+        ))
+    }
+
+    fn to_tags(&self) -> gst::TagList {
+        let mut tags = gst::TagList::new();
+        {
+            let tags = tags.get_mut().unwrap();
+
+            tags.add::<gst::tags::ContainerFormat>(&"Flash Video", gst::TagMergeMode::Replace);
+
+            if let Some(ref title) = self.title {
+                tags.add::<gst::tags::Title>(&title.as_str(), gst::TagMergeMode::Replace);
+            }
+            if let Some(ref artist) = self.artist {
+                tags.add::<gst::tags::Artist>(&artist.as_str(), gst::TagMergeMode::Replace);
+            }
+            if let Some(encoder) = self.encoder.as_ref().or(self.metadata_creator.as_ref()) {
+                tags.add::<gst::tags::Encoder>(&encoder.as_str(), gst::TagMergeMode::Replace);
+            }
+            if let Some(ref creator) = self.creator {
+                tags.add::<gst::tags::ApplicationName>(
+                    &creator.as_str(),
+                    gst::TagMergeMode::Replace,
+                );
+            }
+            if let Some(date_time) = self.creation_date
+                .as_ref()
+                .and_then(|date| Metadata::parse_date_time(date))
+            {
+                tags.add::<gst::tags::DateTime>(&date_time, gst::TagMergeMode::Replace);
+            }
+            if self.duration.is_some() {
+                tags.add::<gst::tags::Duration>(&self.duration, gst::TagMergeMode::Replace);
+            }
+        }
+
+        tags
+    }
+
+    fn parse_keyframes(objects: &[flavors::ScriptDataObject]) -> Vec<(u64, u64)> {
+        let mut times = None;
+        let mut positions = None;
+
+        for object in objects {
+            match (object.name, &object.data) {
+                ("times", &flavors::ScriptDataValue::StrictArray(ref values)) => {
+                    times = Some(values);
+                }
+                ("filepositions", &flavors::ScriptDataValue::StrictArray(ref values)) => {
+                    positions = Some(values);
+                }
+                _ => {}
+            }
+        }
+
+        let (times, positions) = match (times, positions) {
+            (Some(times), Some(positions)) => (times, positions),
+            _ => return Vec::new(),
+        };
+
+        let mut keyframes = times
+            .iter()
+            .zip(positions.iter())
+            .filter_map(|(time, position)| match (time, position) {
+                (
+                    &flavors::ScriptDataValue::Number(time),
+                    &flavors::ScriptDataValue::Number(position),
+                ) if time >= 0.0 && position >= 0.0 =>
+                {
+                    Some(((time * 1000.0 * 1000.0 * 1000.0) as u64, position as u64))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // Broken muxers sometimes write the table unsorted
+        keyframes.sort();
+        keyframes.dedup_by_key(|&mut (time, _)| time);
+
+        keyframes
+    }
 }
 
 pub struct FlvDemux {
     cat: gst::DebugCategory,
     state: State,
     adapter: Adapter,
+    // Upstream byte offset right after the last byte pushed into the adapter
+    offset: u64,
+    // Upstream byte offset of the first tag, i.e. right after the FLV header
+    data_offset: u64,
+    // Known keyframe times (nanoseconds) and byte offsets of their tags, sorted by time.
+    // Filled from the onMetaData keyframes table and while streaming
+    keyframes: Vec<(u64, u64)>,
+    // Size of the last tag, for checking the following PreviousTagSize
+    last_tag_size: Option<u32>,
+    // Number of corrupted tags skipped so far and how many are allowed
+    errors: u32,
+    max_errors: i32,
+    // Tags of deselected streams are skipped without creating buffers
+    audio_selected: bool,
+    video_selected: bool,
     // Only in >= State::Streaming
     streaming_state: Option<StreamingState>,
 }
@@ -499,6 +740,14 @@ impl FlvDemux {
             ),
             state: State::Stopped,
             adapter: Adapter::new(),
+            offset: 0,
+            data_offset: 0,
+            keyframes: Vec::new(),
+            last_tag_size: None,
+            errors: 0,
+            max_errors: DEFAULT_MAX_ERRORS,
+            audio_selected: true,
+            video_selected: true,
             streaming_state: None,
         }
     }
@@ -507,6 +756,117 @@ impl FlvDemux {
         Box::new(Self::new(demuxer))
     }
 
+    fn add_keyframe(&mut self, time: u64, offset: u64) {
+        match self.keyframes.binary_search_by_key(&time, |&(time, _)| time) {
+            Ok(_) => (),
+            Err(idx) => self.keyframes.insert(idx, (time, offset)),
+        }
+    }
+
+    // Checks if the data at the start of a PreviousTagSize looks like a tag header,
+    // returns the tag data size if it does
+    fn check_tag_header(data: &[u8; 15]) -> Option<u32> {
+        let tag_type = data[4];
+        let data_size = (u32::from(data[5]) << 16) | (u32::from(data[6]) << 8) | u32::from(data[7]);
+
+        match tag_type {
+            8 | 9 if data_size > 0 => (),
+            18 => (),
+            _ => return None,
+        }
+
+        // Stream ID is always 0
+        if data[12] != 0 || data[13] != 0 || data[14] != 0 {
+            return None;
+        }
+
+        Some(data_size)
+    }
+
+    fn handle_parse_error(
+        &mut self,
+        demuxer: &Element,
+        reason: &str,
+    ) -> Result<HandleBufferResult, FlowError> {
+        // Offset of the tag header, after the previous tag size
+        let tag_offset = self.offset - self.adapter.get_available() as u64 + 4;
+
+        self.errors += 1;
+        gst_warning!(
+            self.cat,
+            obj: demuxer,
+            "{} at offset {} ({} errors so far)",
+            reason,
+            tag_offset,
+            self.errors
+        );
+
+        if self.max_errors >= 0 && self.errors > self.max_errors as u32 {
+            return Err(FlowError::Error(gst_error_msg!(
+                gst::StreamError::Demux,
+                [
+                    "{} at offset {}, too many errors ({})",
+                    reason,
+                    tag_offset,
+                    self.errors
+                ]
+            )));
+        }
+
+        gst_element_warning!(
+            demuxer,
+            gst::StreamError::Demux,
+            ["{} at offset {}, resynchronizing", reason, tag_offset]
+        );
+
+        // The tag at the current position is broken, start looking at the next byte
+        self.adapter.flush(1).unwrap();
+        self.last_tag_size = None;
+        self.state = State::Resyncing;
+
+        Ok(HandleBufferResult::Again)
+    }
+
+    fn resync(&mut self, demuxer: &Element) -> Result<HandleBufferResult, FlowError> {
+        while self.adapter.get_available() >= 15 {
+            let mut data = [0u8; 15];
+            self.adapter.peek_into(&mut data).unwrap();
+
+            if let Some(data_size) = Self::check_tag_header(&data) {
+                // A plausible tag is only accepted if the PreviousTagSize after it
+                // matches its size
+                let tag_size = 11 + data_size;
+                let needed = 4 + tag_size as usize + 4;
+                if self.adapter.get_available() < needed {
+                    return Ok(HandleBufferResult::NeedMoreData);
+                }
+
+                let previous_size = {
+                    let data = self.adapter.peek(needed).unwrap();
+                    let mut cursor = Cursor::new(&data[needed - 4..]);
+                    cursor.read_u32be().unwrap()
+                };
+
+                if previous_size == tag_size {
+                    let tag_offset = self.offset - self.adapter.get_available() as u64 + 4;
+                    gst_debug!(
+                        self.cat,
+                        obj: demuxer,
+                        "Found next tag at offset {}",
+                        tag_offset
+                    );
+
+                    self.state = State::Streaming;
+                    return Ok(HandleBufferResult::Again);
+                }
+            }
+
+            self.adapter.flush(1).unwrap();
+        }
+
+        Ok(HandleBufferResult::NeedMoreData)
+    }
+
     fn handle_script_tag(
         &mut self,
         demuxer: &Element,
@@ -532,6 +892,10 @@ impl FlvDemux {
                 let metadata = Metadata::new(script_data);
                 gst_debug!(self.cat, obj: demuxer, "Got metadata: {:?}", metadata);
 
+                for &(time, offset) in &metadata.keyframes {
+                    self.add_keyframe(time, offset);
+                }
+
                 let streaming_state = self.streaming_state.as_mut().unwrap();
 
                 let audio_changed = streaming_state
@@ -546,6 +910,11 @@ impl FlvDemux {
                     .unwrap_or(false);
                 streaming_state.metadata = Some(metadata);
 
+                // Bitrates might have changed too
+                streaming_state.global_tags_pending = true;
+                streaming_state.audio_tags_pending = streaming_state.audio.is_some();
+                streaming_state.video_tags_pending = streaming_state.video.is_some();
+
                 if audio_changed || video_changed {
                     let mut streams = Vec::new();
 
@@ -608,6 +977,7 @@ impl FlvDemux {
             let caps = new_audio_format.to_caps();
             if let Some(caps) = caps {
                 streaming_state.audio = Some(new_audio_format);
+                streaming_state.audio_tags_pending = true;
                 let stream = Stream::new(AUDIO_STREAM_ID, caps, String::from("audio"));
                 if new_stream {
                     return Ok(HandleBufferResult::StreamAdded(stream));
@@ -666,7 +1036,7 @@ impl FlvDemux {
             self.adapter.peek_into(&mut data).unwrap();
             match flavors::aac_audio_packet_header(&data[16..]) {
                 IResult::Error(_) | IResult::Incomplete(_) => {
-                    unimplemented!();
+                    return self.handle_parse_error(demuxer, "Invalid AAC packet header");
                 }
                 IResult::Done(_, header) => {
                     gst_trace!(self.cat, obj: demuxer, "Got AAC packet header {:?}", header);
@@ -699,7 +1069,8 @@ impl FlvDemux {
 
         let streaming_state = self.streaming_state.as_ref().unwrap();
 
-        if streaming_state.audio == None {
+        // Sequence headers are still kept above for when the stream is selected again
+        if streaming_state.audio == None || !self.audio_selected {
             self.adapter
                 .flush((tag_header.data_size + 15) as usize)
                 .unwrap();
@@ -784,6 +1155,7 @@ impl FlvDemux {
             let caps = new_video_format.to_caps();
             if let Some(caps) = caps {
                 streaming_state.video = Some(new_video_format);
+                streaming_state.video_tags_pending = true;
                 let stream = Stream::new(VIDEO_STREAM_ID, caps, String::from("video"));
                 if new_stream {
                     return Ok(HandleBufferResult::StreamAdded(stream));
@@ -844,7 +1216,7 @@ impl FlvDemux {
             self.adapter.peek_into(&mut data).unwrap();
             match flavors::avc_video_packet_header(&data[16..]) {
                 IResult::Error(_) | IResult::Incomplete(_) => {
-                    unimplemented!();
+                    return self.handle_parse_error(demuxer, "Invalid AVC packet header");
                 }
                 IResult::Done(_, header) => {
                     gst_trace!(self.cat, obj: demuxer, "Got AVC packet header {:?}", header);
@@ -884,7 +1256,7 @@ impl FlvDemux {
 
         let streaming_state = self.streaming_state.as_ref().unwrap();
 
-        if streaming_state.video == None {
+        if streaming_state.video == None || !self.video_selected {
             self.adapter
                 .flush((tag_header.data_size + 15) as usize)
                 .unwrap();
@@ -951,9 +1323,43 @@ impl FlvDemux {
         Ok(HandleBufferResult::BufferForStream(VIDEO_STREAM_ID, buffer))
     }
 
+    fn get_pending_tags(&mut self, demuxer: &Element) -> Option<HandleBufferResult> {
+        let streaming_state = self.streaming_state.as_mut().unwrap();
+
+        if streaming_state.global_tags_pending {
+            streaming_state.global_tags_pending = false;
+            let tags = streaming_state.get_global_tags();
+            gst_debug!(self.cat, obj: demuxer, "Got global tags {:?}", tags);
+            return Some(HandleBufferResult::GlobalTags(tags));
+        }
+
+        if streaming_state.audio_tags_pending {
+            streaming_state.audio_tags_pending = false;
+            if let Some(ref audio) = streaming_state.audio {
+                return Some(HandleBufferResult::StreamTags(
+                    AUDIO_STREAM_ID,
+                    audio.to_tags(),
+                ));
+            }
+        }
+
+        if streaming_state.video_tags_pending {
+            streaming_state.video_tags_pending = false;
+            if let Some(ref video) = streaming_state.video {
+                return Some(HandleBufferResult::StreamTags(
+                    VIDEO_STREAM_ID,
+                    video.to_tags(),
+                ));
+            }
+        }
+
+        None
+    }
+
     fn update_state(&mut self, demuxer: &Element) -> Result<HandleBufferResult, FlowError> {
         match self.state {
             State::Stopped => unreachable!(),
+            State::Resyncing => self.resync(demuxer),
             State::NeedHeader => {
                 while self.adapter.get_available() >= 9 {
                     let mut data = [0u8; 9];
@@ -974,6 +1380,9 @@ impl FlvDemux {
 
                             self.adapter.flush(9).unwrap();
 
+                            self.data_offset = self.offset - self.adapter.get_available() as u64
+                                + skip as u64;
+
                             self.state = State::Skipping {
                                 audio: header.audio,
                                 video: header.video,
@@ -1009,16 +1418,23 @@ impl FlvDemux {
                 Ok(HandleBufferResult::Again)
             }
             State::Streaming => {
+                if let Some(res) = self.get_pending_tags(demuxer) {
+                    return Ok(res);
+                }
+
                 if self.adapter.get_available() < 16 {
                     return Ok(HandleBufferResult::NeedMoreData);
                 }
 
+                // Offset of the tag header, after the previous tag size
+                let tag_offset = self.offset - self.adapter.get_available() as u64 + 4;
+
                 let mut data = [0u8; 16];
                 self.adapter.peek_into(&mut data).unwrap();
 
                 match nom::be_u32(&data[0..4]) {
                     IResult::Error(_) | IResult::Incomplete(_) => {
-                        unimplemented!();
+                        return self.handle_parse_error(demuxer, "Invalid previous tag size");
                     }
                     IResult::Done(_, previous_size) => {
                         gst_trace!(
@@ -1027,14 +1443,30 @@ impl FlvDemux {
                             "Previous tag size {}",
                             previous_size
                         );
-                        // Nothing to do here, we just consume it for now
+
+                        // Many muxers write wrong sizes, only complain about it
+                        match self.last_tag_size {
+                            Some(last_tag_size) if last_tag_size != previous_size => {
+                                gst_debug!(
+                                    self.cat,
+                                    obj: demuxer,
+                                    "Previous tag size {} doesn't match last tag size {}",
+                                    previous_size,
+                                    last_tag_size
+                                );
+                            }
+                            _ => (),
+                        }
                     }
                 }
 
                 let tag_header = match flavors::tag_header(&data[4..]) {
-                    IResult::Error(_) | IResult::Incomplete(_) => unimplemented!(),
+                    IResult::Error(_) | IResult::Incomplete(_) => {
+                        return self.handle_parse_error(demuxer, "Invalid tag header");
+                    }
                     IResult::Done(_, tag_header) => tag_header,
                 };
+                let tag_size = 11 + tag_header.data_size;
 
                 let res = match tag_header.tag_type {
                     flavors::TagType::Script => {
@@ -1046,7 +1478,9 @@ impl FlvDemux {
                         gst_trace!(self.cat, obj: demuxer, "Found audio tag");
 
                         let data_header = match flavors::audio_data_header(&data[15..]) {
-                            IResult::Error(_) | IResult::Incomplete(_) => unimplemented!(),
+                            IResult::Error(_) | IResult::Incomplete(_) => {
+                                return self.handle_parse_error(demuxer, "Invalid audio tag");
+                            }
                             IResult::Done(_, data_header) => data_header,
                         };
 
@@ -1056,7 +1490,9 @@ impl FlvDemux {
                         gst_trace!(self.cat, obj: demuxer, "Found video tag");
 
                         let data_header = match flavors::video_data_header(&data[15..]) {
-                            IResult::Error(_) | IResult::Incomplete(_) => unimplemented!(),
+                            IResult::Error(_) | IResult::Incomplete(_) => {
+                                return self.handle_parse_error(demuxer, "Invalid video tag");
+                            }
                             IResult::Done(_, data_header) => data_header,
                         };
 
@@ -1064,7 +1500,32 @@ impl FlvDemux {
                     }
                 };
 
-                if let Ok(HandleBufferResult::BufferForStream(_, ref buffer)) = res {
+                // Remember the size once the whole tag is consumed
+                let position = self.offset - self.adapter.get_available() as u64;
+                if position == tag_offset + u64::from(tag_size) {
+                    self.last_tag_size = Some(tag_size);
+                }
+
+                if let Ok(HandleBufferResult::BufferForStream(index, ref buffer)) = res {
+                    // Remember all keyframes we pass by for seeking, or all audio
+                    // frames if there is no video
+                    let expect_video = self.streaming_state.as_ref().unwrap().expect_video;
+                    let is_keyframe = if index == VIDEO_STREAM_ID {
+                        !buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT)
+                    } else {
+                        !expect_video
+                    };
+                    let time = if buffer.get_dts() != gst::CLOCK_TIME_NONE {
+                        buffer.get_dts()
+                    } else {
+                        buffer.get_pts()
+                    };
+                    if is_keyframe {
+                        if let Some(time) = time.0 {
+                            self.add_keyframe(time, tag_offset);
+                        }
+                    }
+
                     let streaming_state = self.streaming_state.as_mut().unwrap();
 
                     if buffer.get_pts() != gst::CLOCK_TIME_NONE {
@@ -1096,6 +1557,12 @@ impl DemuxerImpl for FlvDemux {
         _random_access: bool,
     ) -> Result<(), gst::ErrorMessage> {
         self.state = State::NeedHeader;
+        self.offset = 0;
+        self.data_offset = 0;
+        self.last_tag_size = None;
+        self.errors = 0;
+        self.audio_selected = true;
+        self.video_selected = true;
 
         Ok(())
     }
@@ -1103,6 +1570,10 @@ impl DemuxerImpl for FlvDemux {
     fn stop(&mut self, demuxer: &Element) -> Result<(), gst::ErrorMessage> {
         self.state = State::Stopped;
         self.adapter.clear();
+        self.offset = 0;
+        self.data_offset = 0;
+        self.keyframes.clear();
+        self.last_tag_size = None;
         self.streaming_state = None;
 
         Ok(())
@@ -1114,7 +1585,65 @@ impl DemuxerImpl for FlvDemux {
         start: gst::ClockTime,
         stop: gst::ClockTime,
     ) -> Result<SeekResult, gst::ErrorMessage> {
-        unimplemented!();
+        match self.state {
+            State::Streaming | State::Resyncing => (),
+            _ => return Ok(SeekResult::TooEarly),
+        }
+
+        let start = start.0.unwrap_or(0);
+
+        if let Some(duration) = self.get_duration(demuxer).0 {
+            if start >= duration {
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Seek position {} after duration {}",
+                    start,
+                    duration
+                );
+                return Ok(SeekResult::Eos);
+            }
+        }
+
+        // Go to the last keyframe before the seek position we know of. If the index
+        // does not cover the seek position yet, we continue scanning tags from there
+        // on and downstream clips everything before the segment start
+        let offset = match self.keyframes.iter().rev().find(|&&(time, _)| time <= start) {
+            Some(&(time, offset)) if offset >= self.data_offset + 4 => {
+                gst_debug!(
+                    self.cat,
+                    obj: demuxer,
+                    "Seeking to keyframe at {} with tag offset {}",
+                    gst::ClockTime::from_nseconds(time),
+                    offset
+                );
+                // Start reading at the previous tag size in front of the tag
+                offset - 4
+            }
+            _ => {
+                gst_debug!(self.cat, obj: demuxer, "Seeking to first tag");
+                self.data_offset
+            }
+        };
+
+        gst_debug!(
+            self.cat,
+            obj: demuxer,
+            "Seeking to {:?}-{:?} at byte offset {}",
+            gst::ClockTime::from_nseconds(start),
+            stop,
+            offset
+        );
+
+        self.adapter.clear();
+        self.offset = offset;
+        self.last_tag_size = None;
+        self.state = State::Streaming;
+        if let Some(ref mut streaming_state) = self.streaming_state {
+            streaming_state.last_position = gst::CLOCK_TIME_NONE;
+        }
+
+        Ok(SeekResult::Ok(offset))
     }
 
     fn handle_buffer(
@@ -1123,6 +1652,13 @@ impl DemuxerImpl for FlvDemux {
         buffer: Option<gst::Buffer>,
     ) -> Result<HandleBufferResult, FlowError> {
         if let Some(buffer) = buffer {
+            // Resynchronize our byte position with upstream if possible
+            if self.adapter.get_available() == 0
+                && buffer.get_offset() != gst::BUFFER_OFFSET_NONE
+            {
+                self.offset = buffer.get_offset();
+            }
+            self.offset += buffer.get_size() as u64;
             self.adapter.push(buffer);
         }
 
@@ -1134,8 +1670,18 @@ impl DemuxerImpl for FlvDemux {
         Ok(())
     }
 
+    fn stream_selected(&mut self, demuxer: &Element, index: StreamIndex, selected: bool) {
+        gst_debug!(self.cat, obj: demuxer, "Stream {} selected: {}", index, selected);
+
+        match index {
+            AUDIO_STREAM_ID => self.audio_selected = selected,
+            VIDEO_STREAM_ID => self.video_selected = selected,
+            _ => (),
+        }
+    }
+
     fn is_seekable(&self, demuxer: &Element) -> bool {
-        false
+        self.streaming_state.is_some()
     }
 
     fn get_position(&self, demuxer: &Element) -> gst::ClockTime {
@@ -1157,4 +1703,20 @@ impl DemuxerImpl for FlvDemux {
 
         gst::CLOCK_TIME_NONE
     }
+
+    fn set_property(&mut self, _demuxer: &Element, property: &Property, value: &glib::Value) {
+        match *property {
+            Property::Int("max-errors", ..) => {
+                self.max_errors = value.get().unwrap();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _demuxer: &Element, property: &Property) -> Result<glib::Value, ()> {
+        match *property {
+            Property::Int("max-errors", ..) => Ok(self.max_errors.to_value()),
+            _ => unimplemented!(),
+        }
+    }
 }
@@ -0,0 +1,1410 @@
+// Copyright (C) 2018 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::sync::Mutex;
+use std::u64;
+
+use glib;
+use gst;
+use gst::prelude::*;
+use gst_base;
+use gst_base::prelude::*;
+
+use gst_plugin::aggregator::*;
+use gst_plugin::bytes::*;
+use gst_plugin::element::*;
+
+use gobject_subclass::object::*;
+
+const TAG_TYPE_AUDIO: u8 = 8;
+const TAG_TYPE_VIDEO: u8 = 9;
+const TAG_TYPE_SCRIPT: u8 = 18;
+
+const TAG_HEADER_SIZE: usize = 11;
+const FLV_HEADER_SIZE: usize = 9;
+
+// Number of keyframe index entries that space is reserved for in the onMetaData tag
+// if downstream is seekable. Longer files get a subsampled index
+const INDEX_ENTRIES: usize = 1024;
+
+const AMF_NUMBER: u8 = 0x00;
+const AMF_BOOLEAN: u8 = 0x01;
+const AMF_STRING: u8 = 0x02;
+const AMF_OBJECT: u8 = 0x03;
+const AMF_ECMA_ARRAY: u8 = 0x08;
+const AMF_OBJECT_END: u8 = 0x09;
+const AMF_STRICT_ARRAY: u8 = 0x0a;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamKind {
+    Audio,
+    Video,
+}
+
+#[derive(Debug, Clone)]
+enum Format {
+    Audio {
+        sound_format: u8,
+        sound_rate: u8,
+        sound_size: u8,
+        sound_type: u8,
+        rate: i32,
+        codec_data: Option<gst::Buffer>,
+    },
+    Video {
+        codec_id: u8,
+        width: Option<i32>,
+        height: Option<i32>,
+        framerate: Option<gst::Fraction>,
+        codec_data: Option<gst::Buffer>,
+    },
+}
+
+impl Format {
+    fn from_caps(kind: StreamKind, caps: &gst::CapsRef) -> Option<Format> {
+        let s = caps.get_structure(0)?;
+
+        match kind {
+            StreamKind::Audio => {
+                let rate = s.get::<i32>("rate").unwrap_or(44100);
+                let channels = s.get::<i32>("channels").unwrap_or(1);
+                let sound_type = if channels == 2 { 1 } else { 0 };
+                let sound_rate = match rate {
+                    5512 => Some(0),
+                    11025 => Some(1),
+                    22050 => Some(2),
+                    44100 => Some(3),
+                    _ => None,
+                };
+
+                match s.get_name() {
+                    "audio/mpeg" => match s.get::<i32>("mpegversion") {
+                        // 8kHz MP3 has its own sound format
+                        Some(1) if rate == 8000 => Some(Format::Audio {
+                            sound_format: 14,
+                            sound_rate: 0,
+                            sound_size: 1,
+                            sound_type: sound_type,
+                            rate: rate,
+                            codec_data: None,
+                        }),
+                        Some(1) => Some(Format::Audio {
+                            sound_format: 2,
+                            sound_rate: sound_rate?,
+                            sound_size: 1,
+                            sound_type: sound_type,
+                            rate: rate,
+                            codec_data: None,
+                        }),
+                        // AAC is always signalled as 44.1kHz stereo, the real
+                        // configuration is in the codec data
+                        Some(2) | Some(4) => Some(Format::Audio {
+                            sound_format: 10,
+                            sound_rate: 3,
+                            sound_size: 1,
+                            sound_type: 1,
+                            rate: rate,
+                            codec_data: Some(s.get::<gst::Buffer>("codec_data")?),
+                        }),
+                        _ => None,
+                    },
+                    "audio/x-raw" => {
+                        let sound_size = match s.get::<&str>("format") {
+                            Some("U8") => 0,
+                            Some("S16LE") => 1,
+                            _ => return None,
+                        };
+
+                        Some(Format::Audio {
+                            sound_format: 3,
+                            sound_rate: sound_rate?,
+                            sound_size: sound_size,
+                            sound_type: sound_type,
+                            rate: rate,
+                            codec_data: None,
+                        })
+                    }
+                    // Speex is always 16kHz mono, the rate field is ignored
+                    "audio/x-speex" => Some(Format::Audio {
+                        sound_format: 11,
+                        sound_rate: 0,
+                        sound_size: 1,
+                        sound_type: 0,
+                        rate: rate,
+                        codec_data: None,
+                    }),
+                    _ => None,
+                }
+            }
+            StreamKind::Video => {
+                let (codec_id, codec_data) = match s.get_name() {
+                    "video/x-h264" => (7, Some(s.get::<gst::Buffer>("codec_data")?)),
+                    "video/x-vp6-flash" => (4, None),
+                    "video/x-flash-video" => (2, None),
+                    _ => return None,
+                };
+
+                Some(Format::Video {
+                    codec_id: codec_id,
+                    width: s.get::<i32>("width"),
+                    height: s.get::<i32>("height"),
+                    framerate: s.get::<gst::Fraction>("framerate"),
+                    codec_data: codec_data,
+                })
+            }
+        }
+    }
+
+    fn get_codec_data(&self) -> Option<&gst::Buffer> {
+        match *self {
+            Format::Audio { ref codec_data, .. } | Format::Video { ref codec_data, .. } => {
+                codec_data.as_ref()
+            }
+        }
+    }
+
+    // Bytes between the tag header and the payload
+    fn write_payload_header(
+        &self,
+        data: &mut Vec<u8>,
+        sequence_header: bool,
+        keyframe: bool,
+        composition_time: i32,
+    ) {
+        match *self {
+            Format::Audio {
+                sound_format,
+                sound_rate,
+                sound_size,
+                sound_type,
+                ..
+            } => {
+                data.push((sound_format << 4) | (sound_rate << 2) | (sound_size << 1) | sound_type);
+                if sound_format == 10 {
+                    data.push(if sequence_header { 0 } else { 1 });
+                }
+            }
+            Format::Video { codec_id, .. } => {
+                let frame_type = if keyframe { 1 } else { 2 };
+                data.push((frame_type << 4) | codec_id);
+                match codec_id {
+                    7 => {
+                        data.push(if sequence_header { 0 } else { 1 });
+                        data.write_intbe(i64::from(composition_time), 3).unwrap();
+                    }
+                    // No size adjustment
+                    4 => data.push(0),
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+struct Stream {
+    pad: gst_base::AggregatorPad,
+    kind: StreamKind,
+    format: Option<Format>,
+    // Codec data changed and has to be written before the next buffer
+    codec_data_pending: bool,
+    // Running time of the end of the last buffer
+    last_time: Option<u64>,
+}
+
+struct State {
+    streams: Vec<Stream>,
+    header_written: bool,
+    // Downstream can seek back and we have reserved space to update the metadata
+    seekable: bool,
+    // Size of FLV header and onMetaData tag, which is rewritten at EOS
+    metadata_size: usize,
+    // Audio/video flags of the stream in the FLV header
+    have_audio: bool,
+    have_video: bool,
+    bytes_written: u64,
+    // Running time and byte offset of each keyframe tag
+    keyframes: Vec<(u64, u64)>,
+    rewritten: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            streams: Vec::new(),
+            header_written: false,
+            seekable: false,
+            metadata_size: 0,
+            have_audio: false,
+            have_video: false,
+            bytes_written: 0,
+            keyframes: Vec::new(),
+            rewritten: false,
+        }
+    }
+}
+
+impl State {
+    fn reset(&mut self) {
+        let streams = self.streams
+            .drain(..)
+            .map(|stream| {
+                let codec_data_pending = stream
+                    .format
+                    .as_ref()
+                    .and_then(|f| f.get_codec_data())
+                    .is_some();
+
+                Stream {
+                    codec_data_pending: codec_data_pending,
+                    last_time: None,
+                    ..stream
+                }
+            })
+            .collect();
+
+        *self = Self {
+            streams: streams,
+            ..Self::default()
+        };
+    }
+
+    fn get_duration(&self) -> u64 {
+        self.streams
+            .iter()
+            .filter_map(|stream| stream.last_time)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+struct FlvMux {
+    cat: gst::DebugCategory,
+    state: Mutex<State>,
+}
+
+fn write_tag_header(data: &mut Vec<u8>, tag_type: u8, data_size: usize, timestamp: u64) {
+    // Timestamps are in milliseconds, with the upper 8 bits stored separately
+    let timestamp = (timestamp / gst::MSECOND_VAL) as u32;
+
+    data.push(tag_type);
+    data.write_uintbe(data_size as u64, 3).unwrap();
+    data.write_uintbe(u64::from(timestamp & 0x00ff_ffff), 3).unwrap();
+    data.push((timestamp >> 24) as u8);
+    // Stream ID
+    data.write_uintbe(0, 3).unwrap();
+}
+
+fn write_tag(data: &mut Vec<u8>, tag_type: u8, timestamp: u64, payload: &[u8]) {
+    write_tag_header(data, tag_type, payload.len(), timestamp);
+    data.extend_from_slice(payload);
+    data.write_u32be((TAG_HEADER_SIZE + payload.len()) as u32)
+        .unwrap();
+}
+
+fn write_amf_string(data: &mut Vec<u8>, s: &str) {
+    data.write_u16be(s.len() as u16).unwrap();
+    data.extend_from_slice(s.as_bytes());
+}
+
+fn write_amf_number(data: &mut Vec<u8>, name: &str, value: f64) {
+    write_amf_string(data, name);
+    data.push(AMF_NUMBER);
+    data.write_f64be(value).unwrap();
+}
+
+fn write_amf_boolean(data: &mut Vec<u8>, name: &str, value: bool) {
+    write_amf_string(data, name);
+    data.push(AMF_BOOLEAN);
+    data.push(value as u8);
+}
+
+fn write_amf_strict_array(data: &mut Vec<u8>, name: &str, values: &[f64]) {
+    write_amf_string(data, name);
+    data.push(AMF_STRICT_ARRAY);
+    data.write_u32be(values.len() as u32).unwrap();
+    for value in values {
+        data.push(AMF_NUMBER);
+        data.write_f64be(*value).unwrap();
+    }
+}
+
+fn write_amf_end(data: &mut Vec<u8>) {
+    data.write_uintbe(0, 2).unwrap();
+    data.push(AMF_OBJECT_END);
+}
+
+impl FlvMux {
+    fn new(_aggregator: &Aggregator) -> Self {
+        Self {
+            cat: gst::DebugCategory::new(
+                "rsflvmux",
+                gst::DebugColorFlags::empty(),
+                "Rust FLV muxer",
+            ),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn class_init(klass: &mut AggregatorClass) {
+        klass.set_metadata(
+            "FLV muxer",
+            "Codec/Muxer",
+            "Muxes video/audio streams into a FLV stream",
+            "Sebastian Dröge <sebastian@centricular.com>",
+        );
+
+        let caps = gst::Caps::new_simple("video/x-flv", &[]);
+        let src_pad_template = gst::PadTemplate::new(
+            "src",
+            gst::PadDirection::Src,
+            gst::PadPresence::Always,
+            &caps,
+        );
+        klass.add_pad_template(src_pad_template);
+
+        let mut caps = gst::Caps::new_simple(
+            "audio/mpeg",
+            &[
+                ("mpegversion", &1i32),
+                ("layer", &3i32),
+                ("channels", &gst::IntRange::<i32>::new(1, 2)),
+                (
+                    "rate",
+                    &gst::List::new(&[&5512i32, &8000i32, &11025i32, &22050i32, &44100i32]),
+                ),
+            ],
+        );
+        {
+            let caps = caps.get_mut().unwrap();
+            caps.append(gst::Caps::new_simple(
+                "audio/mpeg",
+                &[
+                    ("mpegversion", &gst::List::new(&[&2i32, &4i32])),
+                    ("stream-format", &"raw"),
+                ],
+            ));
+            caps.append(gst::Caps::new_simple(
+                "audio/x-raw",
+                &[
+                    ("format", &gst::List::new(&[&"U8", &"S16LE"])),
+                    ("layout", &"interleaved"),
+                    ("channels", &gst::IntRange::<i32>::new(1, 2)),
+                    (
+                        "rate",
+                        &gst::List::new(&[&5512i32, &11025i32, &22050i32, &44100i32]),
+                    ),
+                ],
+            ));
+            caps.append(gst::Caps::new_simple(
+                "audio/x-speex",
+                &[("channels", &1i32), ("rate", &16000i32)],
+            ));
+        }
+        let audio_pad_template = gst::PadTemplate::new(
+            "audio",
+            gst::PadDirection::Sink,
+            gst::PadPresence::Request,
+            &caps,
+        );
+        klass.add_pad_template(audio_pad_template);
+
+        let mut caps = gst::Caps::new_simple(
+            "video/x-h264",
+            &[("stream-format", &"avc"), ("alignment", &"au")],
+        );
+        {
+            let caps = caps.get_mut().unwrap();
+            caps.append(gst::Caps::new_simple("video/x-vp6-flash", &[]));
+            caps.append(gst::Caps::new_simple(
+                "video/x-flash-video",
+                &[("flvversion", &1i32)],
+            ));
+        }
+        let video_pad_template = gst::PadTemplate::new(
+            "video",
+            gst::PadDirection::Sink,
+            gst::PadPresence::Request,
+            &caps,
+        );
+        klass.add_pad_template(video_pad_template);
+    }
+
+    fn init(element: &Aggregator) -> Box<AggregatorImpl<Aggregator>> {
+        let imp = Self::new(element);
+        Box::new(imp)
+    }
+
+    fn get_running_time(pad: &gst_base::AggregatorPad, timestamp: gst::ClockTime) -> Option<u64> {
+        let segment = pad.get_segment();
+        match segment.downcast_ref::<gst::ClockTime>() {
+            Some(segment) => segment.to_running_time(timestamp).0,
+            None => timestamp.0,
+        }
+    }
+
+    fn is_downstream_seekable(&self, aggregator: &Aggregator) -> bool {
+        use gst::QueryView;
+
+        let srcpad = aggregator.get_static_pad("src").unwrap();
+        let mut query = gst::Query::new_seeking(gst::Format::Bytes);
+        if !srcpad.peer_query(&mut query) {
+            return false;
+        }
+
+        match query.view() {
+            QueryView::Seeking(ref q) => q.get_result().0,
+            _ => unreachable!(),
+        }
+    }
+
+    fn create_metadata(&self, state: &State, with_index: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        data.push(AMF_STRING);
+        write_amf_string(&mut data, "onMetaData");
+        data.push(AMF_ECMA_ARRAY);
+        // Only a hint, not required to be exact
+        data.write_u32be(0).unwrap();
+
+        if state.seekable {
+            let duration = if with_index {
+                state.get_duration() as f64 / gst::SECOND_VAL as f64
+            } else {
+                0.0
+            };
+            let filesize = if with_index {
+                state.bytes_written as f64
+            } else {
+                0.0
+            };
+
+            write_amf_number(&mut data, "duration", duration);
+            write_amf_number(&mut data, "filesize", filesize);
+        }
+
+        for stream in &state.streams {
+            match stream.format {
+                Some(Format::Audio {
+                    sound_format,
+                    sound_size,
+                    sound_type,
+                    rate,
+                    ..
+                }) => {
+                    write_amf_number(&mut data, "audiocodecid", f64::from(sound_format));
+                    write_amf_number(&mut data, "audiosamplerate", f64::from(rate));
+                    write_amf_number(
+                        &mut data,
+                        "audiosamplesize",
+                        if sound_size == 1 { 16.0 } else { 8.0 },
+                    );
+                    write_amf_boolean(&mut data, "stereo", sound_type == 1);
+                }
+                Some(Format::Video {
+                    codec_id,
+                    width,
+                    height,
+                    framerate,
+                    ..
+                }) => {
+                    write_amf_number(&mut data, "videocodecid", f64::from(codec_id));
+                    if let Some(width) = width {
+                        write_amf_number(&mut data, "width", f64::from(width));
+                    }
+                    if let Some(height) = height {
+                        write_amf_number(&mut data, "height", f64::from(height));
+                    }
+                    if let Some(framerate) = framerate {
+                        if *framerate.denom() != 0 {
+                            write_amf_number(
+                                &mut data,
+                                "framerate",
+                                f64::from(*framerate.numer()) / f64::from(*framerate.denom()),
+                            );
+                        }
+                    }
+                }
+                None => (),
+            }
+        }
+
+        write_amf_string(&mut data, "metadatacreator");
+        data.push(AMF_STRING);
+        write_amf_string(&mut data, "GStreamer Rust FLV muxer");
+
+        if state.seekable {
+            // Always write the same number of entries so that the tag keeps its size
+            // when it is rewritten at EOS. Unused entries repeat the last keyframe
+            let mut times = Vec::with_capacity(INDEX_ENTRIES);
+            let mut positions = Vec::with_capacity(INDEX_ENTRIES);
+
+            if with_index && !state.keyframes.is_empty() {
+                let n_keyframes = state.keyframes.len();
+                for i in 0..INDEX_ENTRIES {
+                    let idx = if n_keyframes > INDEX_ENTRIES {
+                        i * n_keyframes / INDEX_ENTRIES
+                    } else {
+                        cmp::min(i, n_keyframes - 1)
+                    };
+                    let (time, position) = state.keyframes[idx];
+                    times.push(time as f64 / gst::SECOND_VAL as f64);
+                    positions.push(position as f64);
+                }
+            } else {
+                times.resize(INDEX_ENTRIES, 0.0);
+                positions.resize(INDEX_ENTRIES, 0.0);
+            }
+
+            write_amf_string(&mut data, "keyframes");
+            data.push(AMF_OBJECT);
+            write_amf_strict_array(&mut data, "times", &times);
+            write_amf_strict_array(&mut data, "filepositions", &positions);
+            write_amf_end(&mut data);
+        }
+
+        write_amf_end(&mut data);
+
+        data
+    }
+
+    fn create_header(&self, state: &State, with_index: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        let mut flags = 0u8;
+        if state.have_audio {
+            flags |= 0x04;
+        }
+        if state.have_video {
+            flags |= 0x01;
+        }
+
+        data.extend_from_slice(b"FLV");
+        data.push(1);
+        data.push(flags);
+        data.write_u32be(FLV_HEADER_SIZE as u32).unwrap();
+        // PreviousTagSize0
+        data.write_u32be(0).unwrap();
+
+        let metadata = self.create_metadata(state, with_index);
+        write_tag(&mut data, TAG_TYPE_SCRIPT, 0, &metadata);
+
+        data
+    }
+
+    fn create_sequence_header(stream: &Stream, timestamp: u64) -> Option<Vec<u8>> {
+        let format = stream.format.as_ref()?;
+        let codec_data = format.get_codec_data()?;
+        let map = codec_data.map_readable()?;
+
+        let mut payload = Vec::new();
+        format.write_payload_header(&mut payload, true, true, 0);
+        payload.extend_from_slice(map.as_slice());
+
+        let tag_type = match stream.kind {
+            StreamKind::Audio => TAG_TYPE_AUDIO,
+            StreamKind::Video => TAG_TYPE_VIDEO,
+        };
+
+        let mut data = Vec::new();
+        write_tag(&mut data, tag_type, timestamp, &payload);
+
+        Some(data)
+    }
+
+    fn write_header(
+        &self,
+        element: &gst::Element,
+        state: &mut State,
+        seekable: bool,
+    ) -> gst::Buffer {
+        state.seekable = seekable;
+        state.have_audio = state.streams.iter().any(|s| s.kind == StreamKind::Audio);
+        state.have_video = state.streams.iter().any(|s| s.kind == StreamKind::Video);
+
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Writing header (audio {}, video {}, seekable {})",
+            state.have_audio,
+            state.have_video,
+            state.seekable
+        );
+
+        let mut data = self.create_header(state, false);
+        state.metadata_size = data.len();
+
+        for stream in &mut state.streams {
+            if let Some(sequence_header) = Self::create_sequence_header(stream, 0) {
+                data.extend_from_slice(&sequence_header);
+            }
+            stream.codec_data_pending = false;
+        }
+
+        state.header_written = true;
+        state.bytes_written = data.len() as u64;
+
+        let mut buffer = gst::Buffer::from_mut_slice(data).unwrap();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_offset(0);
+            buffer.set_offset_end(state.bytes_written);
+            buffer.set_flags(gst::BufferFlags::HEADER);
+        }
+
+        buffer
+    }
+
+    // Header with the final duration, file size and index, replacing the first
+    // metadata_size bytes of the output
+    fn create_final_header(
+        &self,
+        element: &gst::Element,
+        state: &mut State,
+    ) -> Option<gst::Buffer> {
+        if !state.header_written || !state.seekable || state.rewritten {
+            return None;
+        }
+        state.rewritten = true;
+
+        let data = self.create_header(state, true);
+        if data.len() != state.metadata_size {
+            gst_warning!(
+                self.cat,
+                obj: element,
+                "Metadata size changed from {} to {}, not rewriting",
+                state.metadata_size,
+                data.len()
+            );
+            return None;
+        }
+
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Rewriting header with duration {} and {} keyframes",
+            gst::ClockTime::from_nseconds(state.get_duration()),
+            state.keyframes.len()
+        );
+
+        let mut buffer = gst::Buffer::from_mut_slice(data).unwrap();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_offset(0);
+            buffer.set_offset_end(state.metadata_size as u64);
+        }
+
+        Some(buffer)
+    }
+
+    fn rewrite_header(&self, aggregator: &Aggregator) {
+        let buffer = {
+            let mut state = self.state.lock().unwrap();
+            match self.create_final_header(aggregator.upcast_ref(), &mut state) {
+                Some(buffer) => buffer,
+                None => return,
+            }
+        };
+
+        let srcpad = aggregator.get_static_pad("src").unwrap();
+        let segment = gst::FormattedSegment::<gst::format::Bytes>::new();
+        srcpad.push_event(gst::Event::new_segment(&segment).build());
+
+        let flow_ret = srcpad.push(buffer);
+        if flow_ret != gst::FlowReturn::Ok {
+            gst_warning!(
+                self.cat,
+                obj: aggregator,
+                "Failed to rewrite header: {:?}",
+                flow_ret
+            );
+        }
+    }
+
+    fn create_tag(
+        &self,
+        element: &gst::Element,
+        state: &mut State,
+        stream_idx: usize,
+        buffer: &gst::Buffer,
+    ) -> Result<gst::Buffer, gst::FlowReturn> {
+        let pad = state.streams[stream_idx].pad.clone();
+        let kind = state.streams[stream_idx].kind;
+
+        let dts = if buffer.get_dts().is_some() {
+            buffer.get_dts()
+        } else {
+            buffer.get_pts()
+        };
+        let last_time = state.streams[stream_idx].last_time;
+        let timestamp = Self::get_running_time(&pad, dts)
+            .or(last_time)
+            .unwrap_or(0);
+        let composition_time = match (Self::get_running_time(&pad, buffer.get_pts()), timestamp) {
+            (Some(pts), dts) if pts > dts => ((pts - dts) / gst::MSECOND_VAL) as i32,
+            _ => 0,
+        };
+        let keyframe = !buffer.get_flags().contains(gst::BufferFlags::DELTA_UNIT);
+
+        let mut data = Vec::new();
+
+        if state.streams[stream_idx].codec_data_pending {
+            gst_debug!(self.cat, obj: &pad, "Writing updated codec data");
+            if let Some(sequence_header) =
+                Self::create_sequence_header(&state.streams[stream_idx], timestamp)
+            {
+                data.extend_from_slice(&sequence_header);
+            }
+            state.streams[stream_idx].codec_data_pending = false;
+        }
+
+        let format = match state.streams[stream_idx].format {
+            Some(ref format) => format.clone(),
+            None => {
+                gst_element_error!(
+                    element,
+                    gst::StreamError::Format,
+                    ["Got buffer before caps"]
+                );
+                return Err(gst::FlowReturn::NotNegotiated);
+            }
+        };
+
+        let map = match buffer.map_readable() {
+            Some(map) => map,
+            None => {
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Failed,
+                    ["Failed to map buffer"]
+                );
+                return Err(gst::FlowReturn::Error);
+            }
+        };
+
+        let mut payload = Vec::with_capacity(map.get_size() + 5);
+        format.write_payload_header(&mut payload, false, keyframe, composition_time);
+        payload.extend_from_slice(map.as_slice());
+
+        let tag_type = match kind {
+            StreamKind::Audio => TAG_TYPE_AUDIO,
+            StreamKind::Video => TAG_TYPE_VIDEO,
+        };
+
+        let tag_offset = state.bytes_written + data.len() as u64;
+        write_tag(&mut data, tag_type, timestamp, &payload);
+
+        // Index video keyframes, or audio once per second if there is no video
+        let index = match kind {
+            StreamKind::Video => keyframe,
+            StreamKind::Audio => {
+                !state.have_video
+                    && state
+                        .keyframes
+                        .last()
+                        .map(|&(time, _)| timestamp >= time + gst::SECOND_VAL)
+                        .unwrap_or(true)
+            }
+        };
+        if index {
+            state.keyframes.push((timestamp, tag_offset));
+        }
+
+        let end_time = match buffer.get_duration().0 {
+            Some(duration) => timestamp + duration,
+            None => timestamp,
+        };
+        state.streams[stream_idx].last_time = Some(cmp::max(end_time, last_time.unwrap_or(0)));
+
+        let offset = state.bytes_written;
+        state.bytes_written += data.len() as u64;
+
+        let mut outbuf = gst::Buffer::from_mut_slice(data).unwrap();
+        {
+            let outbuf = outbuf.get_mut().unwrap();
+            outbuf.set_pts(gst::ClockTime::from_nseconds(timestamp));
+            outbuf.set_dts(gst::ClockTime::from_nseconds(timestamp));
+            outbuf.set_offset(offset);
+            outbuf.set_offset_end(state.bytes_written);
+            if kind == StreamKind::Video && !keyframe
+                || kind == StreamKind::Audio && state.have_video
+            {
+                outbuf.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+
+        Ok(outbuf)
+    }
+}
+
+impl ObjectImpl<Aggregator> for FlvMux {}
+
+impl ElementImpl<Aggregator> for FlvMux {
+    fn release_pad(&self, element: &Aggregator, pad: &gst::Pad) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state
+                .streams
+                .retain(|stream| stream.pad.upcast_ref::<gst::Pad>() != pad);
+        }
+
+        element.parent_release_pad(pad);
+    }
+}
+
+impl AggregatorImpl<Aggregator> for FlvMux {
+    fn create_new_pad(
+        &self,
+        aggregator: &Aggregator,
+        templ: &gst::PadTemplate,
+        _req_name: Option<&str>,
+        _caps: Option<&gst::CapsRef>,
+    ) -> Option<gst_base::AggregatorPad> {
+        let kind = if Some(templ) == aggregator.get_pad_template("audio").as_ref() {
+            StreamKind::Audio
+        } else if Some(templ) == aggregator.get_pad_template("video").as_ref() {
+            StreamKind::Video
+        } else {
+            return None;
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.header_written {
+            gst_error!(self.cat, obj: aggregator, "Can't add new pads after starting");
+            return None;
+        }
+
+        if state.streams.iter().any(|stream| stream.kind == kind) {
+            gst_error!(self.cat, obj: aggregator, "Already have a {:?} pad", kind);
+            return None;
+        }
+
+        let name = match kind {
+            StreamKind::Audio => "audio",
+            StreamKind::Video => "video",
+        };
+
+        let pad = glib::Object::new(
+            gst_base::AggregatorPad::static_type(),
+            &[
+                ("name", &name),
+                ("direction", &gst::PadDirection::Sink),
+                ("template", templ),
+            ],
+        ).ok()
+            .and_then(|obj| obj.downcast::<gst_base::AggregatorPad>().ok())?;
+
+        state.streams.push(Stream {
+            pad: pad.clone(),
+            kind: kind,
+            format: None,
+            codec_data_pending: false,
+            last_time: None,
+        });
+
+        Some(pad)
+    }
+
+    fn sink_event(
+        &self,
+        aggregator: &Aggregator,
+        aggregator_pad: &gst_base::AggregatorPad,
+        event: gst::Event,
+    ) -> bool {
+        use gst::EventView;
+
+        if let EventView::Caps(ref ev) = event.view() {
+            let caps = ev.get_caps();
+            gst_debug!(self.cat, obj: aggregator_pad, "Got caps {:?}", caps);
+
+            let mut state = self.state.lock().unwrap();
+            let header_written = state.header_written;
+            let stream = match state
+                .streams
+                .iter_mut()
+                .find(|stream| stream.pad == *aggregator_pad)
+            {
+                Some(stream) => stream,
+                None => return false,
+            };
+
+            let format = match Format::from_caps(stream.kind, caps) {
+                Some(format) => format,
+                None => {
+                    gst_error!(self.cat, obj: aggregator_pad, "Unsupported caps {:?}", caps);
+                    return false;
+                }
+            };
+
+            if header_written {
+                match (stream.format.as_ref(), &format) {
+                    (
+                        Some(&Format::Audio {
+                            sound_format: old, ..
+                        }),
+                        &Format::Audio { sound_format, .. },
+                    ) if old != sound_format =>
+                    {
+                        gst_error!(self.cat, obj: aggregator_pad, "Can't change codec");
+                        return false;
+                    }
+                    (
+                        Some(&Format::Video { codec_id: old, .. }),
+                        &Format::Video { codec_id, .. },
+                    ) if old != codec_id =>
+                    {
+                        gst_error!(self.cat, obj: aggregator_pad, "Can't change codec");
+                        return false;
+                    }
+                    _ => (),
+                }
+            }
+
+            let codec_data_changed = match (
+                stream.format.as_ref().and_then(|f| f.get_codec_data()),
+                format.get_codec_data(),
+            ) {
+                (Some(old), Some(new)) => {
+                    let old = old.map_readable().unwrap();
+                    let new = new.map_readable().unwrap();
+                    old.as_slice() != new.as_slice()
+                }
+                (None, Some(_)) => true,
+                _ => false,
+            };
+
+            stream.codec_data_pending = stream.codec_data_pending || codec_data_changed;
+            stream.format = Some(format);
+        }
+
+        aggregator.parent_sink_event(aggregator_pad, event)
+    }
+
+    fn aggregate(&self, aggregator: &Aggregator, _timeout: bool) -> gst::FlowReturn {
+        let pads = {
+            let state = self.state.lock().unwrap();
+            state
+                .streams
+                .iter()
+                .map(|stream| stream.pad.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // Select the pad with the earliest buffer
+        let mut best: Option<(gst_base::AggregatorPad, u64)> = None;
+        let mut all_eos = true;
+        for pad in pads {
+            let buffer = match pad.peek_buffer() {
+                Some(buffer) => buffer,
+                None => {
+                    if !pad.is_eos() {
+                        all_eos = false;
+                    }
+                    continue;
+                }
+            };
+            all_eos = false;
+
+            let dts = if buffer.get_dts().is_some() {
+                buffer.get_dts()
+            } else {
+                buffer.get_pts()
+            };
+            let running_time = Self::get_running_time(&pad, dts).unwrap_or(0);
+
+            let earlier = match best {
+                Some((_, best_time)) => running_time < best_time,
+                None => true,
+            };
+            if earlier {
+                best = Some((pad, running_time));
+            }
+        }
+
+        let pad = match best {
+            Some((pad, _)) => pad,
+            None if all_eos => {
+                gst_debug!(self.cat, obj: aggregator, "All pads are EOS");
+                self.rewrite_header(aggregator);
+                return gst::FlowReturn::Eos;
+            }
+            None => return gst::FlowReturn::Ok,
+        };
+
+        let buffer = match pad.pop_buffer() {
+            Some(buffer) => buffer,
+            None => return gst::FlowReturn::Ok,
+        };
+
+        gst_trace!(self.cat, obj: &pad, "Muxing buffer {:?}", buffer);
+
+        let (header, outbuf) = {
+            let mut state = self.state.lock().unwrap();
+
+            let header = if state.header_written {
+                None
+            } else {
+                let seekable = self.is_downstream_seekable(aggregator);
+                Some(self.write_header(aggregator.upcast_ref(), &mut state, seekable))
+            };
+
+            let stream_idx = match state.streams.iter().position(|stream| stream.pad == pad) {
+                Some(idx) => idx,
+                None => return gst::FlowReturn::Ok,
+            };
+
+            match self.create_tag(aggregator.upcast_ref(), &mut state, stream_idx, &buffer) {
+                Ok(outbuf) => (header, outbuf),
+                Err(flow_ret) => return flow_ret,
+            }
+        };
+
+        if let Some(header) = header {
+            let flow_ret = aggregator.finish_buffer(header);
+            if flow_ret != gst::FlowReturn::Ok {
+                return flow_ret;
+            }
+        }
+
+        aggregator.finish_buffer(outbuf)
+    }
+
+    fn start(&self, _aggregator: &Aggregator) -> bool {
+        self.state.lock().unwrap().reset();
+
+        true
+    }
+
+    fn stop(&self, _aggregator: &Aggregator) -> bool {
+        self.state.lock().unwrap().reset();
+
+        true
+    }
+}
+
+struct FlvMuxStatic;
+
+impl ImplTypeStatic<Aggregator> for FlvMuxStatic {
+    fn get_name(&self) -> &str {
+        "FlvMux"
+    }
+
+    fn new(&self, element: &Aggregator) -> Box<AggregatorImpl<Aggregator>> {
+        FlvMux::init(element)
+    }
+
+    fn class_init(&self, klass: &mut AggregatorClass) {
+        FlvMux::class_init(klass);
+    }
+}
+
+pub fn register(plugin: &gst::Plugin) {
+    let flvmux_static = FlvMuxStatic;
+    let type_ = register_type(flvmux_static);
+    gst::Element::register(plugin, "rsflvmux", 256 + 100, type_);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flavors::parser as flavors;
+    use nom::IResult;
+
+    fn setup() -> (FlvMux, gst::Element) {
+        gst::init().unwrap();
+
+        let mux = FlvMux {
+            cat: gst::DebugCategory::new(
+                "rsflvmux",
+                gst::DebugColorFlags::empty(),
+                "Rust FLV muxer",
+            ),
+            state: Mutex::new(State::default()),
+        };
+        let element = gst::Bin::new(None).upcast::<gst::Element>();
+
+        (mux, element)
+    }
+
+    fn add_stream(state: &mut State, kind: StreamKind, caps: &gst::Caps) {
+        let pad = glib::Object::new(
+            gst_base::AggregatorPad::static_type(),
+            &[("direction", &gst::PadDirection::Sink)],
+        ).unwrap()
+            .downcast::<gst_base::AggregatorPad>()
+            .unwrap();
+
+        let format = Format::from_caps(kind, caps);
+        assert!(format.is_some());
+
+        state.streams.push(Stream {
+            pad: pad,
+            kind: kind,
+            format: format,
+            codec_data_pending: false,
+            last_time: None,
+        });
+    }
+
+    fn create_buffer(pts: u64, keyframe: bool, data: &[u8]) -> gst::Buffer {
+        let mut buffer = gst::Buffer::from_mut_slice(data.to_vec()).unwrap();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(pts * gst::MSECOND);
+            buffer.set_duration(40 * gst::MSECOND);
+            if !keyframe {
+                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+
+        buffer
+    }
+
+    // Muxes 10 video and 10 audio buffers of 40ms, with a keyframe every 5 video buffers,
+    // and applies the header rewrite at EOS like a seekable downstream would
+    fn mux_buffers(mux: &FlvMux, element: &gst::Element, seekable: bool) -> Vec<u8> {
+        let mut state = mux.state.lock().unwrap();
+
+        add_stream(
+            &mut state,
+            StreamKind::Video,
+            &gst::Caps::new_simple(
+                "video/x-flash-video",
+                &[
+                    ("flvversion", &1i32),
+                    ("width", &320i32),
+                    ("height", &240i32),
+                ],
+            ),
+        );
+        add_stream(
+            &mut state,
+            StreamKind::Audio,
+            &gst::Caps::new_simple(
+                "audio/x-raw",
+                &[
+                    ("format", &"S16LE"),
+                    ("layout", &"interleaved"),
+                    ("channels", &2i32),
+                    ("rate", &44100i32),
+                ],
+            ),
+        );
+
+        let header = mux.write_header(element, &mut state, seekable);
+        assert_eq!(header.get_offset(), 0);
+        let mut data = header.map_readable().unwrap().as_slice().to_vec();
+
+        for i in 0..10 {
+            let buffers = [
+                (0, create_buffer(i * 40, i % 5 == 0, &[0x01; 16])),
+                (1, create_buffer(i * 40, true, &[0x02; 32])),
+            ];
+
+            for &(stream_idx, ref buffer) in &buffers {
+                let outbuf = mux.create_tag(element, &mut state, stream_idx, buffer)
+                    .unwrap();
+                assert_eq!(outbuf.get_offset(), data.len() as u64);
+                data.extend_from_slice(outbuf.map_readable().unwrap().as_slice());
+                assert_eq!(outbuf.get_offset_end(), data.len() as u64);
+            }
+        }
+
+        if let Some(buffer) = mux.create_final_header(element, &mut state) {
+            let map = buffer.map_readable().unwrap();
+            data[..map.get_size()].copy_from_slice(map.as_slice());
+        }
+
+        data
+    }
+
+    fn read_u32be(data: &[u8]) -> u32 {
+        let mut data = data;
+        data.read_u32be().unwrap()
+    }
+
+    // Splits the FLV data into its tags, checking the tag sizes and back pointers
+    fn parse_tags(data: &[u8]) -> (flavors::Header, Vec<(flavors::TagHeader, &[u8])>) {
+        let header = match flavors::header(data) {
+            IResult::Done(_, header) => header,
+            _ => panic!("Invalid FLV header"),
+        };
+
+        let mut offset = header.offset as usize;
+        let mut last_tag_size = 0;
+        let mut tags = Vec::new();
+
+        while offset < data.len() {
+            assert_eq!(read_u32be(&data[offset..]), last_tag_size);
+            offset += 4;
+            if offset == data.len() {
+                break;
+            }
+
+            let tag_header = match flavors::tag_header(&data[offset..]) {
+                IResult::Done(_, tag_header) => tag_header,
+                _ => panic!("Invalid tag header at {}", offset),
+            };
+            let tag_size = TAG_HEADER_SIZE + tag_header.data_size as usize;
+            let payload = &data[offset + TAG_HEADER_SIZE..offset + tag_size];
+
+            tags.push((tag_header, payload));
+            last_tag_size = tag_size as u32;
+            offset += tag_size;
+        }
+
+        (header, tags)
+    }
+
+    fn get_number(objects: &[flavors::ScriptDataObject], name: &str) -> Option<f64> {
+        objects
+            .iter()
+            .find(|object| object.name == name)
+            .and_then(|object| match object.data {
+                flavors::ScriptDataValue::Number(value) => Some(value),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (mux, element) = setup();
+        let data = mux_buffers(&mux, &element, false);
+
+        let (header, tags) = parse_tags(&data);
+        assert_eq!(header.version, 1);
+        assert!(header.audio);
+        assert!(header.video);
+        assert_eq!(tags.len(), 21);
+
+        match tags[0].0.tag_type {
+            flavors::TagType::Script => (),
+            _ => panic!("First tag is not the metadata"),
+        }
+        let script_data = match flavors::script_data(tags[0].1) {
+            IResult::Done(_, script_data) => script_data,
+            _ => panic!("Invalid metadata"),
+        };
+        assert_eq!(script_data.name, "onMetaData");
+        let objects = match script_data.arguments {
+            flavors::ScriptDataValue::ECMAArray(ref objects) => objects,
+            _ => panic!("Metadata is not an ECMA array"),
+        };
+        assert_eq!(get_number(objects, "width"), Some(320.0));
+        assert_eq!(get_number(objects, "height"), Some(240.0));
+        assert_eq!(get_number(objects, "audiosamplerate"), Some(44100.0));
+        // Not seekable, so no duration that could be rewritten later
+        assert_eq!(get_number(objects, "duration"), None);
+
+        for (i, pair) in tags[1..].chunks(2).enumerate() {
+            let (ref video_header, video_payload) = pair[0];
+            let (ref audio_header, audio_payload) = pair[1];
+
+            match video_header.tag_type {
+                flavors::TagType::Video => (),
+                _ => panic!("Expected video tag {}", i),
+            }
+            assert_eq!(video_header.timestamp, i as u32 * 40);
+            let video_data_header = match flavors::video_data_header(video_payload) {
+                IResult::Done(_, data_header) => data_header,
+                _ => panic!("Invalid video tag {}", i),
+            };
+            match video_data_header.codec_id {
+                flavors::CodecId::SORENSON_H263 => (),
+                _ => panic!("Wrong video codec"),
+            }
+            assert_eq!(
+                video_data_header.frame_type == flavors::FrameType::Key,
+                i % 5 == 0
+            );
+            assert_eq!(&video_payload[1..], &[0x01; 16][..]);
+
+            match audio_header.tag_type {
+                flavors::TagType::Audio => (),
+                _ => panic!("Expected audio tag {}", i),
+            }
+            assert_eq!(audio_header.timestamp, i as u32 * 40);
+            let audio_data_header = match flavors::audio_data_header(audio_payload) {
+                IResult::Done(_, data_header) => data_header,
+                _ => panic!("Invalid audio tag {}", i),
+            };
+            match (
+                audio_data_header.sound_format,
+                audio_data_header.sound_rate,
+                audio_data_header.sound_size,
+                audio_data_header.sound_type,
+            ) {
+                (
+                    flavors::SoundFormat::PCM_LE,
+                    flavors::SoundRate::_44KHZ,
+                    flavors::SoundSize::Snd16bit,
+                    flavors::SoundType::SndStereo,
+                ) => (),
+                _ => panic!("Wrong audio format"),
+            }
+            assert_eq!(&audio_payload[1..], &[0x02; 32][..]);
+        }
+    }
+
+    #[test]
+    fn test_metadata_rewrite_keeps_size() {
+        let (mux, element) = setup();
+        let data = mux_buffers(&mux, &element, true);
+
+        let state = mux.state.lock().unwrap();
+        assert!(state.rewritten);
+
+        // The rewritten header must exactly cover the initial one, otherwise the
+        // tags after it would be corrupted and parsing would fail
+        let (_, tags) = parse_tags(&data);
+        assert_eq!(tags.len(), 21);
+        assert_eq!(
+            FLV_HEADER_SIZE + 4 + TAG_HEADER_SIZE + tags[0].1.len() + 4,
+            state.metadata_size
+        );
+
+        let script_data = match flavors::script_data(tags[0].1) {
+            IResult::Done(_, script_data) => script_data,
+            _ => panic!("Invalid metadata"),
+        };
+        let objects = match script_data.arguments {
+            flavors::ScriptDataValue::ECMAArray(ref objects) => objects,
+            _ => panic!("Metadata is not an ECMA array"),
+        };
+        assert_eq!(get_number(objects, "duration"), Some(0.4));
+        assert_eq!(get_number(objects, "filesize"), Some(data.len() as f64));
+
+        let keyframes = objects
+            .iter()
+            .find(|object| object.name == "keyframes")
+            .map(|object| match object.data {
+                flavors::ScriptDataValue::Object(ref objects) => objects,
+                _ => panic!("Keyframes are not an object"),
+            })
+            .unwrap();
+        let positions = keyframes
+            .iter()
+            .find(|object| object.name == "filepositions")
+            .map(|object| match object.data {
+                flavors::ScriptDataValue::StrictArray(ref values) => values,
+                _ => panic!("File positions are not an array"),
+            })
+            .unwrap();
+        assert_eq!(positions.len(), INDEX_ENTRIES);
+
+        // The first keyframe is the first video tag right after the header
+        match positions[0] {
+            flavors::ScriptDataValue::Number(position) => {
+                assert_eq!(position, state.metadata_size as f64)
+            }
+            _ => panic!("File position is not a number"),
+        }
+    }
+}
@@ -9,11 +9,14 @@
 #![crate_type = "cdylib"]
 
 extern crate flavors;
+extern crate glib;
+extern crate gobject_subclass;
 #[macro_use]
 extern crate gst_plugin;
 extern crate gst_plugin_simple;
 #[macro_use]
 extern crate gstreamer as gst;
+extern crate gstreamer_base as gst_base;
 extern crate muldiv;
 extern crate nom;
 extern crate num_rational;
@@ -22,6 +25,7 @@ extern crate url;
 use gst_plugin_simple::demuxer::*;
 
 mod flvdemux;
+mod flvmux;
 
 use flvdemux::FlvDemux;
 
@@ -38,9 +42,12 @@ fn plugin_init(plugin: &gst::Plugin) -> bool {
             create_instance: FlvDemux::new_boxed,
             input_caps: gst::Caps::new_simple("video/x-flv", &[]),
             output_caps: gst::Caps::new_any(),
+            properties: &flvdemux::PROPERTIES,
         },
     );
 
+    flvmux::register(plugin);
+
     true
 }
 
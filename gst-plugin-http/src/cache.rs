@@ -0,0 +1,600 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::{Arc, Condvar, Mutex};
+
+static TEMP_FILE_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Buffering starts again once the data after the read position drops below this
+const LOW_PERCENT: i32 = 10;
+
+enum Storage {
+    // Only the newest data is kept, the oldest is dropped once full
+    Memory { data: VecDeque<u8>, capacity: usize },
+    // Everything is kept at its offset in the stream, the file is removed again together with
+    // the cache
+    File { file: File, path: PathBuf },
+}
+
+impl fmt::Debug for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Storage::Memory { ref data, capacity } => f.debug_struct("Memory")
+                .field("len", &data.len())
+                .field("capacity", &capacity)
+                .finish(),
+            Storage::File { ref path, .. } => f.debug_struct("File").field("path", path).finish(),
+        }
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        if let Storage::File { ref path, .. } = *self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Data of an earlier download that is kept in the file
+#[derive(Debug, Clone, Copy)]
+struct Range {
+    start: u64,
+    end: u64,
+    eos: bool,
+}
+
+#[derive(Debug)]
+struct State {
+    storage: Storage,
+    // Range of the stream that is read and extended by the current download
+    start: u64,
+    end: u64,
+    // Other cached ranges, only for file storage
+    ranges: Vec<Range>,
+    // Position of the reader, data after it is never dropped
+    position: u64,
+    eos: bool,
+    error: Option<String>,
+    // Id of the download that is allowed to write, changed to stop the current one
+    download: u64,
+    buffering: bool,
+    percent: i32,
+}
+
+// Data downloaded so far, filled from a download thread and read by the streaming thread
+#[derive(Debug)]
+pub struct Cache {
+    state: Mutex<State>,
+    cond: Condvar,
+    // Amount of data after the read position that is considered fully buffered
+    buffer_size: u64,
+}
+
+impl Cache {
+    pub fn new_memory(position: u64, capacity: u64) -> Cache {
+        let storage = Storage::Memory {
+            data: VecDeque::new(),
+            capacity: capacity as usize,
+        };
+
+        Cache::new(storage, position, capacity)
+    }
+
+    pub fn new_file(dir: &str, position: u64, buffer_size: u64) -> io::Result<Cache> {
+        let path = Path::new(dir).join(format!(
+            "rshttpsrc-{}-{}",
+            process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let file = try!(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+        );
+
+        let storage = Storage::File {
+            file: file,
+            path: path,
+        };
+
+        Ok(Cache::new(storage, position, buffer_size))
+    }
+
+    fn new(storage: Storage, position: u64, buffer_size: u64) -> Cache {
+        Cache {
+            state: Mutex::new(State {
+                storage: storage,
+                start: position,
+                end: position,
+                ranges: Vec::new(),
+                position: position,
+                eos: false,
+                error: None,
+                download: 0,
+                buffering: true,
+                percent: -1,
+            }),
+            cond: Condvar::new(),
+            buffer_size: cmp::max(buffer_size, 1),
+        }
+    }
+
+    // End of the cached data, downloads continue from here
+    pub fn end(&self) -> u64 {
+        self.state.lock().unwrap().end
+    }
+
+    // Moves the read position if it is inside the cached data
+    pub fn seek(&self, position: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if position < state.start || position > state.end {
+            return false;
+        }
+
+        state.position = position;
+        self.cond.notify_all();
+
+        true
+    }
+
+    // Makes the cached range that contains the position, or a new empty one starting at it,
+    // the one that is read and extended. The current download is stopped and a new one has to
+    // continue at the returned offset, unless the range already reaches the end of the stream.
+    pub fn restart(&self, position: u64) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        state.download += 1;
+        state.error = None;
+        state.buffering = true;
+        state.percent = -1;
+
+        let (start, end, eos) = match state.storage {
+            Storage::Memory { ref mut data, .. } => {
+                data.clear();
+                (position, position, false)
+            }
+            Storage::File { .. } => {
+                if state.end > state.start {
+                    state.ranges.push(Range {
+                        start: state.start,
+                        end: state.end,
+                        eos: state.eos,
+                    });
+                }
+
+                match state
+                    .ranges
+                    .iter()
+                    .position(|r| position >= r.start && position <= r.end)
+                {
+                    Some(idx) => {
+                        let range = state.ranges.remove(idx);
+                        (range.start, range.end, range.eos)
+                    }
+                    None => (position, position, false),
+                }
+            }
+        };
+
+        state.start = start;
+        state.end = end;
+        state.eos = eos;
+        state.position = position;
+        self.cond.notify_all();
+
+        if eos {
+            None
+        } else {
+            Some(end)
+        }
+    }
+
+    // Wakes up a blocked read so that it can check for flushing
+    pub fn wake(&self) {
+        let _state = self.state.lock().unwrap();
+        self.cond.notify_all();
+    }
+
+    // Blocks until data is available at the position, returns the size read and the buffering
+    // percentage to post if it changed
+    pub fn read(
+        &self,
+        is_flushing: &Fn() -> bool,
+        position: u64,
+        data: &mut [u8],
+    ) -> io::Result<(usize, Option<i32>)> {
+        let mut state = self.state.lock().unwrap();
+
+        if position < state.start || position > state.end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Position {} not cached", position),
+            ));
+        }
+        state.position = position;
+
+        while state.end == position && !state.eos && state.error.is_none() {
+            if is_flushing() {
+                return Err(io::Error::new(io::ErrorKind::Other, "Flushing"));
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+
+        if state.end == position {
+            return match state.error {
+                Some(ref err) => Err(io::Error::new(io::ErrorKind::Other, err.clone())),
+                None => Ok((0, None)),
+            };
+        }
+
+        let len = cmp::min(data.len() as u64, state.end - position) as usize;
+        let data = &mut data[..len];
+
+        match state.storage {
+            Storage::Memory { data: ref cached, .. } => {
+                let offset = (position - state.start) as usize;
+                let (first, second) = cached.as_slices();
+                if offset >= first.len() {
+                    let offset = offset - first.len();
+                    data.copy_from_slice(&second[offset..offset + len]);
+                } else {
+                    let first_len = cmp::min(len, first.len() - offset);
+                    data[..first_len].copy_from_slice(&first[offset..offset + first_len]);
+                    data[first_len..].copy_from_slice(&second[..len - first_len]);
+                }
+            }
+            Storage::File { ref mut file, .. } => {
+                try!(file.seek(SeekFrom::Start(position)));
+                try!(file.read_exact(data));
+            }
+        }
+
+        state.position += len as u64;
+        // The download might be waiting for space
+        self.cond.notify_all();
+
+        Ok((len, self.update_buffering(&mut state)))
+    }
+
+    // Blocks while there is no space left, returns Err if the download was stopped
+    pub fn write(&self, download: u64, mut data: &[u8]) -> Result<Option<i32>, ()> {
+        let mut state = self.state.lock().unwrap();
+
+        while !data.is_empty() {
+            if state.download != download {
+                return Err(());
+            }
+
+            let unread = state.end - cmp::max(state.position, state.start);
+            let space = match state.storage {
+                Storage::Memory { capacity, .. } => (capacity as u64).saturating_sub(unread),
+                Storage::File { .. } => data.len() as u64,
+            };
+
+            if space == 0 {
+                state = self.cond.wait(state).unwrap();
+                continue;
+            }
+
+            let len = cmp::min(space, data.len() as u64) as usize;
+            let end = state.end;
+            let res = match state.storage {
+                Storage::Memory {
+                    data: ref mut cached,
+                    capacity,
+                } => {
+                    cached.extend(&data[..len]);
+                    let excess = cached.len().saturating_sub(capacity);
+                    cached.drain(..excess);
+                    Ok(excess as u64)
+                }
+                Storage::File { ref mut file, .. } => file.seek(SeekFrom::Start(end))
+                    .and_then(|_| file.write_all(&data[..len]))
+                    .map(|_| 0),
+            };
+
+            match res {
+                Ok(dropped) => {
+                    state.start += dropped;
+                    state.end += len as u64;
+                    data = &data[len..];
+
+                    // Earlier ranges that were downloaded again are not needed anymore
+                    let (start, end) = (state.start, state.end);
+                    state.ranges.retain(|r| r.start < start || r.end > end);
+
+                    self.cond.notify_all();
+                }
+                Err(err) => {
+                    state.error = Some(format!("Failed to write to cache: {}", err));
+                    self.cond.notify_all();
+                    return Err(());
+                }
+            }
+        }
+
+        Ok(self.update_buffering(&mut state))
+    }
+
+    // Marks the end of the download, with an error if it failed
+    pub fn finish(&self, download: u64, error: Option<String>) -> Option<i32> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.download != download {
+            return None;
+        }
+
+        match error {
+            Some(error) => state.error = Some(error),
+            None => state.eos = true,
+        }
+        self.cond.notify_all();
+
+        self.update_buffering(&mut state)
+    }
+
+    // Buffering percentage with hysteresis, None if nothing has to be posted
+    fn update_buffering(&self, state: &mut State) -> Option<i32> {
+        let percent = if state.eos || state.error.is_some() {
+            100
+        } else {
+            let level = state.end.saturating_sub(state.position);
+            cmp::min(100, level * 100 / self.buffer_size) as i32
+        };
+
+        if !state.buffering {
+            if percent >= LOW_PERCENT {
+                return None;
+            }
+            state.buffering = true;
+        }
+
+        if percent == state.percent {
+            return None;
+        }
+
+        state.percent = percent;
+        if percent == 100 {
+            state.buffering = false;
+        }
+
+        Some(percent)
+    }
+}
+
+// Download into the cache, stopped again when dropped
+#[derive(Debug)]
+pub struct Download {
+    pub cache: Arc<Cache>,
+    id: u64,
+}
+
+impl Download {
+    // Any previous download into the cache is stopped
+    pub fn new(cache: Arc<Cache>) -> Download {
+        let id = {
+            let mut state = cache.state.lock().unwrap();
+            state.download += 1;
+            state.eos = false;
+            state.error = None;
+            cache.cond.notify_all();
+            state.download
+        };
+
+        Download {
+            cache: cache,
+            id: id,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        let mut state = self.cache.state.lock().unwrap();
+        if state.download == self.id {
+            state.download += 1;
+            self.cache.cond.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn read(cache: &Cache, position: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        let (size, _) = cache.read(&|| false, position, &mut data).unwrap();
+        data.truncate(size);
+        data
+    }
+
+    fn new_file_cache(buffer_size: u64) -> Arc<Cache> {
+        let dir = env::temp_dir();
+        Arc::new(Cache::new_file(dir.to_str().unwrap(), 0, buffer_size).unwrap())
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let cache = Arc::new(Cache::new_memory(0, 8));
+        let download = Download::new(cache.clone());
+
+        cache.write(download.id(), b"01234567").unwrap();
+        assert_eq!(read(&cache, 0, 6), b"012345");
+
+        // The 6 bytes already read are dropped to make space
+        cache.write(download.id(), b"89abcd").unwrap();
+        assert_eq!(read(&cache, 6, 16), b"6789abcd");
+        assert_eq!(cache.end(), 14);
+    }
+
+    #[test]
+    fn test_back_pressure() {
+        let cache = Arc::new(Cache::new_memory(0, 4));
+        let download = Download::new(cache.clone());
+        let id = download.id();
+
+        cache.write(id, b"0123").unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let writer = {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                sender.send(cache.write(id, b"45")).unwrap();
+            })
+        };
+
+        // Blocked until the reader frees some space
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        assert_eq!(read(&cache, 0, 2), b"01");
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).unwrap().is_ok());
+        writer.join().unwrap();
+
+        assert_eq!(read(&cache, 2, 4), b"2345");
+    }
+
+    #[test]
+    fn test_seek() {
+        let cache = Arc::new(Cache::new_memory(0, 8));
+        let download = Download::new(cache.clone());
+
+        cache.write(download.id(), b"01234567").unwrap();
+        assert_eq!(read(&cache, 0, 6), b"012345");
+        cache.write(download.id(), b"89abcd").unwrap();
+
+        // Only [6, 14] is still cached
+        assert!(!cache.seek(5));
+        assert!(cache.seek(6));
+        assert!(cache.seek(14));
+        assert!(!cache.seek(15));
+
+        let mut data = [0; 4];
+        let err = cache.read(&|| false, 3, &mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        assert!(cache.seek(10));
+        assert_eq!(read(&cache, 10, 4), b"abcd");
+    }
+
+    #[test]
+    fn test_download_supersession() {
+        let cache = Arc::new(Cache::new_memory(0, 4));
+        let old = Download::new(cache.clone());
+        let new = Download::new(cache.clone());
+
+        assert!(cache.write(old.id(), b"01").is_err());
+        assert_eq!(cache.finish(old.id(), None), None);
+        // Dropping a stopped download does not stop the current one
+        drop(old);
+
+        cache.write(new.id(), b"0123").unwrap();
+        assert_eq!(cache.end(), 4);
+
+        let (sender, receiver) = mpsc::channel();
+        let writer = {
+            let cache = cache.clone();
+            let id = new.id();
+            thread::spawn(move || {
+                sender.send(cache.write(id, b"45")).unwrap();
+            })
+        };
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // A new download wakes up and stops the blocked writer
+        let _newer = Download::new(cache.clone());
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).unwrap().is_err());
+        writer.join().unwrap();
+
+        assert_eq!(cache.end(), 4);
+    }
+
+    #[test]
+    fn test_buffering_percent() {
+        let cache = Arc::new(Cache::new_memory(0, 100));
+        let download = Download::new(cache.clone());
+
+        assert_eq!(cache.write(download.id(), &[0; 50]), Ok(Some(50)));
+        assert_eq!(cache.write(download.id(), &[0; 50]), Ok(Some(100)));
+
+        // No more messages until the level drops below 10%
+        let mut data = [0; 85];
+        assert_eq!(cache.read(&|| false, 0, &mut data).unwrap(), (85, None));
+        let mut data = [0; 10];
+        assert_eq!(cache.read(&|| false, 85, &mut data).unwrap(), (10, Some(5)));
+
+        assert_eq!(cache.write(download.id(), &[0; 20]), Ok(Some(25)));
+        assert_eq!(cache.finish(download.id(), None), Some(100));
+    }
+
+    #[test]
+    fn test_restart_file() {
+        let cache = new_file_cache(10);
+
+        let download = Download::new(cache.clone());
+        cache.write(download.id(), b"0123456789").unwrap();
+        drop(download);
+
+        // Outside of the cached data a new range is started
+        assert_eq!(cache.restart(100), Some(100));
+        assert!(!cache.seek(5));
+        let download = Download::new(cache.clone());
+        cache.write(download.id(), b"abc").unwrap();
+        assert_eq!(read(&cache, 100, 2), b"ab");
+
+        // The first range is still in the file and continued at its end
+        assert_eq!(cache.restart(5), Some(10));
+        assert!(cache.write(download.id(), b"def").is_err());
+        assert_eq!(read(&cache, 5, 10), b"56789");
+
+        let download = Download::new(cache.clone());
+        assert_eq!(cache.finish(download.id(), None), Some(100));
+        assert_eq!(read(&cache, 10, 10), b"");
+
+        // A range that reaches the end of the stream needs no new download
+        assert_eq!(cache.restart(101), Some(103));
+        assert_eq!(read(&cache, 101, 10), b"bc");
+        assert_eq!(cache.restart(2), None);
+        assert_eq!(read(&cache, 2, 10), b"23456789");
+    }
+
+    #[test]
+    fn test_restart_memory() {
+        let cache = Arc::new(Cache::new_memory(0, 8));
+        let download = Download::new(cache.clone());
+        cache.write(download.id(), b"0123").unwrap();
+
+        // Only the newest data is kept in memory, so it starts again empty
+        assert_eq!(cache.restart(2), Some(2));
+        assert!(!cache.seek(0));
+        assert!(cache.write(download.id(), b"45").is_err());
+        assert_eq!(cache.end(), 2);
+    }
+}
@@ -0,0 +1,656 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// native-tls uses OpenSSL everywhere except on these
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+use native_tls;
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+use openssl::error::ErrorStack;
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+use openssl::ssl;
+use reqwest::header::{Headers, UserAgent};
+use reqwest::{self, Certificate, Client, Method, Pkcs12, Proxy, RequestBuilder, StatusCode};
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read};
+use std::str;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use cache::Cache;
+
+use gst_plugin_simple::Unlocker;
+
+use glib;
+use gst;
+use gst::prelude::*;
+
+use gobject_subclass::object::*;
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+pub const DEFAULT_TIMEOUT: u32 = 15;
+pub const DEFAULT_COMPRESS: bool = false;
+pub const DEFAULT_RETRIES: i32 = 3;
+pub const DEFAULT_RETRY_BACKOFF: u32 = 500;
+pub const DEFAULT_SSL_STRICT: bool = true;
+
+// Configuration of the client and its requests, shared by all elements that fetch data
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    default_user_agent: &'static str,
+    pub user_agent: String,
+    pub extra_headers: Option<gst::Structure>,
+    pub cookies: Vec<String>,
+    pub user_id: Option<String>,
+    pub user_pw: Option<String>,
+    pub timeout: u32,
+    pub compress: bool,
+    pub proxy: Option<String>,
+    pub retries: i32,
+    pub retry_backoff: u32,
+    pub ssl_ca_file: Option<String>,
+    pub client_certificate: Option<String>,
+    pub client_certificate_password: Option<String>,
+    pub ssl_strict: bool,
+}
+
+impl ClientSettings {
+    pub fn new(default_user_agent: &'static str) -> Self {
+        ClientSettings {
+            default_user_agent: default_user_agent,
+            user_agent: default_user_agent.into(),
+            extra_headers: None,
+            cookies: Vec::new(),
+            user_id: None,
+            user_pw: None,
+            timeout: DEFAULT_TIMEOUT,
+            compress: DEFAULT_COMPRESS,
+            proxy: None,
+            retries: DEFAULT_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            ssl_ca_file: None,
+            client_certificate: None,
+            client_certificate_password: None,
+            ssl_strict: DEFAULT_SSL_STRICT,
+        }
+    }
+
+    // Returns false if the property doesn't belong to the client settings
+    pub fn set_property(&mut self, property: &Property, value: &glib::Value) -> bool {
+        match *property {
+            Property::String("user-agent", ..) => {
+                let default_user_agent = self.default_user_agent;
+                self.user_agent = value
+                    .get()
+                    .unwrap_or_else(|| default_user_agent.into());
+            }
+            Property::Boxed("extra-headers", ..) => {
+                self.extra_headers = value.get();
+            }
+            Property::Boxed("cookies", ..) => {
+                self.cookies = value.get().unwrap_or_else(Vec::new);
+            }
+            Property::String("user-id", ..) => {
+                self.user_id = value.get();
+            }
+            Property::String("user-pw", ..) => {
+                self.user_pw = value.get();
+            }
+            Property::UInt("timeout", ..) => {
+                self.timeout = value.get().unwrap();
+            }
+            Property::Boolean("compress", ..) => {
+                self.compress = value.get().unwrap();
+            }
+            Property::String("proxy", ..) => {
+                self.proxy = value.get();
+            }
+            Property::Int("retries", ..) => {
+                self.retries = value.get().unwrap();
+            }
+            Property::UInt("retry-backoff", ..) => {
+                self.retry_backoff = value.get().unwrap();
+            }
+            Property::String("ssl-ca-file", ..) => {
+                self.ssl_ca_file = value.get();
+            }
+            Property::String("client-certificate", ..) => {
+                self.client_certificate = value.get();
+            }
+            Property::String("client-certificate-password", ..) => {
+                self.client_certificate_password = value.get();
+            }
+            Property::Boolean("ssl-strict", ..) => {
+                self.ssl_strict = value.get().unwrap();
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    pub fn get_property(&self, property: &Property) -> Option<glib::Value> {
+        match *property {
+            Property::String("user-agent", ..) => Some(self.user_agent.to_value()),
+            Property::Boxed("extra-headers", ..) => Some(self.extra_headers.to_value()),
+            Property::Boxed("cookies", ..) => Some(self.cookies.to_value()),
+            Property::String("user-id", ..) => Some(self.user_id.to_value()),
+            Property::String("user-pw", ..) => Some(self.user_pw.to_value()),
+            Property::UInt("timeout", ..) => Some(self.timeout.to_value()),
+            Property::Boolean("compress", ..) => Some(self.compress.to_value()),
+            Property::String("proxy", ..) => Some(self.proxy.to_value()),
+            Property::Int("retries", ..) => Some(self.retries.to_value()),
+            Property::UInt("retry-backoff", ..) => Some(self.retry_backoff.to_value()),
+            Property::String("ssl-ca-file", ..) => Some(self.ssl_ca_file.to_value()),
+            Property::String("client-certificate", ..) => {
+                Some(self.client_certificate.to_value())
+            }
+            Property::String("client-certificate-password", ..) => {
+                Some(self.client_certificate_password.to_value())
+            }
+            Property::Boolean("ssl-strict", ..) => Some(self.ssl_strict.to_value()),
+            _ => None,
+        }
+    }
+
+    // Whether another attempt is allowed after the given number of retries
+    pub fn may_retry(&self, attempt: i32) -> bool {
+        self.retries == -1 || attempt < self.retries
+    }
+
+    pub fn retry_delay(&self, attempt: i32) -> Duration {
+        let backoff = self.retry_backoff as u64;
+        Duration::from_millis(backoff.saturating_mul(1 << cmp::min(attempt, 16)))
+    }
+
+    pub fn create_client(
+        &self,
+        cat: gst::DebugCategory,
+        element: &gst::Element,
+    ) -> Result<Client, gst::ErrorMessage> {
+        let mut builder = Client::builder();
+
+        builder
+            .timeout(Duration::from_secs(self.timeout as u64))
+            .gzip(self.compress);
+
+        if let Some(ref proxy) = self.proxy {
+            let proxy = try!(Proxy::all(proxy.as_str()).or_else(|err| {
+                gst_error!(cat, obj: element, "Invalid proxy {}: {:?}", proxy, err);
+                Err(gst_error_msg!(
+                    gst::ResourceError::Settings,
+                    ["Invalid proxy {}: {}", proxy, err.to_string()]
+                ))
+            }));
+            builder.proxy(proxy);
+        }
+
+        if let Some(ref ca_file) = self.ssl_ca_file {
+            for certificate in try!(load_certificates(ca_file)) {
+                builder.add_root_certificate(certificate);
+            }
+        }
+
+        if let Some(ref client_certificate) = self.client_certificate {
+            let identity = try!(load_identity(
+                client_certificate,
+                self.client_certificate_password
+                    .as_ref()
+                    .map(|p| p.as_str())
+                    .unwrap_or("")
+            ));
+            builder.identity(identity);
+        }
+
+        if !self.ssl_strict {
+            gst_warning!(cat, obj: element, "Not validating server certificates");
+            builder.danger_accept_invalid_certs(true);
+        }
+
+        builder.build().or_else(|err| {
+            gst_error!(cat, obj: element, "Failed to create client: {:?}", err);
+            Err(gst_error_msg!(
+                gst::ResourceError::Settings,
+                ["Failed to create HTTP client: {}", err.to_string()]
+            ))
+        })
+    }
+
+    // Request with the configured headers and authentication
+    pub fn create_request(
+        &self,
+        cat: gst::DebugCategory,
+        element: &gst::Element,
+        client: &Client,
+        method: Method,
+        uri: Url,
+    ) -> RequestBuilder {
+        let mut req = client.request(method, uri);
+
+        req.header(UserAgent::new(self.user_agent.clone()));
+
+        if let Some(ref user_id) = self.user_id {
+            req.basic_auth(user_id.clone(), self.user_pw.clone());
+        }
+
+        let mut headers = Headers::new();
+
+        if !self.cookies.is_empty() {
+            headers.set_raw("Cookie", self.cookies.join("; "));
+        }
+
+        if let Some(ref extra_headers) = self.extra_headers {
+            for (field, value) in extra_headers.iter() {
+                let values = header_values(value);
+                if values.is_empty() {
+                    gst_warning!(
+                        cat,
+                        obj: element,
+                        "Can't convert header {} to a string",
+                        field
+                    );
+                    continue;
+                }
+
+                for value in values {
+                    headers.append_raw(String::from(field), value.into_bytes());
+                }
+            }
+        }
+
+        req.headers(headers);
+
+        req
+    }
+}
+
+#[derive(Debug)]
+pub struct ResponseHead {
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: Headers,
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Response(Result<ResponseHead, reqwest::Error>),
+    Data(io::Result<Vec<u8>>),
+    // Wakes up the streaming thread when unlocking
+    Unlock,
+}
+
+#[derive(Debug, Default)]
+struct CancellerState {
+    flushing: bool,
+    // Sender of the current request, used to wake up the streaming thread
+    sender: Option<mpsc::SyncSender<Message>>,
+    // Cache the streaming thread might be waiting on
+    cache: Option<Arc<Cache>>,
+}
+
+// Requests run in their own thread so that the streaming thread can stop waiting for
+// them when unlocking. A stalled request is left behind and ends with its timeout.
+#[derive(Debug, Default, Clone)]
+pub struct Canceller(Arc<(Mutex<CancellerState>, Condvar)>);
+
+impl Canceller {
+    pub fn is_flushing(&self) -> bool {
+        (self.0).0.lock().unwrap().flushing
+    }
+
+    fn set_sender(&self, sender: mpsc::SyncSender<Message>) -> bool {
+        let mut state = (self.0).0.lock().unwrap();
+        if state.flushing {
+            return false;
+        }
+        state.sender = Some(sender);
+        true
+    }
+
+    pub fn set_cache(&self, cache: Arc<Cache>) {
+        (self.0).0.lock().unwrap().cache = Some(cache);
+    }
+
+    // Sends the request from its own thread and waits for the response. Returns None if
+    // unlocked before
+    pub fn send(
+        &self,
+        req: RequestBuilder,
+    ) -> Option<Result<(ResponseHead, Connection), reqwest::Error>> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        if !self.set_sender(sender.clone()) {
+            return None;
+        }
+
+        thread::spawn(move || run_request(req, sender));
+
+        let connection = Connection {
+            receiver: receiver,
+            canceller: self.clone(),
+            pending: Vec::new(),
+            pending_offset: 0,
+        };
+
+        match connection.recv() {
+            Some(Message::Response(Ok(head))) => Some(Ok((head, connection))),
+            Some(Message::Response(Err(err))) => Some(Err(err)),
+            Some(msg) => unreachable!("Unexpected message {:?}", msg),
+            None => None,
+        }
+    }
+
+    // Returns false if unlocked while waiting
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let &(ref lock, ref cond) = &*self.0;
+        let deadline = Instant::now() + timeout;
+
+        let mut state = lock.lock().unwrap();
+        loop {
+            if state.flushing {
+                return false;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            state = cond.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+impl Unlocker for Canceller {
+    fn unlock(&self) {
+        let &(ref lock, ref cond) = &*self.0;
+        let cache = {
+            let mut state = lock.lock().unwrap();
+
+            state.flushing = true;
+            if let Some(ref sender) = state.sender {
+                // If the channel is full the streaming thread isn't waiting anyway
+                let _ = sender.try_send(Message::Unlock);
+            }
+            cond.notify_all();
+
+            state.cache.clone()
+        };
+
+        // Only after releasing our lock, the cache checks for flushing with its lock held
+        if let Some(cache) = cache {
+            cache.wake();
+        }
+    }
+
+    fn unlock_stop(&self) {
+        (self.0).0.lock().unwrap().flushing = false;
+    }
+}
+
+// Receiving end of a request that is running in its own thread
+#[derive(Debug)]
+pub struct Connection {
+    pub receiver: mpsc::Receiver<Message>,
+    canceller: Canceller,
+    pending: Vec<u8>,
+    pending_offset: usize,
+}
+
+impl Connection {
+    // Returns None when unlocked
+    fn recv(&self) -> Option<Message> {
+        loop {
+            if self.canceller.is_flushing() {
+                return None;
+            }
+
+            match self.receiver.recv() {
+                // Stale unlock message, check again
+                Ok(Message::Unlock) => continue,
+                Ok(msg) => return Some(msg),
+                Err(_) => return Some(Message::Data(Ok(Vec::new()))),
+            }
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        if self.pending_offset == self.pending.len() {
+            match self.recv() {
+                Some(Message::Data(Ok(pending))) => {
+                    self.pending = pending;
+                    self.pending_offset = 0;
+                }
+                Some(Message::Data(Err(err))) => return Err(err),
+                Some(msg) => unreachable!("Unexpected message {:?}", msg),
+                None => return Err(io::Error::new(io::ErrorKind::Other, "Flushing")),
+            }
+        }
+
+        let len = cmp::min(data.len(), self.pending.len() - self.pending_offset);
+        data[..len].copy_from_slice(&self.pending[self.pending_offset..self.pending_offset + len]);
+        self.pending_offset += len;
+
+        Ok(len)
+    }
+}
+
+// Runs in its own thread until the request is finished or the receiver is gone
+fn run_request(mut req: RequestBuilder, sender: mpsc::SyncSender<Message>) {
+    let mut response = match req.send() {
+        Ok(response) => response,
+        Err(err) => {
+            let _ = sender.send(Message::Response(Err(err)));
+            return;
+        }
+    };
+
+    let head = ResponseHead {
+        url: response.url().clone(),
+        status: response.status(),
+        headers: response.headers().clone(),
+    };
+    if sender.send(Message::Response(Ok(head))).is_err() {
+        return;
+    }
+
+    loop {
+        let mut data = vec![0; READ_CHUNK_SIZE];
+        let res = response.read(&mut data).map(|size| {
+            data.truncate(size);
+            data
+        });
+
+        let done = match res {
+            Ok(ref data) => data.is_empty(),
+            Err(_) => true,
+        };
+
+        if sender.send(Message::Data(res)).is_err() || done {
+            break;
+        }
+    }
+}
+
+// Loads all certificates of a PEM bundle or a single DER encoded certificate
+fn load_certificates(path: &str) -> Result<Vec<Certificate>, gst::ErrorMessage> {
+    let invalid = |err: String| {
+        gst_error_msg!(
+            gst::ResourceError::Settings,
+            ["Failed to load CA file {}: {}", path, err]
+        )
+    };
+
+    let mut data = Vec::new();
+    try!(
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut data))
+            .map_err(|err| invalid(err.to_string()))
+    );
+
+    const PEM_END: &'static str = "-----END CERTIFICATE-----";
+
+    let pem = match str::from_utf8(&data) {
+        Ok(pem) if pem.contains("-----BEGIN CERTIFICATE-----") => pem,
+        _ => {
+            let certificate =
+                try!(Certificate::from_der(&data).map_err(|err| invalid(err.to_string())));
+            return Ok(vec![certificate]);
+        }
+    };
+
+    let mut certificates = Vec::new();
+    for block in pem.split(PEM_END).filter(|block| block.contains("-----BEGIN")) {
+        let block = format!("{}{}\n", block.trim(), PEM_END);
+        certificates.push(try!(
+            Certificate::from_pem(block.as_bytes()).map_err(|err| invalid(err.to_string()))
+        ));
+    }
+
+    Ok(certificates)
+}
+
+// Client certificate and private key from a PKCS #12 archive
+fn load_identity(path: &str, password: &str) -> Result<Pkcs12, gst::ErrorMessage> {
+    let mut data = Vec::new();
+
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|err| err.to_string())
+        .and_then(|_| Pkcs12::from_der(&data, password).map_err(|err| err.to_string()))
+        .map_err(|err| {
+            gst_error_msg!(
+                gst::ResourceError::Settings,
+                ["Failed to load client certificate {}: {}", path, err]
+            )
+        })
+}
+
+// Walks the causes of a failed request to the TLS error and checks if the server certificate
+// could not be verified. Other TLS errors, e.g. protocol mismatches or rejected client
+// certificates, are not certificate errors and can be retried
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+pub fn is_certificate_error(err: &reqwest::Error) -> bool {
+    let mut cause = err.get_ref();
+    while let Some(err) = cause {
+        if let Some(err) = err.downcast_ref::<native_tls::Error>() {
+            return is_native_tls_certificate_error(err);
+        }
+
+        if let Some(err) = err.downcast_ref::<ssl::Error>() {
+            return err.ssl_error().map_or(false, is_certificate_error_stack);
+        }
+
+        if let Some(stack) = err.downcast_ref::<ErrorStack>() {
+            return is_certificate_error_stack(stack);
+        }
+
+        // Connection errors wrap the TLS error in an I/O error
+        cause = match err.downcast_ref::<io::Error>() {
+            Some(err) => err.get_ref(),
+            None => None,
+        };
+    }
+
+    false
+}
+
+// Errors of the other TLS backends are not classified, they are all handled like any other
+// request error
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "windows"))]
+pub fn is_certificate_error(_err: &reqwest::Error) -> bool {
+    false
+}
+
+// native-tls only exposes the OpenSSL error through its causes, which can't be downcast, so
+// their descriptions are checked for the X509 verification failure instead
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+fn is_native_tls_certificate_error(err: &native_tls::Error) -> bool {
+    use std::error::Error;
+
+    let mut cause: Option<&Error> = Some(err);
+    while let Some(err) = cause {
+        if err.to_string().contains("certificate verify failed") {
+            return true;
+        }
+        cause = err.cause();
+    }
+
+    false
+}
+
+// Only X509 verification failures, not any other OpenSSL error
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+fn is_certificate_error_stack(stack: &ErrorStack) -> bool {
+    stack
+        .errors()
+        .iter()
+        .any(|err| err.reason() == Some("certificate verify failed"))
+}
+
+pub fn status_error(uri: &Url, status: StatusCode) -> gst::ErrorMessage {
+    match status {
+        StatusCode::Unauthorized
+        | StatusCode::Forbidden
+        | StatusCode::ProxyAuthenticationRequired => gst_error_msg!(
+            gst::ResourceError::NotAuthorized,
+            ["Not authorized to fetch {}: {}", uri, status]
+        ),
+        StatusCode::NotFound | StatusCode::Gone => gst_error_msg!(
+            gst::ResourceError::NotFound,
+            ["Failed to find {}: {}", uri, status]
+        ),
+        _ if status.is_server_error() => gst_error_msg!(
+            gst::ResourceError::OpenRead,
+            ["Server error while fetching {}: {}", uri, status]
+        ),
+        _ => gst_error_msg!(
+            gst::ResourceError::Read,
+            ["Failed to fetch {}: {}", uri, status]
+        ),
+    }
+}
+
+pub fn request_error(uri: &Url, err: &reqwest::Error) -> gst::ErrorMessage {
+    if is_certificate_error(err) {
+        gst_error_msg!(
+            gst::ResourceError::OpenRead,
+            [
+                "Server certificate validation failed for {}: {}",
+                uri,
+                err.to_string()
+            ]
+        )
+    } else {
+        gst_error_msg!(
+            gst::ResourceError::Read,
+            ["Failed to fetch {}: {}", uri, err.to_string()]
+        )
+    }
+}
+
+// Strings are used as is, arrays and lists give one header line per value and
+// everything else is converted to a string
+pub fn header_values(value: &glib::Value) -> Vec<String> {
+    if let Some(array) = value.get::<gst::Array>() {
+        array.as_slice().iter().flat_map(|v| header_values(v)).collect()
+    } else if let Some(list) = value.get::<gst::List>() {
+        list.as_slice().iter().flat_map(|v| header_values(v)).collect()
+    } else if let Some(s) = value.get::<String>() {
+        vec![s]
+    } else {
+        value
+            .transform::<String>()
+            .and_then(|v| v.get::<String>())
+            .into_iter()
+            .collect()
+    }
+}
@@ -0,0 +1,1008 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use glib;
+use gst;
+use gst::prelude::*;
+
+use gst_plugin::element::*;
+use gst_plugin_simple::Unlocker;
+
+use gobject_subclass::object::*;
+
+#[cfg(any(
+    feature = "openssl",
+    not(any(target_os = "macos", target_os = "ios", target_os = "windows"))
+))]
+use openssl::symm::{Cipher, Crypter, Mode};
+use reqwest::{Client, Method};
+use url::Url;
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{i32, u32};
+
+use client::{self, Canceller, ClientSettings, Connection};
+use m3u8::{self, Key, MediaPlaylist, Playlist, Segment, Variant};
+
+const DEFAULT_CONNECTION_SPEED: u32 = 0;
+const DEFAULT_USER_AGENT: &'static str = "GStreamer rshlsdemux";
+
+const CHUNK_SIZE: usize = 16384;
+
+static PROPERTIES: [Property; 15] = [
+    Property::UInt(
+        "connection-speed",
+        "Connection Speed",
+        "Network connection speed in kbps (0 = measure while downloading)",
+        (0, u32::MAX / 1000),
+        DEFAULT_CONNECTION_SPEED,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "user-agent",
+        "User-Agent",
+        "Value of the User-Agent HTTP request header field",
+        Some(DEFAULT_USER_AGENT),
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boxed(
+        "extra-headers",
+        "Extra Headers",
+        "Extra headers to append to the HTTP requests",
+        gst::Structure::static_type,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boxed(
+        "cookies",
+        "Cookies",
+        "HTTP request cookies",
+        Vec::<String>::static_type,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "user-id",
+        "User ID",
+        "HTTP user id for authentication",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "user-pw",
+        "User Password",
+        "HTTP user password for authentication",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "timeout",
+        "Timeout",
+        "Value in seconds to timeout a blocking I/O",
+        (1, 3600),
+        client::DEFAULT_TIMEOUT,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "compress",
+        "Compress",
+        "Allow compressed content encodings",
+        client::DEFAULT_COMPRESS,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "proxy",
+        "Proxy",
+        "HTTP proxy server URI",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Int(
+        "retries",
+        "Retries",
+        "Maximum number of retries of a failed playlist, key or segment request (-1 = unlimited)",
+        (-1, i32::MAX),
+        client::DEFAULT_RETRIES,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "retry-backoff",
+        "Retry Backoff",
+        "Delay in milliseconds before the first retry, doubled for every further attempt",
+        (0, u32::MAX),
+        client::DEFAULT_RETRY_BACKOFF,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "ssl-ca-file",
+        "SSL CA File",
+        "Location of a CA file (PEM bundle or DER) to use in addition to the system ones",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "client-certificate",
+        "Client Certificate",
+        "Location of a PKCS #12 archive with the client certificate and private key",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "client-certificate-password",
+        "Client Certificate Password",
+        "Password of the client certificate archive",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "ssl-strict",
+        "SSL Strict",
+        "Fail for invalid server certificates",
+        client::DEFAULT_SSL_STRICT,
+        PropertyMutability::ReadWrite,
+    ),
+];
+
+#[derive(Debug, Clone)]
+struct Settings {
+    connection_speed: u32,
+    // Taken over when starting the download
+    client: ClientSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            connection_speed: DEFAULT_CONNECTION_SPEED,
+            client: ClientSettings::new(DEFAULT_USER_AGENT),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // Playlist data from upstream, collected until EOS
+    playlist: Vec<u8>,
+    // Stops the download thread and interrupts its requests
+    download: Option<(Canceller, thread::JoinHandle<()>)>,
+}
+
+struct HlsDemux {
+    cat: gst::DebugCategory,
+    sinkpad: gst::Pad,
+    srcpad: gst::Pad,
+    settings: Arc<Mutex<Settings>>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+enum DownloadError {
+    Stopped,
+    Flow(gst::FlowReturn),
+    Error(gst::ErrorMessage),
+}
+
+impl From<gst::ErrorMessage> for DownloadError {
+    fn from(err: gst::ErrorMessage) -> Self {
+        DownloadError::Error(err)
+    }
+}
+
+// AES-128-CBC decryption of segments. OpenSSL is always available where native-tls uses it,
+// on other platforms only with the openssl feature
+#[cfg(any(
+    feature = "openssl",
+    not(any(target_os = "macos", target_os = "ios", target_os = "windows"))
+))]
+struct Decrypter(Crypter);
+
+#[cfg(any(
+    feature = "openssl",
+    not(any(target_os = "macos", target_os = "ios", target_os = "windows"))
+))]
+impl Decrypter {
+    fn new(key: &[u8], iv: &[u8; 16]) -> Result<Decrypter, String> {
+        Crypter::new(Cipher::aes_128_cbc(), Mode::Decrypt, key, Some(&iv[..]))
+            .map(Decrypter)
+            .map_err(|err| err.to_string())
+    }
+
+    // Empty data marks the end of the segment
+    fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut decrypted = vec![0; data.len() + Cipher::aes_128_cbc().block_size()];
+        let mut len = try!(
+            self.0
+                .update(data, &mut decrypted)
+                .map_err(|err| err.to_string())
+        );
+        if data.is_empty() {
+            len += try!(
+                self.0
+                    .finalize(&mut decrypted[len..])
+                    .map_err(|err| err.to_string())
+            );
+        }
+        decrypted.truncate(len);
+
+        Ok(decrypted)
+    }
+}
+
+#[cfg(not(any(
+    feature = "openssl",
+    not(any(target_os = "macos", target_os = "ios", target_os = "windows"))
+)))]
+enum Decrypter {}
+
+#[cfg(not(any(
+    feature = "openssl",
+    not(any(target_os = "macos", target_os = "ios", target_os = "windows"))
+)))]
+impl Decrypter {
+    fn new(_key: &[u8], _iv: &[u8; 16]) -> Result<Decrypter, String> {
+        Err(String::from("Decryption requires the openssl feature"))
+    }
+
+    fn decrypt(&mut self, _data: &[u8]) -> Result<Vec<u8>, String> {
+        match *self {}
+    }
+}
+
+// Runs in its own thread, fetches the playlists and pushes the segment data
+struct Download {
+    cat: gst::DebugCategory,
+    element: gst::Element,
+    srcpad: gst::Pad,
+    client: Client,
+    client_settings: ClientSettings,
+    settings: Arc<Mutex<Settings>>,
+    canceller: Canceller,
+    stream_id: String,
+    caps: Option<gst::Caps>,
+    offset: u64,
+    discont: bool,
+    keys: HashMap<Url, Vec<u8>>,
+    // Measured during the last segment download, in bits per second
+    bitrate: Option<u64>,
+}
+
+impl Download {
+    fn run(mut self, uri: Url, data: Vec<u8>) {
+        match self.stream(uri, data) {
+            Ok(()) => {
+                gst_debug!(self.cat, obj: &self.element, "All segments pushed");
+                self.srcpad.push_event(gst::Event::new_eos().build());
+            }
+            Err(DownloadError::Stopped) => {
+                gst_debug!(self.cat, obj: &self.element, "Stopped");
+            }
+            Err(DownloadError::Flow(flow_ret)) => {
+                gst_debug!(self.cat, obj: &self.element, "Stopped with {:?}", flow_ret);
+
+                match flow_ret {
+                    gst::FlowReturn::Flushing => (),
+                    gst::FlowReturn::Eos => {
+                        self.srcpad.push_event(gst::Event::new_eos().build());
+                    }
+                    _ => {
+                        gst_element_error!(
+                            self.element,
+                            gst::StreamError::Failed,
+                            ["Internal data stream error: {:?}", flow_ret]
+                        );
+                        self.srcpad.push_event(gst::Event::new_eos().build());
+                    }
+                }
+            }
+            Err(DownloadError::Error(ref msg)) => {
+                gst_error!(self.cat, obj: &self.element, "Failed: {:?}", msg);
+                self.element.post_error_message(msg);
+                self.srcpad.push_event(gst::Event::new_eos().build());
+            }
+        }
+    }
+
+    fn check_stopped(&self) -> Result<(), DownloadError> {
+        if self.canceller.is_flushing() {
+            Err(DownloadError::Stopped)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn wait(&self, timeout: Duration) -> Result<(), DownloadError> {
+        if self.canceller.wait(timeout) {
+            Ok(())
+        } else {
+            Err(DownloadError::Stopped)
+        }
+    }
+
+    // Waits before the next attempt after a failure, or gives up with the error
+    fn retry(&self, attempt: &mut i32, err: gst::ErrorMessage) -> Result<(), DownloadError> {
+        try!(self.check_stopped());
+
+        if !self.client_settings.may_retry(*attempt) {
+            gst_error!(self.cat, obj: &self.element, "Giving up after {} retries", attempt);
+            return Err(err.into());
+        }
+
+        let delay = self.client_settings.retry_delay(*attempt);
+        *attempt += 1;
+
+        gst_warning!(
+            self.cat,
+            obj: &self.element,
+            "{:?}, retrying in {:?} (attempt {})",
+            err,
+            delay,
+            attempt
+        );
+        self.wait(delay)
+    }
+
+    // Retries failed requests and server errors, other errors are final
+    fn open(&self, uri: &Url, attempt: &mut i32) -> Result<Connection, DownloadError> {
+        loop {
+            let req = self.client_settings.create_request(
+                self.cat,
+                &self.element,
+                &self.client,
+                Method::Get,
+                uri.clone(),
+            );
+
+            let err = match self.canceller.send(req) {
+                None => return Err(DownloadError::Stopped),
+                Some(Ok((head, connection))) => {
+                    if head.status.is_success() {
+                        return Ok(connection);
+                    }
+
+                    let err = client::status_error(uri, head.status);
+                    if !head.status.is_server_error() {
+                        return Err(err.into());
+                    }
+                    err
+                }
+                Some(Err(err)) => {
+                    if client::is_certificate_error(&err) {
+                        return Err(client::request_error(uri, &err).into());
+                    }
+                    client::request_error(uri, &err)
+                }
+            };
+
+            try!(self.retry(attempt, err));
+        }
+    }
+
+    fn read_error(&self, uri: &Url, err: io::Error) -> DownloadError {
+        if self.canceller.is_flushing() {
+            return DownloadError::Stopped;
+        }
+
+        gst_error_msg!(
+            gst::ResourceError::Read,
+            ["Failed to fetch {}: {}", uri, err.to_string()]
+        ).into()
+    }
+
+    fn fetch(&self, uri: &Url) -> Result<Vec<u8>, DownloadError> {
+        let mut attempt = 0;
+
+        loop {
+            let mut connection = try!(self.open(uri, &mut attempt));
+
+            let mut data = Vec::new();
+            let err = match connection.read_to_end(&mut data) {
+                Ok(_) => return Ok(data),
+                Err(err) => self.read_error(uri, err),
+            };
+
+            match err {
+                DownloadError::Error(err) => try!(self.retry(&mut attempt, err)),
+                err => return Err(err),
+            }
+        }
+    }
+
+    fn fetch_media_playlist(&self, uri: &Url) -> Result<MediaPlaylist, DownloadError> {
+        gst_debug!(self.cat, obj: &self.element, "Fetching media playlist {}", uri);
+
+        let data = try!(self.fetch(uri));
+        match m3u8::parse(&data, uri) {
+            Ok(Playlist::Media(playlist)) => Ok(playlist),
+            Ok(Playlist::Master(..)) => Err(gst_error_msg!(
+                gst::StreamError::Demux,
+                ["Expected media playlist at {}", uri]
+            ).into()),
+            Err(err) => Err(gst_error_msg!(
+                gst::StreamError::Demux,
+                ["Failed to parse playlist {}: {}", uri, err]
+            ).into()),
+        }
+    }
+
+    // Highest bandwidth that fits into the connection speed, or the lowest one
+    fn select_variant(&self, variants: &[Variant]) -> usize {
+        let connection_speed = self.settings.lock().unwrap().connection_speed;
+        let speed = if connection_speed > 0 {
+            Some(connection_speed as u64 * 1000)
+        } else {
+            // Leave some headroom for fluctuations
+            self.bitrate.map(|bitrate| bitrate * 4 / 5)
+        };
+
+        let speed = match speed {
+            // Start with the first variant as recommended by the specification
+            None => return 0,
+            Some(speed) => speed,
+        };
+
+        let best = variants
+            .iter()
+            .enumerate()
+            .filter(|&(_, v)| v.bandwidth <= speed)
+            .max_by_key(|&(_, v)| v.bandwidth);
+
+        match best {
+            Some((idx, _)) => idx,
+            None => variants
+                .iter()
+                .enumerate()
+                .min_by_key(|&(_, v)| v.bandwidth)
+                .map(|(idx, _)| idx)
+                .unwrap_or(0),
+        }
+    }
+
+    fn stream(&mut self, uri: Url, data: Vec<u8>) -> Result<(), DownloadError> {
+        let playlist = try!(m3u8::parse(&data, &uri).map_err(|err| {
+            gst_error_msg!(
+                gst::StreamError::Demux,
+                ["Failed to parse playlist {}: {}", uri, err]
+            )
+        }));
+
+        let mut variants = Vec::new();
+        let mut variant = None;
+        let (mut media_uri, mut media) = match playlist {
+            Playlist::Media(media) => (uri.clone(), media),
+            Playlist::Master(master) => {
+                variants = master.variants;
+                let idx = self.select_variant(&variants);
+                gst_info!(
+                    self.cat,
+                    obj: &self.element,
+                    "Selected variant {:?}",
+                    variants[idx]
+                );
+                variant = Some(idx);
+                let media_uri = variants[idx].uri.clone();
+                let media = try!(self.fetch_media_playlist(&media_uri));
+                (media_uri, media)
+            }
+        };
+
+        let mut next_sequence: Option<u64> = None;
+
+        loop {
+            try!(self.check_stopped());
+
+            // Live playlists start three target durations from the end
+            let first = match next_sequence {
+                Some(sequence) => sequence,
+                None if media.endlist => media.media_sequence,
+                None => {
+                    let skip = media.segments.len().saturating_sub(3);
+                    media.media_sequence + skip as u64
+                }
+            };
+
+            let segments = media
+                .segments
+                .iter()
+                .filter(|s| s.sequence >= first)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if next_sequence.is_some() && segments.first().map(|s| s.sequence) > next_sequence {
+                gst_warning!(self.cat, obj: &self.element, "Missed segments");
+                self.discont = true;
+            }
+
+            let mut switched = false;
+            for segment in &segments {
+                try!(self.check_stopped());
+
+                try!(self.push_segment(segment));
+                next_sequence = Some(segment.sequence + 1);
+
+                if variants.is_empty() {
+                    continue;
+                }
+
+                let idx = self.select_variant(&variants);
+                if Some(idx) != variant {
+                    gst_info!(
+                        self.cat,
+                        obj: &self.element,
+                        "Switching to variant {:?}",
+                        variants[idx]
+                    );
+                    variant = Some(idx);
+                    media_uri = variants[idx].uri.clone();
+                    media = try!(self.fetch_media_playlist(&media_uri));
+                    self.discont = true;
+                    switched = true;
+                    break;
+                }
+            }
+
+            if switched {
+                continue;
+            }
+
+            if media.endlist {
+                return Ok(());
+            }
+
+            // Wait half the target duration if nothing changed since the last refresh
+            let wait = if segments.is_empty() {
+                media.target_duration / 2.0
+            } else {
+                media.target_duration
+            };
+            try!(self.wait(Duration::from_millis((wait * 1000.0) as u64)));
+
+            media = try!(self.fetch_media_playlist(&media_uri));
+        }
+    }
+
+    fn get_key(&mut self, key: &Key) -> Result<Vec<u8>, DownloadError> {
+        if let Some(data) = self.keys.get(&key.uri) {
+            return Ok(data.clone());
+        }
+
+        let data = try!(self.fetch(&key.uri));
+        if data.len() != 16 {
+            return Err(gst_error_msg!(
+                gst::StreamError::Decrypt,
+                ["Invalid key size {} at {}", data.len(), key.uri]
+            ).into());
+        }
+
+        self.keys.insert(key.uri.clone(), data.clone());
+        Ok(data)
+    }
+
+    fn push_segment(&mut self, segment: &Segment) -> Result<(), DownloadError> {
+        gst_debug!(self.cat, obj: &self.element, "Fetching segment {:?}", segment);
+
+        let decrypt_error = |err: String| {
+            gst_error_msg!(
+                gst::StreamError::Decrypt,
+                ["Failed to decrypt {}: {}", segment.uri, err]
+            )
+        };
+
+        let mut decrypter = match segment.key {
+            None => None,
+            Some(ref key) => {
+                let data = try!(self.get_key(key));
+                // Without explicit IV the media sequence number is used
+                let iv = key.iv.unwrap_or_else(|| {
+                    let mut iv = [0u8; 16];
+                    for i in 0..8 {
+                        iv[8 + i] = (segment.sequence >> (56 - 8 * i)) as u8;
+                    }
+                    iv
+                });
+
+                Some(try!(Decrypter::new(&data, &iv).map_err(|err| decrypt_error(err))))
+            }
+        };
+
+        // Only the time spent downloading is measured, not the time blocked pushing while
+        // downstream is full. Otherwise the bitrate would drop to the playback rate
+        let start = Instant::now();
+
+        // Data already pushed can't be taken back, so only the request is retried
+        let mut attempt = 0;
+        let mut response = try!(self.open(&segment.uri, &mut attempt));
+        let mut download_time = start.elapsed();
+
+        if segment.discontinuity {
+            self.discont = true;
+        }
+
+        let mut size = 0;
+        loop {
+            try!(self.check_stopped());
+
+            let mut data = vec![0; CHUNK_SIZE];
+            let read_start = Instant::now();
+            let len = try!(
+                response
+                    .read(&mut data)
+                    .map_err(|err| self.read_error(&segment.uri, err))
+            );
+            download_time += read_start.elapsed();
+            data.truncate(len);
+            size += len;
+
+            let data = match decrypter {
+                None => data,
+                Some(ref mut decrypter) => {
+                    try!(decrypter.decrypt(&data).map_err(|err| decrypt_error(err)))
+                }
+            };
+
+            if !data.is_empty() {
+                try!(self.push_data(segment, data));
+            }
+
+            if len == 0 {
+                break;
+            }
+        }
+
+        let elapsed = download_time.as_secs() as f64
+            + download_time.subsec_nanos() as f64 / 1_000_000_000.0;
+        if elapsed > 0.0 {
+            self.bitrate = Some((size as f64 * 8.0 / elapsed) as u64);
+            gst_debug!(
+                self.cat,
+                obj: &self.element,
+                "Measured bitrate {:?}",
+                self.bitrate
+            );
+        }
+
+        Ok(())
+    }
+
+    fn push_data(&mut self, segment: &Segment, data: Vec<u8>) -> Result<(), DownloadError> {
+        let caps = segment_caps(&segment.uri);
+
+        if self.caps.is_none() {
+            self.srcpad.push_event(
+                gst::Event::new_stream_start(&self.stream_id)
+                    .group_id(gst::util_group_id_next())
+                    .build(),
+            );
+            self.srcpad.push_event(gst::Event::new_caps(&caps).build());
+
+            let segment = gst::FormattedSegment::<gst::format::Bytes>::new();
+            self.srcpad
+                .push_event(gst::Event::new_segment(&segment).build());
+
+            self.caps = Some(caps);
+            self.discont = true;
+        } else if self.caps.as_ref() != Some(&caps) {
+            self.srcpad.push_event(gst::Event::new_caps(&caps).build());
+            self.caps = Some(caps);
+        }
+
+        let len = data.len() as u64;
+        let mut buffer = gst::Buffer::from_mut_slice(data).unwrap();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_offset(self.offset);
+            buffer.set_offset_end(self.offset + len);
+            if self.discont {
+                buffer.set_flags(gst::BufferFlags::DISCONT);
+                self.discont = false;
+            }
+        }
+        self.offset += len;
+
+        match self.srcpad.push(buffer) {
+            gst::FlowReturn::Ok => Ok(()),
+            flow_ret => Err(DownloadError::Flow(flow_ret)),
+        }
+    }
+}
+
+// The container can't be known before looking at the data, so guess it from the URI
+fn segment_caps(uri: &Url) -> gst::Caps {
+    let path = uri.path().to_lowercase();
+
+    if path.ends_with(".aac") {
+        gst::Caps::new_simple(
+            "audio/mpeg",
+            &[("mpegversion", &4i32), ("stream-format", &"adts")],
+        )
+    } else if path.ends_with(".mp4") || path.ends_with(".m4s") {
+        gst::Caps::new_simple("video/quicktime", &[])
+    } else {
+        gst::Caps::new_simple("video/mpegts", &[("systemstream", &true)])
+    }
+}
+
+impl HlsDemux {
+    fn new(_element: &Element, sinkpad: gst::Pad, srcpad: gst::Pad) -> Self {
+        Self {
+            cat: gst::DebugCategory::new(
+                "rshlsdemux",
+                gst::DebugColorFlags::empty(),
+                "Rust HLS demuxer",
+            ),
+            sinkpad: sinkpad,
+            srcpad: srcpad,
+            settings: Arc::new(Mutex::new(Settings::default())),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn class_init(klass: &mut ElementClass) {
+        klass.set_metadata(
+            "HLS Demuxer",
+            "Codec/Demuxer/Adaptive",
+            "HTTP Live Streaming demuxer",
+            "Sebastian Dröge <sebastian@centricular.com>",
+        );
+
+        let caps = gst::Caps::new_simple("application/x-hls", &[]);
+        let sink_pad_template = gst::PadTemplate::new(
+            "sink",
+            gst::PadDirection::Sink,
+            gst::PadPresence::Always,
+            &caps,
+        );
+        klass.add_pad_template(sink_pad_template);
+
+        let caps = gst::Caps::new_any();
+        let src_pad_template = gst::PadTemplate::new(
+            "src",
+            gst::PadDirection::Src,
+            gst::PadPresence::Always,
+            &caps,
+        );
+        klass.add_pad_template(src_pad_template);
+
+        klass.install_properties(&PROPERTIES);
+    }
+
+    fn init(element: &Element) -> Box<ElementImpl<Element>> {
+        let templ = element.get_pad_template("sink").unwrap();
+        let sinkpad = gst::Pad::new_from_template(&templ, "sink");
+        sinkpad.set_chain_function(HlsDemux::sink_chain);
+        sinkpad.set_event_function(HlsDemux::sink_event);
+        element.add_pad(&sinkpad).unwrap();
+
+        let templ = element.get_pad_template("src").unwrap();
+        let srcpad = gst::Pad::new_from_template(&templ, "src");
+        srcpad.set_event_function(HlsDemux::src_event);
+        element.add_pad(&srcpad).unwrap();
+
+        let imp = Self::new(element, sinkpad, srcpad);
+        Box::new(imp)
+    }
+
+    fn sink_chain(
+        _pad: &gst::Pad,
+        parent: &Option<gst::Object>,
+        buffer: gst::Buffer,
+    ) -> gst::FlowReturn {
+        let element = parent.as_ref().unwrap().downcast_ref::<Element>().unwrap();
+        let demux = element.get_impl().downcast_ref::<HlsDemux>().unwrap();
+
+        gst_trace!(demux.cat, obj: element, "Got playlist data {:?}", buffer);
+
+        let map = match buffer.map_readable() {
+            None => {
+                gst_element_error!(element, gst::CoreError::Failed, ["Failed to map buffer"]);
+                return gst::FlowReturn::Error;
+            }
+            Some(map) => map,
+        };
+
+        let mut state = demux.state.lock().unwrap();
+        state.playlist.extend_from_slice(map.as_slice());
+
+        gst::FlowReturn::Ok
+    }
+
+    fn sink_event(pad: &gst::Pad, parent: &Option<gst::Object>, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        let element = parent.as_ref().unwrap().downcast_ref::<Element>().unwrap();
+        let demux = element.get_impl().downcast_ref::<HlsDemux>().unwrap();
+
+        match event.view() {
+            // Starts the download instead of forwarding EOS
+            EventView::Eos(..) => demux.start_download(element),
+            // We send our own events on the source pad
+            EventView::StreamStart(..) | EventView::Caps(..) | EventView::Segment(..) => true,
+            _ => pad.event_default(parent.as_ref(), event),
+        }
+    }
+
+    fn src_event(pad: &gst::Pad, parent: &Option<gst::Object>, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        match event.view() {
+            // Seeking in playlists is not supported
+            EventView::Seek(..) => false,
+            _ => pad.event_default(parent.as_ref(), event),
+        }
+    }
+
+    fn start_download(&self, element: &Element) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.download.is_some() {
+            gst_warning!(self.cat, obj: element, "Download already started");
+            return true;
+        }
+
+        let uri = {
+            use gst::QueryView;
+
+            let mut query = gst::Query::new_uri();
+            let uri = if self.sinkpad.peer_query(&mut query) {
+                match query.view() {
+                    QueryView::Uri(ref q) => q.get_uri(),
+                    _ => unreachable!(),
+                }
+            } else {
+                None
+            };
+
+            match uri.and_then(|uri| Url::parse(&uri).ok()) {
+                Some(uri) => uri,
+                None => {
+                    gst_element_error!(
+                        element,
+                        gst::StreamError::Demux,
+                        ["Failed to get playlist URI from upstream"]
+                    );
+                    return false;
+                }
+            }
+        };
+
+        gst_debug!(self.cat, obj: element, "Starting download of {}", uri);
+
+        let client_settings = self.settings.lock().unwrap().client.clone();
+        let client = match client_settings.create_client(self.cat, element.upcast_ref()) {
+            Ok(client) => client,
+            Err(ref msg) => {
+                element.post_error_message(msg);
+                return false;
+            }
+        };
+
+        let canceller = Canceller::default();
+        let download = Download {
+            cat: self.cat,
+            element: element.clone().upcast::<gst::Element>(),
+            srcpad: self.srcpad.clone(),
+            client: client,
+            client_settings: client_settings,
+            settings: self.settings.clone(),
+            canceller: canceller.clone(),
+            stream_id: format!("{}/src", uri),
+            caps: None,
+            offset: 0,
+            discont: true,
+            keys: HashMap::new(),
+            bitrate: None,
+        };
+
+        let data = mem::replace(&mut state.playlist, Vec::new());
+        let thread = thread::spawn(move || download.run(uri, data));
+        state.download = Some((canceller, thread));
+
+        true
+    }
+
+    // Called after the source pad was deactivated, so the thread can't be blocked pushing
+    // anymore and a running request is interrupted by the canceller
+    fn stop_download(&self, element: &Element) {
+        let download = {
+            let mut state = self.state.lock().unwrap();
+            state.playlist.clear();
+            state.download.take()
+        };
+
+        if let Some((canceller, thread)) = download {
+            gst_debug!(self.cat, obj: element, "Stopping download");
+
+            canceller.unlock();
+            let _ = thread.join();
+
+            gst_debug!(self.cat, obj: element, "Stopped download");
+        }
+    }
+}
+
+impl ObjectImpl<Element> for HlsDemux {
+    fn set_property(&self, _obj: &glib::Object, id: u32, value: &glib::Value) {
+        let prop = &PROPERTIES[id as usize];
+
+        let mut settings = self.settings.lock().unwrap();
+
+        if settings.client.set_property(prop, value) {
+            return;
+        }
+
+        match *prop {
+            Property::UInt("connection-speed", ..) => {
+                settings.connection_speed = value.get().unwrap();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _obj: &glib::Object, id: u32) -> Result<glib::Value, ()> {
+        let prop = &PROPERTIES[id as usize];
+
+        let settings = self.settings.lock().unwrap();
+
+        if let Some(value) = settings.client.get_property(prop) {
+            return Ok(value);
+        }
+
+        match *prop {
+            Property::UInt("connection-speed", ..) => Ok(settings.connection_speed.to_value()),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+impl ElementImpl<Element> for HlsDemux {
+    fn change_state(
+        &self,
+        element: &Element,
+        transition: gst::StateChange,
+    ) -> gst::StateChangeReturn {
+        gst_trace!(self.cat, obj: element, "Changing state {:?}", transition);
+
+        let ret = element.parent_change_state(transition);
+        if ret == gst::StateChangeReturn::Failure {
+            return ret;
+        }
+
+        match transition {
+            gst::StateChange::PausedToReady => {
+                self.stop_download(element);
+            }
+            _ => (),
+        }
+
+        ret
+    }
+}
+
+struct HlsDemuxStatic;
+
+impl ImplTypeStatic<Element> for HlsDemuxStatic {
+    fn get_name(&self) -> &str {
+        "HlsDemux"
+    }
+
+    fn new(&self, element: &Element) -> Box<ElementImpl<Element>> {
+        HlsDemux::init(element)
+    }
+
+    fn class_init(&self, klass: &mut ElementClass) {
+        HlsDemux::class_init(klass);
+    }
+}
+
+pub fn register(plugin: &gst::Plugin) {
+    let hlsdemux_static = HlsDemuxStatic;
+    let type_ = register_type(hlsdemux_static);
+    gst::Element::register(plugin, "rshlsdemux", 256 + 100, type_);
+}
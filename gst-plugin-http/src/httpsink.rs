@@ -0,0 +1,654 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use reqwest::header::Headers;
+use reqwest::{self, Body, Client, Method, RequestBuilder, StatusCode};
+use std::cmp;
+use std::io::{self, Read};
+use std::mem;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use gst_plugin::error::*;
+
+use gst_plugin_simple::sink::*;
+use gst_plugin_simple::{Unlocker, UriValidator};
+
+use glib;
+use gst;
+use gst::prelude::*;
+
+use gobject_subclass::object::*;
+
+use client::{self, ClientSettings};
+use httpsrc::validate_uri;
+
+const DEFAULT_METHOD: &'static str = "PUT";
+const DEFAULT_CHUNKED: bool = true;
+const DEFAULT_USER_AGENT: &'static str = "GStreamer rshttpsink";
+
+// Interval for checking for flushing while the request doesn't take more data
+const SEND_RETRY_INTERVAL_MS: u64 = 50;
+
+// Uploads are not retried and their responses are not decompressed, so the retries and
+// compress properties of the client settings are left out
+pub static PROPERTIES: [Property; 14] = [
+    Property::String(
+        "method",
+        "Method",
+        "HTTP method used for the upload (PUT or POST)",
+        Some(DEFAULT_METHOD),
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "chunked",
+        "Chunked",
+        "Upload with chunked transfer encoding instead of a single request on EOS",
+        DEFAULT_CHUNKED,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "content-type",
+        "Content Type",
+        "Value of the Content-Type HTTP request header field",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "user-agent",
+        "User-Agent",
+        "Value of the User-Agent HTTP request header field",
+        Some(DEFAULT_USER_AGENT),
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boxed(
+        "extra-headers",
+        "Extra Headers",
+        "Extra headers to append to the HTTP request",
+        gst::Structure::static_type,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boxed(
+        "cookies",
+        "Cookies",
+        "HTTP request cookies",
+        Vec::<String>::static_type,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "user-id",
+        "User ID",
+        "HTTP location URI user id for authentication",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "user-pw",
+        "User Password",
+        "HTTP location URI user password for authentication",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "timeout",
+        "Timeout",
+        "Value in seconds to timeout a blocking I/O",
+        (1, 3600),
+        client::DEFAULT_TIMEOUT,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "proxy",
+        "Proxy",
+        "HTTP proxy server URI",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "ssl-ca-file",
+        "SSL CA File",
+        "Location of a CA file (PEM bundle or DER) to use in addition to the system ones",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "client-certificate",
+        "Client Certificate",
+        "Location of a PKCS #12 archive with the client certificate and private key",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "client-certificate-password",
+        "Client Certificate Password",
+        "Password of the client certificate archive",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "ssl-strict",
+        "SSL Strict",
+        "Fail for invalid server certificates",
+        client::DEFAULT_SSL_STRICT,
+        PropertyMutability::ReadWrite,
+    ),
+];
+
+#[derive(Debug, Clone)]
+struct Settings {
+    method: String,
+    chunked: bool,
+    content_type: Option<String>,
+    client: ClientSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            method: DEFAULT_METHOD.into(),
+            chunked: DEFAULT_CHUNKED,
+            content_type: None,
+            client: ClientSettings::new(DEFAULT_USER_AGENT),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ResponseHead {
+    status: StatusCode,
+    headers: Headers,
+}
+
+#[derive(Debug)]
+enum Upload {
+    // Data is sent while it arrives, the request runs in its own thread
+    Chunked {
+        // None marks the end of the data
+        sender: mpsc::SyncSender<Option<Vec<u8>>>,
+        thread: thread::JoinHandle<Result<ResponseHead, reqwest::Error>>,
+    },
+    // Data is collected and sent with its length in a single request on EOS
+    Single { data: Vec<u8> },
+    Finished,
+}
+
+#[derive(Debug)]
+enum StreamingState {
+    Stopped,
+    Started {
+        uri: Url,
+        upload: Upload,
+        position: u64,
+    },
+}
+
+// Request body of chunked uploads. Fails if the upload is aborted before the end
+// of the data so that the server doesn't consider it complete.
+struct BodyReader {
+    receiver: mpsc::Receiver<Option<Vec<u8>>>,
+    pending: Vec<u8>,
+    pending_offset: usize,
+    finished: bool,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, data: &mut [u8]) -> io::Result<usize> {
+        while self.pending_offset == self.pending.len() {
+            if self.finished {
+                return Ok(0);
+            }
+
+            match self.receiver.recv() {
+                Ok(Some(pending)) => {
+                    self.pending = pending;
+                    self.pending_offset = 0;
+                }
+                Ok(None) => self.finished = true,
+                Err(_) => {
+                    return Err(io::Error::new(io::ErrorKind::Other, "Upload aborted"));
+                }
+            }
+        }
+
+        let len = cmp::min(data.len(), self.pending.len() - self.pending_offset);
+        data[..len].copy_from_slice(&self.pending[self.pending_offset..self.pending_offset + len]);
+        self.pending_offset += len;
+
+        Ok(len)
+    }
+}
+
+// Interrupts waiting for a stalled chunked upload to take more data when unlocking
+#[derive(Debug, Default, Clone)]
+struct Flusher(Arc<(Mutex<bool>, Condvar)>);
+
+impl Flusher {
+    // Returns false if unlocked while waiting
+    fn wait(&self, timeout: Duration) -> bool {
+        let &(ref lock, ref cond) = &*self.0;
+        let deadline = Instant::now() + timeout;
+
+        let mut flushing = lock.lock().unwrap();
+        loop {
+            if *flushing {
+                return false;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            flushing = cond.wait_timeout(flushing, deadline - now).unwrap().0;
+        }
+    }
+
+    // Sends to the request thread without blocking unlocking. Returns false if the request
+    // stopped reading the data and an error if unlocked while the server is stalled
+    fn send(
+        &self,
+        sender: &mpsc::SyncSender<Option<Vec<u8>>>,
+        data: Option<Vec<u8>>,
+    ) -> Result<bool, ()> {
+        let mut data = data;
+        loop {
+            match sender.try_send(data) {
+                Ok(()) => return Ok(true),
+                Err(mpsc::TrySendError::Disconnected(_)) => return Ok(false),
+                Err(mpsc::TrySendError::Full(pending)) => data = pending,
+            }
+
+            if !self.wait(Duration::from_millis(SEND_RETRY_INTERVAL_MS)) {
+                return Err(());
+            }
+        }
+    }
+}
+
+impl Unlocker for Flusher {
+    fn unlock(&self) {
+        let &(ref lock, ref cond) = &*self.0;
+        *lock.lock().unwrap() = true;
+        cond.notify_all();
+    }
+
+    fn unlock_stop(&self) {
+        *(self.0).0.lock().unwrap() = false;
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpSink {
+    streaming_state: StreamingState,
+    cat: gst::DebugCategory,
+    client: Client,
+    settings: Settings,
+    flusher: Flusher,
+}
+
+impl HttpSink {
+    pub fn new(_sink: &BaseSink) -> HttpSink {
+        HttpSink {
+            streaming_state: StreamingState::Stopped,
+            cat: gst::DebugCategory::new(
+                "rshttpsink",
+                gst::DebugColorFlags::empty(),
+                "Rust HTTP sink",
+            ),
+            client: Client::new(),
+            settings: Settings::default(),
+            flusher: Flusher::default(),
+        }
+    }
+
+    pub fn new_boxed(sink: &BaseSink) -> Box<SinkImpl> {
+        Box::new(HttpSink::new(sink))
+    }
+
+    fn create_request(&self, sink: &BaseSink, uri: Url) -> RequestBuilder {
+        let method = if self.settings.method == "POST" {
+            Method::Post
+        } else {
+            Method::Put
+        };
+
+        let mut req = self.settings.client.create_request(
+            self.cat,
+            sink.upcast_ref(),
+            &self.client,
+            method,
+            uri,
+        );
+
+        if let Some(ref content_type) = self.settings.content_type {
+            let mut headers = Headers::new();
+            headers.set_raw("Content-Type", content_type.clone());
+            req.headers(headers);
+        }
+
+        req
+    }
+
+    fn take_upload(&mut self) -> Option<(Url, Upload)> {
+        match self.streaming_state {
+            StreamingState::Started {
+                ref uri,
+                ref mut upload,
+                ..
+            } => Some((uri.clone(), mem::replace(upload, Upload::Finished))),
+            StreamingState::Stopped => None,
+        }
+    }
+
+    fn finish_response(
+        &self,
+        sink: &BaseSink,
+        uri: &Url,
+        response: Result<ResponseHead, reqwest::Error>,
+    ) -> Result<(), gst::ErrorMessage> {
+        let head = try!(response.or_else(|err| {
+            gst_error!(self.cat, obj: sink, "Upload failed: {:?}", err);
+            if client::is_certificate_error(&err) {
+                Err(gst_error_msg!(
+                    gst::ResourceError::OpenWrite,
+                    [
+                        "Server certificate validation failed for {}: {}",
+                        uri,
+                        err.to_string()
+                    ]
+                ))
+            } else {
+                Err(gst_error_msg!(
+                    gst::ResourceError::Write,
+                    ["Failed to upload to {}: {}", uri, err.to_string()]
+                ))
+            }
+        }));
+
+        gst_debug!(self.cat, obj: sink, "Got response {:?}", head);
+
+        let mut response_headers = gst::Structure::new_empty("response-headers");
+        for header in head.headers.iter() {
+            response_headers.set(header.name(), &header.value_string());
+        }
+
+        let mut s = gst::Structure::new(
+            "http-response",
+            &[
+                ("uri", &uri.as_str()),
+                ("http-status-code", &(head.status.as_u16() as u32)),
+            ],
+        );
+        s.set("response-headers", &response_headers);
+
+        let _ = sink.post_message(&gst::Message::new_element(s).src(Some(sink)).build());
+
+        if !head.status.is_success() {
+            return Err(gst_error_msg!(
+                gst::ResourceError::Write,
+                ["Failed to upload to {}: {}", uri, head.status]
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl SinkImpl for HttpSink {
+    fn uri_validator(&self) -> Box<UriValidator> {
+        Box::new(validate_uri)
+    }
+
+    fn unlocker(&self) -> Option<Box<Unlocker>> {
+        Some(Box::new(self.flusher.clone()))
+    }
+
+    fn start(&mut self, sink: &BaseSink, uri: Url) -> Result<(), gst::ErrorMessage> {
+        if let StreamingState::Started { .. } = self.streaming_state {
+            return Err(gst_error_msg!(
+                gst::LibraryError::Failed,
+                ["Sink already started"]
+            ));
+        }
+
+        self.client = try!(self.settings.client.create_client(self.cat, sink.upcast_ref()));
+
+        let upload = if self.settings.chunked {
+            let (sender, receiver) = mpsc::sync_channel(4);
+            let mut req = self.create_request(sink, uri.clone());
+            req.body(Body::new(BodyReader {
+                receiver: receiver,
+                pending: Vec::new(),
+                pending_offset: 0,
+                finished: false,
+            }));
+
+            gst_debug!(self.cat, obj: sink, "Starting chunked upload {:?}", req);
+
+            let thread = thread::spawn(move || {
+                req.send().map(|response| ResponseHead {
+                    status: response.status(),
+                    headers: response.headers().clone(),
+                })
+            });
+
+            Upload::Chunked {
+                sender: sender,
+                thread: thread,
+            }
+        } else {
+            Upload::Single { data: Vec::new() }
+        };
+
+        self.streaming_state = StreamingState::Started {
+            uri: uri,
+            upload: upload,
+            position: 0,
+        };
+
+        Ok(())
+    }
+
+    fn stop(&mut self, _sink: &BaseSink) -> Result<(), gst::ErrorMessage> {
+        // Dropping the sender aborts a running chunked upload
+        self.streaming_state = StreamingState::Stopped;
+
+        Ok(())
+    }
+
+    fn render(&mut self, sink: &BaseSink, buffer: &gst::BufferRef) -> Result<(), FlowError> {
+        let cat = self.cat;
+
+        gst_trace!(cat, obj: sink, "Rendering {:?}", buffer);
+
+        let map = match buffer.map_readable() {
+            None => {
+                return Err(FlowError::Error(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Failed to map buffer"]
+                )));
+            }
+            Some(map) => map,
+        };
+        let data = map.as_slice();
+
+        match self.streaming_state {
+            StreamingState::Started {
+                upload: Upload::Single { data: ref mut pending },
+                ref mut position,
+                ..
+            } => {
+                pending.extend_from_slice(data);
+                *position += data.len() as u64;
+                return Ok(());
+            }
+            StreamingState::Started {
+                upload: Upload::Chunked { ref sender, .. },
+                ref mut position,
+                ..
+            } => match self.flusher.send(sender, Some(data.to_vec())) {
+                Ok(true) => {
+                    *position += data.len() as u64;
+                    return Ok(());
+                }
+                Ok(false) => (),
+                Err(()) => {
+                    gst_debug!(cat, obj: sink, "Unlocked while waiting for the server");
+                    return Err(FlowError::Flushing);
+                }
+            },
+            StreamingState::Started {
+                upload: Upload::Finished,
+                ..
+            } => {
+                return Err(FlowError::Error(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Upload already finished"]
+                )));
+            }
+            StreamingState::Stopped => {
+                return Err(FlowError::Error(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Not started yet"]
+                )));
+            }
+        }
+
+        // The request stopped reading the data, get its result
+        let err = match self.take_upload() {
+            Some((uri, Upload::Chunked { thread, .. })) => match thread.join() {
+                Ok(response) => match self.finish_response(sink, &uri, response) {
+                    Ok(()) => gst_error_msg!(
+                        gst::ResourceError::Write,
+                        ["Server finished upload to {} before the end of the data", uri]
+                    ),
+                    Err(err) => err,
+                },
+                Err(_) => gst_error_msg!(
+                    gst::ResourceError::Write,
+                    ["Failed to upload to {}", uri]
+                ),
+            },
+            _ => unreachable!(),
+        };
+
+        gst_error!(cat, obj: sink, "Failed to write: {:?}", err);
+
+        Err(FlowError::Error(err))
+    }
+
+    fn eos(&mut self, sink: &BaseSink) -> Result<(), gst::ErrorMessage> {
+        let (uri, upload) = match self.take_upload() {
+            Some(upload) => upload,
+            None => {
+                return Err(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Not started yet"]
+                ));
+            }
+        };
+
+        let response = match upload {
+            Upload::Single { data } => {
+                let mut req = self.create_request(sink, uri.clone());
+                req.body(data);
+
+                gst_debug!(self.cat, obj: sink, "Uploading {:?}", req);
+
+                req.send().map(|response| ResponseHead {
+                    status: response.status(),
+                    headers: response.headers().clone(),
+                })
+            }
+            Upload::Chunked { sender, thread } => {
+                if let Err(()) = self.flusher.send(&sender, None) {
+                    gst_debug!(self.cat, obj: sink, "Unlocked while finishing the upload");
+
+                    // Keep the upload so that it is aborted when stopping
+                    if let StreamingState::Started { ref mut upload, .. } = self.streaming_state {
+                        *upload = Upload::Chunked {
+                            sender: sender,
+                            thread: thread,
+                        };
+                    }
+                    return Ok(());
+                }
+
+                // Only takes until the server answers or the timeout
+                gst_debug!(self.cat, obj: sink, "Waiting for upload to finish");
+
+                match thread.join() {
+                    Ok(response) => response,
+                    Err(_) => {
+                        return Err(gst_error_msg!(
+                            gst::ResourceError::Write,
+                            ["Failed to upload to {}", uri]
+                        ));
+                    }
+                }
+            }
+            Upload::Finished => {
+                return Err(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Upload already finished"]
+                ));
+            }
+        };
+
+        self.finish_response(sink, &uri, response)
+    }
+
+    fn get_position(&self, _sink: &BaseSink) -> Option<u64> {
+        match self.streaming_state {
+            StreamingState::Started { position, .. } => Some(position),
+            StreamingState::Stopped => None,
+        }
+    }
+
+    fn set_property(&mut self, sink: &BaseSink, property: &Property, value: &glib::Value) {
+        if self.settings.client.set_property(property, value) {
+            return;
+        }
+
+        match *property {
+            Property::String("method", ..) => {
+                let method = value
+                    .get::<String>()
+                    .map(|method| method.to_uppercase())
+                    .unwrap_or_else(|| DEFAULT_METHOD.into());
+
+                if method == "PUT" || method == "POST" {
+                    self.settings.method = method;
+                } else {
+                    gst_warning!(self.cat, obj: sink, "Unsupported method {}", method);
+                }
+            }
+            Property::Boolean("chunked", ..) => {
+                self.settings.chunked = value.get().unwrap();
+            }
+            Property::String("content-type", ..) => {
+                self.settings.content_type = value.get();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _sink: &BaseSink, property: &Property) -> Result<glib::Value, ()> {
+        if let Some(value) = self.settings.client.get_property(property) {
+            return Ok(value);
+        }
+
+        match *property {
+            Property::String("method", ..) => Ok(self.settings.method.to_value()),
+            Property::Boolean("chunked", ..) => Ok(self.settings.chunked.to_value()),
+            Property::String("content-type", ..) => Ok(self.settings.content_type.to_value()),
+            _ => unimplemented!(),
+        }
+    }
+}
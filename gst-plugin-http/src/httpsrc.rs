@@ -7,40 +7,256 @@
 // except according to those terms.
 
 use reqwest::header::{
-    AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, Range, RangeUnit,
+    AcceptRanges, ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, Headers, Range,
+    RangeUnit,
 };
-use reqwest::{Client, Response};
-use std::io::Read;
-use std::u64;
+use reqwest::{Client, Method};
+use std::cmp;
+use std::io::{self, Read};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::{i32, u32, u64};
 use url::Url;
 
+use cache::{Cache, Download};
+use client::{self, Canceller, ClientSettings, Connection, Message, ResponseHead};
+
 use gst_plugin::error::*;
 
 use gst_plugin_simple::error::*;
 use gst_plugin_simple::source::*;
-use gst_plugin_simple::UriValidator;
+use gst_plugin_simple::{PropertyHandler, Unlocker, UriValidator};
 
+use glib;
 use gst;
+use gst::prelude::*;
+use gst_base::prelude::*;
+
+use gobject_subclass::object::*;
+
+const DEFAULT_USER_AGENT: &'static str = "GStreamer rshttpsrc";
+const DEFAULT_IRADIO_MODE: bool = true;
+const DEFAULT_IS_LIVE: bool = false;
+const DEFAULT_LATENCY: u32 = 0;
+const DEFAULT_DOWNLOAD_CACHE: bool = false;
+const DEFAULT_CACHE_SIZE: u64 = 2 * 1024 * 1024;
+
+pub static PROPERTIES: [Property; 20] = [
+    Property::String(
+        "user-agent",
+        "User-Agent",
+        "Value of the User-Agent HTTP request header field",
+        Some(DEFAULT_USER_AGENT),
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boxed(
+        "extra-headers",
+        "Extra Headers",
+        "Extra headers to append to the HTTP request",
+        gst::Structure::static_type,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boxed(
+        "cookies",
+        "Cookies",
+        "HTTP request cookies",
+        Vec::<String>::static_type,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "user-id",
+        "User ID",
+        "HTTP location URI user id for authentication",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "user-pw",
+        "User Password",
+        "HTTP location URI user password for authentication",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "timeout",
+        "Timeout",
+        "Value in seconds to timeout a blocking I/O",
+        (1, 3600),
+        client::DEFAULT_TIMEOUT,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "compress",
+        "Compress",
+        "Allow compressed content encodings",
+        client::DEFAULT_COMPRESS,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "proxy",
+        "Proxy",
+        "HTTP proxy server URI",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Int(
+        "retries",
+        "Retries",
+        "Maximum number of reconnection attempts after a failed read (-1 = unlimited)",
+        (-1, i32::MAX),
+        client::DEFAULT_RETRIES,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "retry-backoff",
+        "Retry Backoff",
+        "Delay in milliseconds before the first retry, doubled for every further attempt",
+        (0, u32::MAX),
+        client::DEFAULT_RETRY_BACKOFF,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "iradio-mode",
+        "iradio-mode",
+        "Enable internet radio mode (ask server to send shoutcast/icecast metadata)",
+        DEFAULT_IRADIO_MODE,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "is-live",
+        "Is Live",
+        "Act like a live source and timestamp buffers with the running time",
+        DEFAULT_IS_LIVE,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt(
+        "latency",
+        "Latency",
+        "Latency in milliseconds to report in live mode, e.g. for network jitter",
+        (0, u32::MAX),
+        DEFAULT_LATENCY,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "ssl-ca-file",
+        "SSL CA File",
+        "Location of a CA file (PEM bundle or DER) to use in addition to the system ones",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "client-certificate",
+        "Client Certificate",
+        "Location of a PKCS #12 archive with the client certificate and private key",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "client-certificate-password",
+        "Client Certificate Password",
+        "Password of the client certificate archive",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "ssl-strict",
+        "SSL Strict",
+        "Fail for invalid server certificates",
+        client::DEFAULT_SSL_STRICT,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::Boolean(
+        "download-cache",
+        "Download Cache",
+        "Keep downloaded data and serve seeks into it locally",
+        DEFAULT_DOWNLOAD_CACHE,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::UInt64(
+        "cache-size",
+        "Cache Size",
+        "Size of the in-memory cache and amount of data to buffer ahead, in bytes",
+        (4096, u64::MAX),
+        DEFAULT_CACHE_SIZE,
+        PropertyMutability::ReadWrite,
+    ),
+    Property::String(
+        "temp-location",
+        "Temporary Location",
+        "Directory for caching the whole download in a temporary file instead of in memory",
+        None,
+        PropertyMutability::ReadWrite,
+    ),
+];
+
+#[derive(Debug, Clone)]
+struct Settings {
+    client: ClientSettings,
+    iradio_mode: bool,
+    is_live: bool,
+    latency: u32,
+    download_cache: bool,
+    cache_size: u64,
+    temp_location: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            client: ClientSettings::new(DEFAULT_USER_AGENT),
+            iradio_mode: DEFAULT_IRADIO_MODE,
+            is_live: DEFAULT_IS_LIVE,
+            latency: DEFAULT_LATENCY,
+            download_cache: DEFAULT_DOWNLOAD_CACHE,
+            cache_size: DEFAULT_CACHE_SIZE,
+            temp_location: None,
+        }
+    }
+}
 
 #[derive(Debug)]
 enum StreamingState {
     Stopped,
     Started {
         uri: Url,
-        response: Response,
+        body: Body,
         seekable: bool,
         position: u64,
         size: Option<u64>,
         start: u64,
         stop: Option<u64>,
+        // Data restarted from the beginning after a reconnection
+        discont: bool,
+        icy: Option<IcyState>,
+        // Tags to push before the next buffer
+        tags: Option<gst::TagList>,
     },
 }
 
+#[derive(Debug)]
+enum Body {
+    Connection(Connection),
+    // Downloaded into the cache from another thread
+    Cached(Download),
+}
+
+#[derive(Debug)]
+struct IcyState {
+    metaint: u64,
+    // Stream bytes until the next metadata block
+    remaining: u64,
+}
+
 #[derive(Debug)]
 pub struct HttpSrc {
     streaming_state: StreamingState,
     cat: gst::DebugCategory,
     client: Client,
+    // Snapshot of the shared settings when starting
+    settings: Settings,
+    shared_settings: SharedSettings,
+    canceller: Canceller,
 }
 
 impl HttpSrc {
@@ -53,6 +269,9 @@ impl HttpSrc {
                 "Rust HTTP source",
             ),
             client: Client::new(),
+            settings: Settings::default(),
+            shared_settings: SharedSettings::default(),
+            canceller: Canceller::default(),
         }
     }
 
@@ -66,9 +285,22 @@ impl HttpSrc {
         uri: Url,
         start: u64,
         stop: Option<u64>,
+        cache: Option<Arc<Cache>>,
     ) -> Result<StreamingState, gst::ErrorMessage> {
         let cat = self.cat;
-        let mut req = self.client.get(uri.clone());
+        let mut req = self.settings.client.create_request(
+            cat,
+            src.upcast_ref(),
+            &self.client,
+            Method::Get,
+            uri.clone(),
+        );
+
+        if self.settings.iradio_mode {
+            let mut headers = Headers::new();
+            headers.set_raw("Icy-MetaData", "1");
+            req.headers(headers);
+        }
 
         match (start != 0, stop) {
             (false, None) => (),
@@ -82,39 +314,75 @@ impl HttpSrc {
 
         gst_debug!(cat, obj: src, "Doing new request {:?}", req);
 
-        let response = try!(req.send().or_else(|err| {
-            gst_error!(cat, obj: src, "Request failed: {:?}", err);
-            Err(gst_error_msg!(
-                gst::ResourceError::Read,
-                ["Failed to fetch {}: {}", uri, err.to_string()]
-            ))
-        }));
+        let (head, response) = match self.canceller.send(req) {
+            Some(Ok(response)) => response,
+            Some(Err(err)) => {
+                gst_error!(cat, obj: src, "Request failed: {:?}", err);
+                return Err(client::request_error(&uri, &err));
+            }
+            None => {
+                return Err(gst_error_msg!(
+                    gst::ResourceError::Read,
+                    ["Flushing while fetching {}", uri]
+                ));
+            }
+        };
 
-        if !response.status().is_success() {
-            gst_error!(cat, obj: src, "Request status failed: {:?}", response);
-            return Err(gst_error_msg!(
-                gst::ResourceError::Read,
-                ["Failed to fetch {}: {}", uri, response.status()]
-            ));
+        // Seeks and reconnections go directly to the final location
+        let final_uri = head.url.clone();
+        if final_uri != uri {
+            gst_debug!(cat, obj: src, "Redirected from {} to {}", uri, final_uri);
+            let _ = src.post_message(&gst::Message::new_redirect(final_uri.as_str(), None, None)
+                .src(Some(src))
+                .build());
         }
 
-        let size = response
-            .headers()
-            .get()
-            .map(|&ContentLength(cl)| cl + start);
+        post_headers_message(src, &uri, &final_uri, &head);
 
-        let accept_byte_ranges = if let Some(&AcceptRanges(ref ranges)) = response.headers().get() {
+        if !head.status.is_success() {
+            gst_error!(cat, obj: src, "Request status failed: {:?}", head);
+            return Err(client::status_error(&uri, head.status));
+        }
+
+        // Live streams are unbounded, usually sent with chunked transfer encoding
+        let size = if self.settings.is_live {
+            None
+        } else {
+            head.headers.get().map(|&ContentLength(cl)| cl + start)
+        };
+
+        let accept_byte_ranges = if let Some(&AcceptRanges(ref ranges)) = head.headers.get() {
             ranges.iter().any(|u| *u == RangeUnit::Bytes)
         } else {
             false
         };
 
-        let seekable = size.is_some() && accept_byte_ranges;
+        let icy_metaint = if self.settings.iradio_mode {
+            head.headers
+                .get_raw("icy-metaint")
+                .and_then(|raw| raw.one())
+                .and_then(|v| str::from_utf8(v).ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .and_then(|metaint| if metaint > 0 { Some(metaint) } else { None })
+        } else {
+            None
+        };
+
+        // The metadata blocks are stripped here and turned into tags, so the output is the
+        // plain audio stream. application/x-icy caps would make downstream plug icydemux,
+        // which expects the blocks to still be interleaved with the data.
+        if let Some(metaint) = icy_metaint {
+            gst_debug!(cat, obj: src, "Got ICY metadata interval {}", metaint);
+        }
+
+        // Offsets in ICY streams don't correspond to the offsets on the server
+        let seekable = size.is_some() && accept_byte_ranges && icy_metaint.is_none()
+            && !self.settings.is_live;
 
         let position = if let Some(&ContentRange(ContentRangeSpec::Bytes {
             range: Some((range_start, _)),
             ..
-        })) = response.headers().get()
+        })) = head.headers.get()
         {
             range_start
         } else {
@@ -128,21 +396,292 @@ impl HttpSrc {
             ));
         }
 
-        gst_debug!(cat, obj: src, "Request successful: {:?}", response);
+        gst_debug!(cat, obj: src, "Request successful: {:?}", head);
+
+        let tags = if self.settings.iradio_mode {
+            icy_header_tags(&head.headers)
+        } else {
+            None
+        };
+
+        // Live and ICY streams can't be seeked, so caching them is pointless
+        let body = if self.settings.download_cache && !self.settings.is_live
+            && icy_metaint.is_none()
+        {
+            let cache = match cache {
+                Some(cache) => cache,
+                None => Arc::new(try!(self.create_cache(src, start))),
+            };
+            Body::Cached(self.start_download(src, response, cache))
+        } else {
+            Body::Connection(response)
+        };
 
         Ok(StreamingState::Started {
-            uri: uri,
-            response: response,
+            uri: final_uri,
+            body: body,
             seekable: seekable,
-            position: 0,
+            position: position,
             size: size,
             start: start,
             stop: stop,
+            discont: false,
+            icy: icy_metaint.map(|metaint| IcyState {
+                metaint: metaint,
+                remaining: metaint,
+            }),
+            tags: tags,
         })
     }
+
+    fn create_cache(&self, src: &BaseSrc, position: u64) -> Result<Cache, gst::ErrorMessage> {
+        let cache_size = self.settings.cache_size;
+
+        let temp_location = match self.settings.temp_location {
+            None => {
+                gst_debug!(self.cat, obj: src, "Caching {} bytes in memory", cache_size);
+                return Ok(Cache::new_memory(position, cache_size));
+            }
+            Some(ref temp_location) => temp_location,
+        };
+
+        gst_debug!(self.cat, obj: src, "Caching in a file in {}", temp_location);
+        Cache::new_file(temp_location, position, cache_size).map_err(|err| {
+            gst_error_msg!(
+                gst::ResourceError::OpenWrite,
+                [
+                    "Failed to create cache file in {}: {}",
+                    temp_location,
+                    err.to_string()
+                ]
+            )
+        })
+    }
+
+    fn start_download(&self, src: &BaseSrc, response: Connection, cache: Arc<Cache>) -> Download {
+        self.canceller.set_cache(cache.clone());
+
+        let download = Download::new(cache.clone());
+        let id = download.id();
+        let element = src.clone().upcast::<gst::Element>();
+        thread::spawn(move || run_download(response, cache, id, element));
+
+        download
+    }
+
+    // Resumes at the current position if the server supports byte ranges, otherwise
+    // starts again from the beginning and marks the next buffer as discont
+    fn reconnect(&mut self, src: &BaseSrc) -> Result<(), gst::ErrorMessage> {
+        let (uri, seekable, position, stop, cache) = match self.streaming_state {
+            StreamingState::Started {
+                ref uri,
+                seekable,
+                position,
+                stop,
+                ref body,
+                ..
+            } => {
+                let cache = match *body {
+                    Body::Cached(ref download) => Some(download.cache.clone()),
+                    Body::Connection(..) => None,
+                };
+                (uri.clone(), seekable, position, stop, cache)
+            }
+            StreamingState::Stopped => {
+                return Err(gst_error_msg!(
+                    gst::LibraryError::Failed,
+                    ["Not started yet"]
+                ));
+            }
+        };
+
+        if seekable {
+            // The download continues after the cached data
+            let resume = cache.as_ref().map(|cache| cache.end()).unwrap_or(position);
+            gst_debug!(self.cat, obj: src, "Resuming at {}", resume);
+            let mut state = try!(self.do_request(src, uri, resume, stop, cache));
+
+            if let StreamingState::Started {
+                position: ref mut new_position,
+                ..
+            } = state
+            {
+                *new_position = position;
+            }
+            self.streaming_state = state;
+        } else {
+            gst_debug!(self.cat, obj: src, "Restarting from the beginning");
+            let mut state = try!(self.do_request(src, uri, 0, None, None));
+
+            // Offsets continue from where we were as far as the base class is concerned
+            if let StreamingState::Started {
+                position: ref mut new_position,
+                ref mut discont,
+                ..
+            } = state
+            {
+                *new_position = position;
+                *discont = true;
+            }
+            self.streaming_state = state;
+        }
+
+        Ok(())
+    }
+}
+
+// Runs in its own thread and moves the response data into the cache until the download
+// is finished or stopped
+fn run_download(connection: Connection, cache: Arc<Cache>, id: u64, element: gst::Element) {
+    loop {
+        let res = match connection.receiver.recv() {
+            Ok(Message::Data(res)) => res,
+            Ok(Message::Unlock) => continue,
+            Ok(msg) => unreachable!("Unexpected message {:?}", msg),
+            Err(_) => Ok(Vec::new()),
+        };
+
+        let (percent, done) = match res {
+            Ok(ref data) if data.is_empty() => (cache.finish(id, None), true),
+            Ok(ref data) => match cache.write(id, data) {
+                Ok(percent) => (percent, false),
+                Err(()) => break,
+            },
+            Err(err) => (cache.finish(id, Some(err.to_string())), true),
+        };
+
+        if let Some(percent) = percent {
+            post_buffering_message(&element, percent);
+        }
+
+        if done {
+            break;
+        }
+    }
+}
+
+fn post_buffering_message<T: IsA<gst::Element> + IsA<gst::Object>>(element: &T, percent: i32) {
+    let _ = element.post_message(&gst::Message::new_buffering(percent)
+        .src(Some(element))
+        .build());
+}
+
+fn post_headers_message(src: &BaseSrc, uri: &Url, final_uri: &Url, head: &ResponseHead) {
+    let mut response_headers = gst::Structure::new_empty("response-headers");
+    for header in head.headers.iter() {
+        response_headers.set(header.name(), &header.value_string());
+    }
+
+    let mut s = gst::Structure::new(
+        "http-headers",
+        &[
+            ("uri", &uri.as_str()),
+            ("http-status-code", &(head.status.as_u16() as u32)),
+        ],
+    );
+    s.set("response-headers", &response_headers);
+    if final_uri != uri {
+        s.set("redirection-uri", &final_uri.as_str());
+    }
+
+    let _ = src.post_message(&gst::Message::new_element(s).src(Some(src)).build());
 }
 
-fn validate_uri(uri: &Url) -> Result<(), UriError> {
+// Reads stream data and strips the ICY metadata blocks that are interleaved with it
+fn read_stream<R: Read>(
+    response: &mut R,
+    icy: &mut Option<IcyState>,
+    tags: &mut Option<gst::TagList>,
+    data: &mut [u8],
+) -> io::Result<usize> {
+    let icy = match *icy {
+        None => return response.read(data),
+        Some(ref mut icy) => icy,
+    };
+
+    if icy.remaining == 0 {
+        let mut len = [0u8; 1];
+        if try!(response.read(&mut len)) == 0 {
+            return Ok(0);
+        }
+
+        let mut metadata = vec![0; len[0] as usize * 16];
+        try!(response.read_exact(&mut metadata));
+        icy.remaining = icy.metaint;
+
+        if !metadata.is_empty() {
+            let tags = tags.get_or_insert_with(gst::TagList::new);
+            add_icy_metadata_tags(tags, &metadata);
+        }
+    }
+
+    let len = cmp::min(data.len() as u64, icy.remaining) as usize;
+    let size = try!(response.read(&mut data[..len]));
+    icy.remaining -= size as u64;
+
+    Ok(size)
+}
+
+// Metadata blocks look like "StreamTitle='Artist - Title';StreamUrl='http://...';"
+// and are padded with zeroes
+fn add_icy_metadata_tags(tags: &mut gst::TagList, metadata: &[u8]) {
+    let metadata = String::from_utf8_lossy(metadata);
+    let tags = tags.make_mut();
+
+    for entry in metadata.trim_right_matches('\0').split("';") {
+        let mut parts = entry.splitn(2, "='");
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim_right_matches('\'')),
+            _ => continue,
+        };
+
+        match key {
+            "StreamTitle" if !value.is_empty() => {
+                tags.add::<gst::tags::Title>(&value, gst::TagMergeMode::Replace);
+            }
+            "StreamUrl" if !value.is_empty() => {
+                tags.add::<gst::tags::Homepage>(&value, gst::TagMergeMode::Replace);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn icy_header_tags(headers: &Headers) -> Option<gst::TagList> {
+    let header = |name: &str| {
+        headers
+            .get_raw(name)
+            .and_then(|raw| raw.one())
+            .map(|v| String::from_utf8_lossy(v).trim().to_string())
+            .and_then(|v| if v.is_empty() { None } else { Some(v) })
+    };
+
+    let name = header("icy-name");
+    let genre = header("icy-genre");
+    let url = header("icy-url");
+
+    if name.is_none() && genre.is_none() && url.is_none() {
+        return None;
+    }
+
+    let mut tags = gst::TagList::new();
+    {
+        let tags = tags.get_mut().unwrap();
+        if let Some(ref name) = name {
+            tags.add::<gst::tags::Organization>(&name.as_str(), gst::TagMergeMode::Replace);
+        }
+        if let Some(ref genre) = genre {
+            tags.add::<gst::tags::Genre>(&genre.as_str(), gst::TagMergeMode::Replace);
+        }
+        if let Some(ref url) = url {
+            tags.add::<gst::tags::Homepage>(&url.as_str(), gst::TagMergeMode::Replace);
+        }
+    }
+
+    Some(tags)
+}
+
+pub fn validate_uri(uri: &Url) -> Result<(), UriError> {
     if uri.scheme() != "http" && uri.scheme() != "https" {
         return Err(UriError::new(
             gst::URIError::UnsupportedProtocol,
@@ -158,6 +697,14 @@ impl SourceImpl for HttpSrc {
         Box::new(validate_uri)
     }
 
+    fn unlocker(&self) -> Option<Box<Unlocker>> {
+        Some(Box::new(self.canceller.clone()))
+    }
+
+    fn property_handler(&self) -> Option<Box<PropertyHandler<BaseSrc>>> {
+        Some(Box::new(self.shared_settings.clone()))
+    }
+
     fn is_seekable(&self, _src: &BaseSrc) -> bool {
         match self.streaming_state {
             StreamingState::Started { seekable, .. } => seekable,
@@ -174,7 +721,9 @@ impl SourceImpl for HttpSrc {
 
     fn start(&mut self, src: &BaseSrc, uri: Url) -> Result<(), gst::ErrorMessage> {
         self.streaming_state = StreamingState::Stopped;
-        self.streaming_state = try!(self.do_request(src, uri, 0, None));
+        self.settings = self.shared_settings.0.lock().unwrap().clone();
+        self.client = try!(self.settings.client.create_client(self.cat, src.upcast_ref()));
+        self.streaming_state = try!(self.do_request(src, uri, 0, None, None));
 
         Ok(())
     }
@@ -210,8 +759,52 @@ impl SourceImpl for HttpSrc {
             return Ok(());
         }
 
+        let mut cache = None;
+        if let StreamingState::Started {
+            body: Body::Cached(ref download),
+            position: ref mut cur_position,
+            stop: ref mut cur_stop,
+            ..
+        } = self.streaming_state
+        {
+            if download.cache.seek(start) {
+                gst_debug!(self.cat, obj: src, "Seeking to {} in the cache", start);
+                *cur_position = start;
+                *cur_stop = stop;
+                return Ok(());
+            }
+
+            // Data cached in a file for other ranges is kept and the download only continues
+            // after the data cached at the new position. A memory cache starts again empty
+            match download.cache.restart(start) {
+                Some(resume) if stop.map_or(true, |stop| resume < stop) => {
+                    cache = Some((download.cache.clone(), resume));
+                }
+                _ => {
+                    gst_debug!(self.cat, obj: src, "Seeking to {} in cached range", start);
+                    *cur_position = start;
+                    *cur_stop = stop;
+                    return Ok(());
+                }
+            }
+        }
+
         self.streaming_state = StreamingState::Stopped;
-        self.streaming_state = try!(self.do_request(src, uri, start, stop));
+        self.streaming_state = match cache {
+            Some((cache, resume)) => {
+                gst_debug!(self.cat, obj: src, "Seeking to {}, downloading from {}", start, resume);
+                let mut state = try!(self.do_request(src, uri, resume, stop, Some(cache)));
+                if let StreamingState::Started {
+                    ref mut position,
+                    ..
+                } = state
+                {
+                    *position = start;
+                }
+                state
+            }
+            None => try!(self.do_request(src, uri, start, stop, None)),
+        };
 
         Ok(())
     }
@@ -225,28 +818,73 @@ impl SourceImpl for HttpSrc {
     ) -> Result<(), FlowError> {
         let cat = self.cat;
 
-        let (response, position) = match self.streaming_state {
-            StreamingState::Started {
-                ref mut response,
-                ref mut position,
-                ..
-            } => (response, position),
+        match self.streaming_state {
+            StreamingState::Started { position, .. } if position != offset => {
+                return Err(FlowError::Error(gst_error_msg!(
+                    gst::ResourceError::Seek,
+                    ["Got unexpected offset {}, expected {}", offset, position]
+                )));
+            }
+            StreamingState::Started { .. } => (),
             StreamingState::Stopped => {
                 return Err(FlowError::Error(gst_error_msg!(
                     gst::LibraryError::Failed,
                     ["Not started yet"]
                 )));
             }
-        };
-
-        if *position != offset {
-            return Err(FlowError::Error(gst_error_msg!(
-                gst::ResourceError::Seek,
-                ["Got unexpected offset {}, expected {}", offset, position]
-            )));
         }
 
-        let size = {
+        let mut attempt = 0;
+        let mut last_error: Option<String> = None;
+        let size = loop {
+            if let Some(err) = last_error.take() {
+                if self.canceller.is_flushing() {
+                    gst_debug!(cat, obj: src, "Flushing");
+                    return Err(FlowError::Flushing);
+                }
+
+                if !self.settings.client.may_retry(attempt) {
+                    gst_error!(cat, obj: src, "Giving up after {} retries", attempt);
+                    return Err(FlowError::Error(gst_error_msg!(
+                        gst::ResourceError::Read,
+                        ["Failed to read at {}: {}", offset, err]
+                    )));
+                }
+
+                let delay = self.settings.client.retry_delay(attempt);
+                attempt += 1;
+
+                gst_warning!(
+                    cat,
+                    obj: src,
+                    "Failed to read at {}: {}, retrying in {:?} (attempt {})",
+                    offset,
+                    err,
+                    delay,
+                    attempt
+                );
+                if !self.canceller.wait(delay) {
+                    gst_debug!(cat, obj: src, "Flushing");
+                    return Err(FlowError::Flushing);
+                }
+
+                if let Err(msg) = self.reconnect(src) {
+                    gst_warning!(cat, obj: src, "Failed to reconnect: {:?}", msg);
+                    last_error = Some(format!("{:?}", msg));
+                    continue;
+                }
+            }
+
+            let (body, icy, tags) = match self.streaming_state {
+                StreamingState::Started {
+                    ref mut body,
+                    ref mut icy,
+                    ref mut tags,
+                    ..
+                } => (body, icy, tags),
+                StreamingState::Stopped => unreachable!(),
+            };
+
             let mut map = match buffer.map_writable() {
                 None => {
                     return Err(FlowError::Error(gst_error_msg!(
@@ -257,25 +895,138 @@ impl SourceImpl for HttpSrc {
                 Some(map) => map,
             };
 
-            let data = map.as_mut_slice();
+            let res = match *body {
+                Body::Connection(ref mut response) => {
+                    read_stream(response, icy, tags, map.as_mut_slice())
+                }
+                Body::Cached(ref download) => {
+                    let canceller = &self.canceller;
+                    download
+                        .cache
+                        .read(&|| canceller.is_flushing(), offset, map.as_mut_slice())
+                        .map(|(size, percent)| {
+                            if let Some(percent) = percent {
+                                post_buffering_message(src, percent);
+                            }
+                            size
+                        })
+                }
+            };
 
-            try!(response.read(data).or_else(|err| {
-                gst_error!(cat, obj: src, "Failed to read: {:?}", err);
-                Err(FlowError::Error(gst_error_msg!(
-                    gst::ResourceError::Read,
-                    ["Failed to read at {}: {}", offset, err.to_string()]
-                )))
-            }))
+            match res {
+                Ok(size) => break size,
+                Err(err) => {
+                    gst_debug!(cat, obj: src, "Failed to read: {:?}", err);
+                    last_error = Some(err.to_string());
+                }
+            }
         };
 
         if size == 0 {
             return Err(FlowError::Eos);
         }
 
-        *position += size as u64;
+        if let StreamingState::Started {
+            ref mut position,
+            ref mut discont,
+            ref mut tags,
+            ..
+        } = self.streaming_state
+        {
+            *position += size as u64;
+
+            if let Some(tags) = tags.take() {
+                gst_debug!(cat, obj: src, "Pushing tags {:?}", tags);
+                let pad = src.get_static_pad("src").unwrap();
+                pad.push_event(gst::Event::new_tag(tags).build());
+            }
+
+            if *discont {
+                buffer.set_flags(gst::BufferFlags::DISCONT);
+                *discont = false;
+            }
+        }
 
         buffer.set_size(size);
 
         Ok(())
     }
 }
+
+// Properties are kept outside the source so that they can be accessed while it is blocked
+// reading, they are taken over when starting
+#[derive(Debug, Default, Clone)]
+struct SharedSettings(Arc<Mutex<Settings>>);
+
+impl PropertyHandler<BaseSrc> for SharedSettings {
+    fn set_property(&self, src: &BaseSrc, property: &Property, value: &glib::Value) {
+        let mut settings = self.0.lock().unwrap();
+
+        if settings.client.set_property(property, value) {
+            return;
+        }
+
+        match *property {
+            Property::Boolean("iradio-mode", ..) => {
+                settings.iradio_mode = value.get().unwrap();
+            }
+            Property::Boolean("is-live", ..) => {
+                let is_live = value.get().unwrap();
+                settings.is_live = is_live;
+                src.set_live(is_live);
+                src.set_do_timestamp(is_live);
+            }
+            Property::UInt("latency", ..) => {
+                settings.latency = value.get().unwrap();
+            }
+            Property::Boolean("download-cache", ..) => {
+                settings.download_cache = value.get().unwrap();
+            }
+            Property::UInt64("cache-size", ..) => {
+                settings.cache_size = value.get().unwrap();
+            }
+            Property::String("temp-location", ..) => {
+                settings.temp_location = value.get();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn get_property(&self, _src: &BaseSrc, property: &Property) -> Result<glib::Value, ()> {
+        let settings = self.0.lock().unwrap();
+
+        if let Some(value) = settings.client.get_property(property) {
+            return Ok(value);
+        }
+
+        match *property {
+            Property::Boolean("iradio-mode", ..) => Ok(settings.iradio_mode.to_value()),
+            Property::Boolean("is-live", ..) => Ok(settings.is_live.to_value()),
+            Property::UInt("latency", ..) => Ok(settings.latency.to_value()),
+            Property::Boolean("download-cache", ..) => Ok(settings.download_cache.to_value()),
+            Property::UInt64("cache-size", ..) => Ok(settings.cache_size.to_value()),
+            Property::String("temp-location", ..) => Ok(settings.temp_location.to_value()),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn query(&self, _src: &BaseSrc, query: &mut gst::QueryRef) -> bool {
+        use gst::QueryView;
+
+        match query.view_mut() {
+            // Buffers are timestamped when they arrive, so only the configured latency for
+            // jitter is added. The network can buffer any amount of data
+            QueryView::Latency(ref mut q) => {
+                let settings = self.0.lock().unwrap();
+                if !settings.is_live {
+                    return false;
+                }
+
+                let latency = gst::ClockTime::from_mseconds(settings.latency as u64);
+                q.set(true, latency, gst::CLOCK_TIME_NONE);
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -8,18 +8,35 @@
 
 #![crate_type = "cdylib"]
 
+extern crate glib;
+extern crate gobject_subclass;
 #[macro_use]
 extern crate gst_plugin;
 extern crate gst_plugin_simple;
 #[macro_use]
 extern crate gstreamer as gst;
+extern crate gstreamer_base as gst_base;
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+extern crate native_tls;
+#[cfg(any(
+    feature = "openssl",
+    not(any(target_os = "macos", target_os = "ios", target_os = "windows"))
+))]
+extern crate openssl;
 extern crate reqwest;
 extern crate url;
 
+use gst_plugin_simple::sink::*;
 use gst_plugin_simple::source::*;
 
+mod cache;
+mod client;
+mod hlsdemux;
+mod httpsink;
 mod httpsrc;
+mod m3u8;
 
+use httpsink::HttpSink;
 use httpsrc::HttpSrc;
 
 fn plugin_init(plugin: &gst::Plugin) -> bool {
@@ -35,9 +52,27 @@ fn plugin_init(plugin: &gst::Plugin) -> bool {
             create_instance: HttpSrc::new_boxed,
             protocols: vec!["http".into(), "https".into()],
             push_only: true,
+            properties: &httpsrc::PROPERTIES,
         },
     );
 
+    sink_register(
+        plugin,
+        SinkInfo {
+            name: "rshttpsink".into(),
+            long_name: "HTTP/HTTPS Sink".into(),
+            description: "Uploads streams via HTTP/HTTPS".into(),
+            classification: "Sink/Network".into(),
+            author: "Sebastian Dröge <sebastian@centricular.com>".into(),
+            rank: 256 + 100,
+            create_instance: HttpSink::new_boxed,
+            protocols: vec!["http".into(), "https".into()],
+            properties: &httpsink::PROPERTIES,
+        },
+    );
+
+    hlsdemux::register(plugin);
+
     true
 }
 
@@ -0,0 +1,515 @@
+// Copyright (C) 2016-2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::str;
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub enum Playlist {
+    Master(MasterPlaylist),
+    Media(MediaPlaylist),
+}
+
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub uri: Url,
+    // In bits per second
+    pub bandwidth: u64,
+    pub codecs: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MasterPlaylist {
+    pub variants: Vec<Variant>,
+}
+
+// Only AES-128 encryption is supported, METHOD=NONE gives no key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    pub uri: Url,
+    pub iv: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub uri: Url,
+    // In seconds
+    pub duration: f64,
+    pub sequence: u64,
+    pub discontinuity: bool,
+    pub key: Option<Key>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaPlaylist {
+    // In seconds
+    pub target_duration: f64,
+    pub media_sequence: u64,
+    pub segments: Vec<Segment>,
+    // No more segments will be added, otherwise the playlist has to be refreshed
+    pub endlist: bool,
+}
+
+// Parses attribute lists like 'BANDWIDTH=1280000,CODECS="avc1.4d401f,mp4a.40.2"'
+fn parse_attributes(s: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    while chars.peek().is_some() {
+        // Attributes without value end at the separator and get an empty value
+        let mut name = String::new();
+        let mut has_value = false;
+        for c in chars.by_ref() {
+            match c {
+                '=' => {
+                    has_value = true;
+                    break;
+                }
+                ',' => break,
+                c => name.push(c),
+            }
+        }
+
+        let mut value = String::new();
+        if has_value {
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                value.extend(chars.by_ref().take_while(|c| *c != '"'));
+                // Skip the separator after the closing quote
+                chars.next();
+            } else {
+                value.extend(chars.by_ref().take_while(|c| *c != ','));
+            }
+        }
+
+        let name = name.trim();
+        if !name.is_empty() {
+            attributes.push((name.to_string(), value));
+        }
+    }
+
+    attributes
+}
+
+fn get_attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|&&(ref n, _)| n == name)
+        .map(|&(_, ref v)| v.as_str())
+}
+
+fn parse_iv(s: &str) -> Result<[u8; 16], String> {
+    let hex = if s.starts_with("0x") || s.starts_with("0X") {
+        &s[2..]
+    } else {
+        return Err(format!("Invalid IV {}", s));
+    };
+
+    // Also makes sure that the slicing below only happens at character boundaries
+    if hex.len() != 32 || !hex.chars().all(|c| c.is_digit(16)) {
+        return Err(format!("Invalid IV {}", s));
+    }
+
+    let mut iv = [0u8; 16];
+    for (i, byte) in iv.iter_mut().enumerate() {
+        *byte = try!(
+            u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| format!("Invalid IV {}", s))
+        );
+    }
+
+    Ok(iv)
+}
+
+fn parse_key(attributes: &[(String, String)], base: &Url) -> Result<Option<Key>, String> {
+    match get_attribute(attributes, "METHOD") {
+        Some("NONE") => Ok(None),
+        Some("AES-128") => {
+            let uri = try!(get_attribute(attributes, "URI").ok_or("Key without URI"));
+            let uri = try!(base.join(uri).map_err(|err| format!("Invalid key URI: {}", err)));
+            let iv = match get_attribute(attributes, "IV") {
+                Some(iv) => Some(try!(parse_iv(iv))),
+                None => None,
+            };
+
+            Ok(Some(Key { uri: uri, iv: iv }))
+        }
+        method => Err(format!("Unsupported encryption method {:?}", method)),
+    }
+}
+
+pub fn parse(data: &[u8], base: &Url) -> Result<Playlist, String> {
+    let data = try!(str::from_utf8(data).map_err(|_| "Playlist is not valid UTF-8"));
+    let mut lines = data.trim_left_matches('\u{feff}')
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty());
+
+    if lines.next() != Some("#EXTM3U") {
+        return Err("Not a M3U8 playlist".into());
+    }
+
+    let mut variants = Vec::new();
+    let mut segments = Vec::new();
+    let mut target_duration = 0.0;
+    let mut media_sequence = 0;
+    let mut endlist = false;
+
+    // Tags that apply to the next URI line
+    let mut stream_inf = None;
+    let mut duration = None;
+    let mut discontinuity = false;
+    let mut key = None;
+
+    for line in lines {
+        if line.starts_with("#EXT-X-STREAM-INF:") {
+            stream_inf = Some(parse_attributes(&line["#EXT-X-STREAM-INF:".len()..]));
+        } else if line.starts_with("#EXTINF:") {
+            let value = &line["#EXTINF:".len()..];
+            let value = value.split(',').next().unwrap_or("");
+            duration = Some(try!(
+                value
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid segment duration {}", value))
+            ));
+        } else if line.starts_with("#EXT-X-TARGETDURATION:") {
+            let value = &line["#EXT-X-TARGETDURATION:".len()..];
+            target_duration = try!(
+                value
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid target duration {}", value))
+            );
+        } else if line.starts_with("#EXT-X-MEDIA-SEQUENCE:") {
+            let value = &line["#EXT-X-MEDIA-SEQUENCE:".len()..];
+            media_sequence = try!(
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid media sequence {}", value))
+            );
+        } else if line.starts_with("#EXT-X-KEY:") {
+            let attributes = parse_attributes(&line["#EXT-X-KEY:".len()..]);
+            key = try!(parse_key(&attributes, base));
+        } else if line == "#EXT-X-DISCONTINUITY" {
+            discontinuity = true;
+        } else if line == "#EXT-X-ENDLIST" {
+            endlist = true;
+        } else if line.starts_with('#') {
+            // Comment or unsupported tag
+            continue;
+        } else {
+            let uri = try!(
+                base.join(line)
+                    .map_err(|err| format!("Invalid URI {}: {}", line, err))
+            );
+
+            if let Some(attributes) = stream_inf.take() {
+                let bandwidth = get_attribute(&attributes, "BANDWIDTH")
+                    .and_then(|b| b.parse::<u64>().ok())
+                    .unwrap_or(0);
+
+                variants.push(Variant {
+                    uri: uri,
+                    bandwidth: bandwidth,
+                    codecs: get_attribute(&attributes, "CODECS").map(String::from),
+                });
+            } else {
+                let sequence = media_sequence + segments.len() as u64;
+                segments.push(Segment {
+                    uri: uri,
+                    duration: duration.take().unwrap_or(0.0),
+                    sequence: sequence,
+                    discontinuity: discontinuity,
+                    key: key.clone(),
+                });
+                discontinuity = false;
+            }
+        }
+    }
+
+    if !variants.is_empty() {
+        Ok(Playlist::Master(MasterPlaylist { variants: variants }))
+    } else {
+        Ok(Playlist::Media(MediaPlaylist {
+            target_duration: target_duration,
+            media_sequence: media_sequence,
+            segments: segments,
+            endlist: endlist,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("http://example.com/live/playlist.m3u8").unwrap()
+    }
+
+    fn parse_media(data: &str) -> MediaPlaylist {
+        match parse(data.as_bytes(), &base()) {
+            Ok(Playlist::Media(playlist)) => playlist,
+            res => panic!("Expected media playlist, got {:?}", res),
+        }
+    }
+
+    fn attributes(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|&(n, v)| (String::from(n), String::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_attributes() {
+        let list = "BANDWIDTH=1280000,CODECS=\"avc1.4d401f,mp4a.40.2\",RESOLUTION=640x360";
+        assert_eq!(
+            parse_attributes(list),
+            attributes(&[
+                ("BANDWIDTH", "1280000"),
+                ("CODECS", "avc1.4d401f,mp4a.40.2"),
+                ("RESOLUTION", "640x360"),
+            ])
+        );
+        assert_eq!(
+            parse_attributes("METHOD=AES-128,URI=\"https://example.com/key?a=1,b=2\""),
+            attributes(&[
+                ("METHOD", "AES-128"),
+                ("URI", "https://example.com/key?a=1,b=2"),
+            ])
+        );
+        assert_eq!(parse_attributes(""), attributes(&[]));
+    }
+
+    #[test]
+    fn test_parse_attributes_missing_values() {
+        assert_eq!(
+            parse_attributes("A=,B=\"\",C=3"),
+            attributes(&[("A", ""), ("B", ""), ("C", "3")])
+        );
+        assert_eq!(
+            parse_attributes("FLAG,B=2,LAST"),
+            attributes(&[("FLAG", ""), ("B", "2"), ("LAST", "")])
+        );
+        assert_eq!(
+            parse_attributes("A=1,,B=2,"),
+            attributes(&[("A", "1"), ("B", "2")])
+        );
+        // Unterminated quotes take the remainder
+        assert_eq!(
+            parse_attributes("A=\"1,B=2"),
+            attributes(&[("A", "1,B=2")])
+        );
+    }
+
+    #[test]
+    fn test_parse_iv() {
+        assert_eq!(
+            parse_iv("0x000102030405060708090a0b0c0d0e0F"),
+            Ok([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])
+        );
+        assert_eq!(
+            parse_iv("0XFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF"),
+            Ok([0xff; 16])
+        );
+
+        assert!(parse_iv("000102030405060708090a0b0c0d0e0f").is_err());
+        assert!(parse_iv("0x000102030405060708090a0b0c0d0e").is_err());
+        assert!(parse_iv("0x000102030405060708090a0b0c0d0e0f00").is_err());
+        assert!(parse_iv("0x000102030405060708090a0b0c0d0e0g").is_err());
+        assert!(parse_iv("0x+00102030405060708090a0b0c0d0e0f").is_err());
+        // 32 bytes, but not 32 characters
+        assert!(parse_iv(&format!("0x{}", "\u{e9}".repeat(16))).is_err());
+        assert!(parse_iv("").is_err());
+    }
+
+    #[test]
+    fn test_parse_master() {
+        let data = "\u{feff}#EXTM3U
+#EXT-X-VERSION:3
+
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,CODECS=\"avc1.4d401f,mp4a.40.2\"
+low/index.m3u8
+#EXT-X-STREAM-INF:PROGRAM-ID=1,BANDWIDTH=2560000
+http://cdn.example.com/high/index.m3u8
+#EXT-X-STREAM-INF:RESOLUTION=1920x1080
+/other/index.m3u8
+";
+
+        let variants = match parse(data.as_bytes(), &base()) {
+            Ok(Playlist::Master(master)) => master.variants,
+            res => panic!("Expected master playlist, got {:?}", res),
+        };
+
+        assert_eq!(variants.len(), 3);
+
+        assert_eq!(
+            variants[0].uri.as_str(),
+            "http://example.com/live/low/index.m3u8"
+        );
+        assert_eq!(variants[0].bandwidth, 1280000);
+        assert_eq!(
+            variants[0].codecs.as_ref().map(|c| c.as_str()),
+            Some("avc1.4d401f,mp4a.40.2")
+        );
+
+        assert_eq!(
+            variants[1].uri.as_str(),
+            "http://cdn.example.com/high/index.m3u8"
+        );
+        assert_eq!(variants[1].bandwidth, 2560000);
+        assert_eq!(variants[1].codecs, None);
+
+        // Without bandwidth
+        assert_eq!(variants[2].uri.as_str(), "http://example.com/other/index.m3u8");
+        assert_eq!(variants[2].bandwidth, 0);
+    }
+
+    #[test]
+    fn test_parse_media() {
+        let playlist = parse_media(
+            "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-VERSION:3
+#EXTINF:9.009,
+segment0.ts
+#EXTINF:9.5,Some title
+segment1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:3,
+segment2.ts
+#EXT-X-ENDLIST
+",
+        );
+
+        assert_eq!(playlist.target_duration, 10.0);
+        assert_eq!(playlist.media_sequence, 0);
+        assert!(playlist.endlist);
+
+        let segments = &playlist.segments;
+        assert_eq!(segments.len(), 3);
+        assert_eq!(
+            segments[0].uri.as_str(),
+            "http://example.com/live/segment0.ts"
+        );
+        assert_eq!(segments[0].duration, 9.009);
+        assert_eq!(segments[1].duration, 9.5);
+        assert_eq!(segments[2].duration, 3.0);
+        assert_eq!(
+            segments.iter().map(|s| s.sequence).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            segments.iter().map(|s| s.discontinuity).collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+        assert!(segments.iter().all(|s| s.key.is_none()));
+    }
+
+    #[test]
+    fn test_parse_live() {
+        let playlist = parse_media(
+            "#EXTM3U
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:2680
+#EXTINF:6.0,
+https://media.example.com/fileSequence2680.ts
+#EXTINF:6.0,
+https://media.example.com/fileSequence2681.ts
+#EXTINF:6.0,
+https://media.example.com/fileSequence2682.ts
+",
+        );
+
+        assert_eq!(playlist.target_duration, 6.0);
+        assert_eq!(playlist.media_sequence, 2680);
+        assert!(!playlist.endlist);
+        assert_eq!(
+            playlist
+                .segments
+                .iter()
+                .map(|s| s.sequence)
+                .collect::<Vec<_>>(),
+            vec![2680, 2681, 2682]
+        );
+        assert_eq!(
+            playlist.segments[2].uri.as_str(),
+            "https://media.example.com/fileSequence2682.ts"
+        );
+    }
+
+    #[test]
+    fn test_parse_encrypted() {
+        let playlist = parse_media(
+            "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-MEDIA-SEQUENCE:7
+#EXT-X-KEY:METHOD=AES-128,URI=\"keys/key1.bin\",IV=0x000102030405060708090a0b0c0d0e0f
+#EXTINF:10,
+segment7.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/key2\"
+#EXTINF:10,
+segment8.ts
+#EXTINF:10,
+segment9.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:10,
+segment10.ts
+#EXT-X-ENDLIST
+",
+        );
+
+        let segments = &playlist.segments;
+        assert_eq!(segments.len(), 4);
+
+        assert_eq!(
+            segments[0].key,
+            Some(Key {
+                uri: Url::parse("http://example.com/live/keys/key1.bin").unwrap(),
+                iv: Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]),
+            })
+        );
+
+        // Keys apply to all following segments
+        let key2 = Some(Key {
+            uri: Url::parse("https://keys.example.com/key2").unwrap(),
+            iv: None,
+        });
+        assert_eq!(segments[1].key, key2);
+        assert_eq!(segments[2].key, key2);
+
+        assert_eq!(segments[3].key, None);
+        assert_eq!(segments[3].sequence, 10);
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let base = base();
+
+        assert!(parse(b"", &base).is_err());
+        assert!(parse(b"#EXTINF:10,\nsegment.ts\n", &base).is_err());
+        assert!(parse(b"#EXTM3U\n#EXTINF:\xff,\nsegment.ts\n", &base).is_err());
+
+        let invalid = [
+            "#EXTM3U\n#EXTINF:ten,\nsegment.ts\n",
+            "#EXTM3U\n#EXT-X-TARGETDURATION:\n",
+            "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:-1\n",
+            "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"key\"\n",
+            "#EXTM3U\n#EXT-X-KEY:URI=\"key\"\n",
+            "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128\n",
+            "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"key\",IV=0x1234\n",
+            "#EXTM3U\nhttp://[invalid/segment.ts\n",
+        ];
+        for data in invalid.iter() {
+            assert!(
+                parse(data.as_bytes(), &base).is_err(),
+                "Parsed invalid playlist {:?}",
+                data
+            );
+        }
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright (C) 2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate glib;
+use glib::prelude::*;
+
+extern crate gstreamer as gst;
+use gst::prelude::*;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+
+fn init() {
+    use std::sync::{Once, ONCE_INIT};
+    static INIT: Once = ONCE_INIT;
+
+    INIT.call_once(|| {
+        gst::init().unwrap();
+
+        #[cfg(debug_assertions)]
+        {
+            use std::path::Path;
+
+            let mut path = Path::new("target/debug");
+            if !path.exists() {
+                path = Path::new("../target/debug");
+            }
+
+            gst::Registry::get().scan_path(path);
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            use std::path::Path;
+
+            let mut path = Path::new("target/release");
+            if !path.exists() {
+                path = Path::new("../target/release");
+            }
+
+            gst::Registry::get().scan_path(path);
+        }
+    });
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    // Header names are lowercase
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref v)| v.as_str())
+    }
+}
+
+// Accepts a single request, answers it with 201 and passes it on
+fn serve_one() -> (String, mpsc::Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = format!("http://{}/upload", listener.local_addr().unwrap());
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let (method, path) = {
+            let mut parts = line.split_whitespace();
+            (
+                String::from(parts.next().unwrap()),
+                String::from(parts.next().unwrap()),
+            )
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_right();
+            if line.is_empty() {
+                break;
+            }
+
+            let colon = line.find(':').unwrap();
+            headers.push((
+                line[..colon].trim().to_lowercase(),
+                String::from(line[colon + 1..].trim()),
+            ));
+        }
+
+        let mut request = Request {
+            method: method,
+            path: path,
+            headers: headers,
+            body: Vec::new(),
+        };
+
+        if request.header("transfer-encoding") == Some("chunked") {
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let len = usize::from_str_radix(line.trim(), 16).unwrap();
+
+                let mut chunk = vec![0; len + 2];
+                reader.read_exact(&mut chunk).unwrap();
+                if len == 0 {
+                    break;
+                }
+                request.body.extend_from_slice(&chunk[..len]);
+            }
+        } else if let Some(len) = request.header("content-length") {
+            let mut body = vec![0; len.parse().unwrap()];
+            reader.read_exact(&mut body).unwrap();
+            request.body = body;
+        }
+
+        let mut stream = stream;
+        stream
+            .write_all(
+                b"HTTP/1.1 201 Created\r\n\
+                  Content-Length: 0\r\n\
+                  X-Upload-Id: 42\r\n\
+                  Connection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        sender.send(request).unwrap();
+    });
+
+    (uri, receiver)
+}
+
+// Uploads the buffers and returns the received request and the http-response message
+fn upload(chunked: bool, method: &str, buffers: &[&[u8]]) -> (String, Request, gst::Structure) {
+    init();
+
+    let (uri, receiver) = serve_one();
+
+    let pipeline = gst::Pipeline::new(None);
+    let sink = gst::ElementFactory::make("rshttpsink", None).unwrap();
+    sink.set_property("uri", &uri).unwrap();
+    sink.set_property("method", &method).unwrap();
+    sink.set_property("chunked", &chunked).unwrap();
+    sink.set_property("content-type", &"application/octet-stream")
+        .unwrap();
+    sink.set_property(
+        "extra-headers",
+        &gst::Structure::new("headers", &[("X-Test", &"test-value")]),
+    ).unwrap();
+    pipeline.add(&sink).unwrap();
+
+    pipeline.set_state(gst::State::Playing);
+
+    let sinkpad = sink.get_static_pad("sink").unwrap();
+    let buffers = buffers
+        .iter()
+        .map(|data| gst::Buffer::from_slice(data.to_vec()).unwrap())
+        .collect::<Vec<_>>();
+    let thread = thread::spawn(move || {
+        assert!(sinkpad.send_event(gst::Event::new_stream_start("test").build()));
+        let segment = gst::FormattedSegment::<gst::format::Bytes>::new();
+        assert!(sinkpad.send_event(gst::Event::new_segment(&segment).build()));
+
+        for buffer in buffers {
+            assert_eq!(sinkpad.chain(buffer), gst::FlowReturn::Ok);
+        }
+
+        assert!(sinkpad.send_event(gst::Event::new_eos().build()));
+    });
+
+    let bus = pipeline.get_bus().unwrap();
+    let mut response = None;
+    while let Some(msg) = bus.timed_pop(10 * gst::SECOND) {
+        use gst::MessageView;
+
+        match msg.view() {
+            MessageView::Element(ref e) => {
+                let s = e.get_structure().unwrap();
+                if s.get_name() == "http-response" {
+                    response = Some(s.to_owned());
+                }
+            }
+            MessageView::Eos(..) => break,
+            MessageView::Error(ref e) => {
+                panic!("Error from {:?}: {:?}", msg.get_src(), e.get_error());
+            }
+            _ => (),
+        }
+    }
+
+    thread.join().unwrap();
+    pipeline.set_state(gst::State::Null);
+
+    let request = receiver.recv().unwrap();
+
+    (uri, request, response.expect("No http-response message"))
+}
+
+fn check_response(uri: &str, response: &gst::Structure) {
+    assert_eq!(response.get::<String>("uri"), Some(String::from(uri)));
+    assert_eq!(response.get::<u32>("http-status-code"), Some(201));
+
+    let headers = response.get::<gst::Structure>("response-headers").unwrap();
+    assert_eq!(headers.get::<String>("X-Upload-Id"), Some(String::from("42")));
+}
+
+#[test]
+fn test_put_single() {
+    let (uri, request, response) = upload(false, "PUT", &[b"Hello ", b"World"]);
+
+    assert_eq!(request.method, "PUT");
+    assert_eq!(request.path, "/upload");
+    assert_eq!(request.header("content-length"), Some("11"));
+    assert_eq!(
+        request.header("content-type"),
+        Some("application/octet-stream")
+    );
+    assert_eq!(request.header("user-agent"), Some("GStreamer rshttpsink"));
+    assert_eq!(request.header("x-test"), Some("test-value"));
+    assert_eq!(request.body, b"Hello World");
+
+    check_response(&uri, &response);
+}
+
+#[test]
+fn test_post_chunked() {
+    let (uri, request, response) = upload(true, "post", &[b"Hello ", b"chunked ", b"World"]);
+
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/upload");
+    assert_eq!(request.header("transfer-encoding"), Some("chunked"));
+    assert_eq!(
+        request.header("content-type"),
+        Some("application/octet-stream")
+    );
+    assert_eq!(request.header("x-test"), Some("test-value"));
+    assert_eq!(request.body, b"Hello chunked World");
+
+    check_response(&uri, &response);
+}
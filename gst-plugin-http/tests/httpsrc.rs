@@ -0,0 +1,180 @@
+// Copyright (C) 2017 Sebastian Dröge <sebastian@centricular.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate glib;
+use glib::prelude::*;
+
+extern crate gstreamer as gst;
+use gst::prelude::*;
+
+extern crate openssl;
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod};
+use openssl::x509::{X509, X509NameBuilder};
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+
+fn init() {
+    use std::sync::{Once, ONCE_INIT};
+    static INIT: Once = ONCE_INIT;
+
+    INIT.call_once(|| {
+        gst::init().unwrap();
+
+        #[cfg(debug_assertions)]
+        {
+            use std::path::Path;
+
+            let mut path = Path::new("target/debug");
+            if !path.exists() {
+                path = Path::new("../target/debug");
+            }
+
+            gst::Registry::get().scan_path(path);
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            use std::path::Path;
+
+            let mut path = Path::new("target/release");
+            if !path.exists() {
+                path = Path::new("../target/release");
+            }
+
+            gst::Registry::get().scan_path(path);
+        }
+    });
+}
+
+// Serves a single request over TLS with a freshly generated self-signed certificate
+fn serve_one_tls(body: &'static [u8]) -> String {
+    let rsa = Rsa::generate(2048).unwrap();
+    let pkey = PKey::from_rsa(rsa).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "127.0.0.1")
+        .unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&pkey).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&pkey, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&pkey).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    let acceptor = acceptor.build();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = format!("https://{}/", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+
+        // Fails if the client rejects the certificate
+        let mut stream = match acceptor.accept(stream) {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+
+        {
+            let mut reader = BufReader::new(&mut stream);
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                    break;
+                }
+            }
+        }
+
+        let _ = write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        let _ = stream.write_all(body);
+        let _ = stream.flush();
+    });
+
+    uri
+}
+
+// Runs rshttpsrc ! fakesink until EOS or an error and returns the error if any
+fn run(uri: &str, ssl_strict: bool) -> Option<glib::Error> {
+    init();
+
+    let pipeline = gst::Pipeline::new(None);
+    let src = gst::ElementFactory::make("rshttpsrc", None).unwrap();
+    src.set_property("uri", &uri).unwrap();
+    src.set_property("ssl-strict", &ssl_strict).unwrap();
+    let sink = gst::ElementFactory::make("fakesink", None).unwrap();
+    pipeline.add_many(&[&src, &sink]).unwrap();
+    src.link(&sink).unwrap();
+
+    pipeline.set_state(gst::State::Playing);
+
+    let bus = pipeline.get_bus().unwrap();
+    let mut error = None;
+    while let Some(msg) = bus.timed_pop(10 * gst::SECOND) {
+        use gst::MessageView;
+
+        match msg.view() {
+            MessageView::Eos(..) => break,
+            MessageView::Error(ref e) => {
+                error = Some(e.get_error());
+                break;
+            }
+            _ => (),
+        }
+    }
+
+    pipeline.set_state(gst::State::Null);
+
+    error
+}
+
+// Certificate errors are only recognized with the OpenSSL backend of native-tls
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+#[test]
+fn test_self_signed_rejected() {
+    let uri = serve_one_tls(b"Hello World");
+
+    let err = run(&uri, true).expect("Self-signed certificate was accepted");
+    assert_eq!(
+        err.kind::<gst::ResourceError>(),
+        Some(gst::ResourceError::OpenRead)
+    );
+    assert!(
+        err.to_string()
+            .starts_with("Server certificate validation failed")
+    );
+}
+
+#[test]
+fn test_self_signed_not_strict() {
+    let uri = serve_one_tls(b"Hello World");
+
+    assert!(run(&uri, false).is_none());
+}
@@ -16,6 +16,7 @@ use gobject_subclass::object::*;
 use gst_plugin::element::*;
 use gst_plugin::error::*;
 
+use glib;
 use gst;
 use gst::prelude::*;
 use gst_base;
@@ -33,14 +34,17 @@ pub enum SeekResult {
 pub enum HandleBufferResult {
     NeedMoreData,
     Again,
-    // NeedDataFromOffset(u64),
+    NeedDataFromOffset(u64),
     StreamAdded(Stream),
     HaveAllStreams,
+    StreamsAdded(Vec<Stream>), // Implies HaveAllStreams
     StreamChanged(Stream),
-    // StreamsAdded(Vec<Stream>), // Implies HaveAllStreams
     StreamsChanged(Vec<Stream>),
-    // TODO need something to replace/add new streams
-    // TODO should probably directly implement the GstStreams new world order
+    // Stream with the same index gets a new stream id and caps
+    StreamReplaced(Stream),
+    StreamRemoved(StreamIndex),
+    StreamTags(StreamIndex, gst::TagList),
+    GlobalTags(gst::TagList),
     BufferForStream(StreamIndex, gst::Buffer),
     Eos(Option<StreamIndex>),
 }
@@ -67,9 +71,21 @@ pub trait DemuxerImpl: Send + 'static {
     ) -> Result<HandleBufferResult, FlowError>;
     fn end_of_stream(&mut self, demuxer: &Element) -> Result<(), gst::ErrorMessage>;
 
+    // Called when a select-streams event changed whether a stream is selected. Buffers of
+    // deselected streams are dropped anyway, this allows skipping the work of creating them
+    fn stream_selected(&mut self, _demuxer: &Element, _index: StreamIndex, _selected: bool) {}
+
     fn is_seekable(&self, demuxer: &Element) -> bool;
     fn get_position(&self, demuxer: &Element) -> gst::ClockTime;
     fn get_duration(&self, demuxer: &Element) -> gst::ClockTime;
+
+    fn set_property(&mut self, _demuxer: &Element, _property: &Property, _value: &glib::Value) {
+        unimplemented!()
+    }
+
+    fn get_property(&self, _demuxer: &Element, _property: &Property) -> Result<glib::Value, ()> {
+        unimplemented!()
+    }
 }
 
 #[derive(Debug)]
@@ -99,6 +115,99 @@ pub struct DemuxerInfo {
     pub create_instance: fn(&Element) -> Box<DemuxerImpl>,
     pub input_caps: gst::Caps,
     pub output_caps: gst::Caps,
+    // Properties handled by the DemuxerImpl
+    pub properties: &'static [Property<'static>],
+}
+
+const PULL_BLOCKSIZE: u32 = 64 * 1024;
+
+fn stream_type_from_caps(caps: &gst::Caps) -> gst::StreamType {
+    let name = match caps.get_structure(0) {
+        Some(s) => s.get_name(),
+        None => return gst::StreamType::UNKNOWN,
+    };
+
+    if name.starts_with("audio/") {
+        gst::StreamType::AUDIO
+    } else if name.starts_with("video/") || name.starts_with("image/") {
+        gst::StreamType::VIDEO
+    } else if name.starts_with("text/") || name.starts_with("subpicture/") {
+        gst::StreamType::TEXT
+    } else {
+        gst::StreamType::UNKNOWN
+    }
+}
+
+struct StreamInfo {
+    stream: gst::Stream,
+    stream_id: String,
+    caps: gst::Caps,
+    tags: Option<gst::TagList>,
+    // Streams are selected until a select-streams event says otherwise. Only selected
+    // streams have a source pad
+    selected: bool,
+}
+
+#[derive(Default)]
+struct Streams {
+    streams: BTreeMap<StreamIndex, StreamInfo>,
+    // Set once the subclass has added all streams, every change afterwards
+    // results in a new collection
+    collection: Option<gst::StreamCollection>,
+    global_tags: Option<gst::TagList>,
+}
+
+impl Streams {
+    // The first stream of each type is selected by default
+    fn get_stream_flags(&self, index: StreamIndex, type_: gst::StreamType) -> gst::StreamFlags {
+        let has_default = self.streams.iter().any(|(other, info)| {
+            *other != index && info.stream.get_stream_type() == type_
+                && info.stream.get_stream_flags().contains(gst::StreamFlags::SELECT)
+        });
+
+        if has_default {
+            gst::StreamFlags::empty()
+        } else {
+            gst::StreamFlags::SELECT
+        }
+    }
+
+    fn update_collection(&mut self, upstream_id: Option<String>) -> gst::StreamCollection {
+        let collection = gst::StreamCollection::new(upstream_id.as_ref().map(|s| s.as_str()));
+        for info in self.streams.values() {
+            collection.add_stream(&info.stream);
+        }
+        self.collection = Some(collection.clone());
+
+        collection
+    }
+}
+
+struct State {
+    // Scheduling mode the sinkpad was activated in
+    mode: gst::PadMode,
+    // Next upstream offset to pull from in pull mode
+    offset: u64,
+    // Output segment of all streams and the seqnum of the seek it came from
+    segment: gst::FormattedSegment<gst::ClockTime>,
+    segment_seqnum: gst::Seqnum,
+    // Segment has to be pushed before the next buffer or EOS
+    segment_pending: bool,
+    // Dropping data until upstream starts from the new position after a seek in push mode
+    seeking: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            mode: gst::PadMode::None,
+            offset: 0,
+            segment: gst::FormattedSegment::new(),
+            segment_seqnum: gst::util_seqnum_next(),
+            segment_pending: false,
+            seeking: false,
+        }
+    }
 }
 
 pub struct Demuxer {
@@ -107,6 +216,9 @@ pub struct Demuxer {
     flow_combiner: Mutex<UniqueFlowCombiner>,
     group_id: Mutex<gst::GroupId>,
     srcpads: Mutex<BTreeMap<u32, gst::Pad>>,
+    streams: Mutex<Streams>,
+    state: Mutex<State>,
+    properties: &'static [Property<'static>],
     imp: Mutex<Box<DemuxerImpl>>,
 }
 
@@ -118,10 +230,18 @@ impl UniqueFlowCombiner {
         self.0.add_pad(pad);
     }
 
+    fn remove_pad(&mut self, pad: &gst::Pad) {
+        self.0.remove_pad(pad);
+    }
+
     fn clear(&mut self) {
         self.0.clear();
     }
 
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+
     fn update_flow(&mut self, flow_ret: gst::FlowReturn) -> gst::FlowReturn {
         self.0.update_flow(flow_ret)
     }
@@ -142,6 +262,9 @@ impl Demuxer {
             flow_combiner: Mutex::new(Default::default()),
             group_id: Mutex::new(gst::util_group_id_next()),
             srcpads: Mutex::new(BTreeMap::new()),
+            streams: Mutex::new(Streams::default()),
+            state: Mutex::new(State::default()),
+            properties: demuxer_info.properties,
             imp: Mutex::new((demuxer_info.create_instance)(element)),
         }
     }
@@ -169,6 +292,8 @@ impl Demuxer {
             &demuxer_info.output_caps,
         );
         klass.add_pad_template(pad_template);
+
+        klass.install_properties(demuxer_info.properties);
     }
 
     fn init(element: &Element, demuxer_info: &DemuxerInfo) -> Box<ElementImpl<Element>> {
@@ -184,28 +309,58 @@ impl Demuxer {
         Box::new(imp)
     }
 
-    fn add_stream(&self, element: &Element, index: u32, caps: gst::Caps, stream_id: &str) {
-        let mut srcpads = self.srcpads.lock().unwrap();
-        assert!(!srcpads.contains_key(&index));
-
+    fn create_srcpad(&self, element: &Element, index: u32) -> gst::Pad {
         let templ = element.get_pad_template("src_%u").unwrap();
         let name = format!("src_{}", index);
         let pad = gst::Pad::new_from_template(&templ, Some(name.as_str()));
         pad.set_query_function(Demuxer::src_query);
         pad.set_event_function(Demuxer::src_event);
 
+        pad
+    }
+
+    fn expose_stream(
+        &self,
+        element: &Element,
+        index: u32,
+        pad: gst::Pad,
+        stream: &gst::Stream,
+        stream_id: &str,
+        caps: &gst::Caps,
+    ) {
+        let (global_tags, tags) = {
+            let streams = self.streams.lock().unwrap();
+            (
+                streams.global_tags.clone(),
+                streams.streams.get(&index).and_then(|info| info.tags.clone()),
+            )
+        };
+
+        let mut srcpads = self.srcpads.lock().unwrap();
+        assert!(!srcpads.contains_key(&index));
+
         pad.set_active(true).unwrap();
 
-        let full_stream_id = pad.create_stream_id(element, stream_id).unwrap();
         pad.push_event(
-            gst::Event::new_stream_start(&full_stream_id)
+            gst::Event::new_stream_start(stream_id)
                 .group_id(*self.group_id.lock().unwrap())
+                .stream(stream.clone())
                 .build(),
         );
-        pad.push_event(gst::Event::new_caps(&caps).build());
+        pad.push_event(gst::Event::new_caps(caps).build());
 
-        let segment = gst::FormattedSegment::<gst::ClockTime>::default();
-        pad.push_event(gst::Event::new_segment(&segment).build());
+        let (segment, seqnum) = {
+            let state = self.state.lock().unwrap();
+            (state.segment.clone(), state.segment_seqnum)
+        };
+        pad.push_event(gst::Event::new_segment(&segment).seqnum(seqnum).build());
+
+        if let Some(global_tags) = global_tags {
+            pad.push_event(gst::Event::new_tag(global_tags).build());
+        }
+        if let Some(tags) = tags {
+            pad.push_event(gst::Event::new_tag(tags).build());
+        }
 
         self.flow_combiner.lock().unwrap().add_pad(&pad);
         element.add_pad(&pad).unwrap();
@@ -213,23 +368,361 @@ impl Demuxer {
         srcpads.insert(index, pad);
     }
 
+    fn remove_srcpad(&self, element: &Element, index: u32) {
+        let pad = match self.srcpads.lock().unwrap().remove(&index) {
+            Some(pad) => pad,
+            None => return,
+        };
+
+        let seqnum = self.state.lock().unwrap().segment_seqnum;
+        pad.push_event(gst::Event::new_eos().seqnum(seqnum).build());
+
+        self.flow_combiner.lock().unwrap().remove_pad(&pad);
+        let _ = pad.set_active(false);
+        element.remove_pad(&pad).unwrap();
+    }
+
+    fn post_collection(&self, element: &Element, collection: &gst::StreamCollection) {
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Posting stream collection with {} streams",
+            collection.get_size()
+        );
+
+        let event = gst::Event::new_stream_collection(collection).build();
+        let srcpads = self.srcpads.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        for pad in srcpads {
+            pad.push_event(event.clone());
+        }
+
+        let _ = element.post_message(&gst::Message::new_stream_collection(collection)
+            .src(Some(element))
+            .build());
+    }
+
+    fn add_stream(&self, element: &Element, index: u32, caps: gst::Caps, stream_id: &str) {
+        let pad = self.create_srcpad(element, index);
+
+        let full_stream_id = pad.create_stream_id(element, stream_id).unwrap();
+
+        let (stream, collection) = {
+            let mut streams = self.streams.lock().unwrap();
+            assert!(!streams.streams.contains_key(&index));
+
+            let type_ = stream_type_from_caps(&caps);
+            let stream = gst::Stream::new(
+                Some(full_stream_id.as_str()),
+                Some(&caps),
+                type_,
+                streams.get_stream_flags(index, type_),
+            );
+
+            streams.streams.insert(
+                index,
+                StreamInfo {
+                    stream: stream.clone(),
+                    stream_id: full_stream_id.clone(),
+                    caps: caps.clone(),
+                    tags: None,
+                    selected: true,
+                },
+            );
+
+            // Streams added after the initial collection update it
+            let collection = if streams.collection.is_some() {
+                Some(streams.update_collection(self.sinkpad.get_stream_id()))
+            } else {
+                None
+            };
+
+            (stream, collection)
+        };
+
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Adding stream {} with id {} and caps {:?}",
+            index,
+            full_stream_id,
+            caps
+        );
+
+        self.expose_stream(element, index, pad, &stream, &full_stream_id, &caps);
+
+        if let Some(collection) = collection {
+            self.post_collection(element, &collection);
+        }
+    }
+
     fn added_all_streams(&self, element: &Element) {
+        let collection = self.streams
+            .lock()
+            .unwrap()
+            .update_collection(self.sinkpad.get_stream_id());
+
         element.no_more_pads();
+        self.post_collection(element, &collection);
+
         *self.group_id.lock().unwrap() = gst::util_group_id_next();
     }
 
+    fn replace_stream(&self, element: &Element, index: u32, caps: gst::Caps, stream_id: &str) {
+        let pad = self.srcpads.lock().unwrap().get(&index).cloned();
+
+        let (pad, exposed) = match pad {
+            Some(pad) => (pad, true),
+            None => {
+                if !self.streams.lock().unwrap().streams.contains_key(&index) {
+                    gst_debug!(self.cat, obj: element, "Replacing unknown stream {}", index);
+                    return self.add_stream(element, index, caps, stream_id);
+                }
+                // Deselected, the pad is only needed for creating the stream id
+                (self.create_srcpad(element, index), false)
+            }
+        };
+
+        let full_stream_id = pad.create_stream_id(element, stream_id).unwrap();
+
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Replacing stream {} with id {} and caps {:?}",
+            index,
+            full_stream_id,
+            caps
+        );
+
+        let (stream, collection) = {
+            let mut streams = self.streams.lock().unwrap();
+
+            let type_ = stream_type_from_caps(&caps);
+            let stream = gst::Stream::new(
+                Some(full_stream_id.as_str()),
+                Some(&caps),
+                type_,
+                streams.get_stream_flags(index, type_),
+            );
+
+            if let Some(info) = streams.streams.get_mut(&index) {
+                info.stream = stream.clone();
+                info.stream_id = full_stream_id.clone();
+                info.caps = caps.clone();
+                info.tags = None;
+            }
+
+            let collection = if streams.collection.is_some() {
+                Some(streams.update_collection(self.sinkpad.get_stream_id()))
+            } else {
+                None
+            };
+
+            (stream, collection)
+        };
+
+        if exposed {
+            pad.push_event(
+                gst::Event::new_stream_start(&full_stream_id)
+                    .group_id(*self.group_id.lock().unwrap())
+                    .stream(stream)
+                    .build(),
+            );
+            pad.push_event(gst::Event::new_caps(&caps).build());
+
+            let (segment, seqnum) = {
+                let state = self.state.lock().unwrap();
+                (state.segment.clone(), state.segment_seqnum)
+            };
+            pad.push_event(gst::Event::new_segment(&segment).seqnum(seqnum).build());
+        }
+
+        if let Some(collection) = collection {
+            self.post_collection(element, &collection);
+        }
+    }
+
+    fn remove_stream(&self, element: &Element, index: u32) {
+        gst_debug!(self.cat, obj: element, "Removing stream {}", index);
+
+        let collection = {
+            let mut streams = self.streams.lock().unwrap();
+            if streams.streams.remove(&index).is_none() {
+                gst_warning!(self.cat, obj: element, "Removing unknown stream {}", index);
+                return;
+            }
+
+            if streams.collection.is_some() {
+                Some(streams.update_collection(self.sinkpad.get_stream_id()))
+            } else {
+                None
+            }
+        };
+
+        self.remove_srcpad(element, index);
+
+        if let Some(collection) = collection {
+            self.post_collection(element, &collection);
+        }
+    }
+
+    fn select_streams(&self, element: &Element, stream_ids: &[String]) -> bool {
+        let mut deselected = Vec::new();
+        let mut selected = Vec::new();
+
+        let (collection, selected_streams) = {
+            let mut streams = self.streams.lock().unwrap();
+
+            let collection = match streams.collection {
+                Some(ref collection) => collection.clone(),
+                None => {
+                    gst_debug!(self.cat, obj: element, "No stream collection yet");
+                    return false;
+                }
+            };
+
+            for id in stream_ids {
+                if !streams.streams.values().any(|info| info.stream_id == *id) {
+                    gst_warning!(self.cat, obj: element, "Selecting unknown stream {}", id);
+                    return false;
+                }
+            }
+
+            for (index, info) in streams.streams.iter_mut() {
+                let select = stream_ids.contains(&info.stream_id);
+
+                if info.selected && !select {
+                    deselected.push(*index);
+                } else if !info.selected && select {
+                    selected.push((
+                        *index,
+                        info.stream.clone(),
+                        info.stream_id.clone(),
+                        info.caps.clone(),
+                    ));
+                }
+                info.selected = select;
+            }
+
+            let selected_streams = streams
+                .streams
+                .values()
+                .filter(|info| info.selected)
+                .map(|info| info.stream.clone())
+                .collect::<Vec<_>>();
+
+            (collection, selected_streams)
+        };
+
+        gst_debug!(self.cat, obj: element, "Selecting streams {:?}", stream_ids);
+
+        {
+            let demuxer_impl = &mut self.imp.lock().unwrap();
+            for index in &deselected {
+                demuxer_impl.stream_selected(element, *index, false);
+            }
+            for &(index, ..) in &selected {
+                demuxer_impl.stream_selected(element, index, true);
+            }
+        }
+
+        for index in deselected {
+            self.remove_srcpad(element, index);
+        }
+
+        for (index, stream, stream_id, caps) in selected {
+            let pad = self.create_srcpad(element, index);
+            self.expose_stream(element, index, pad.clone(), &stream, &stream_id, &caps);
+            pad.push_event(gst::Event::new_stream_collection(&collection).build());
+        }
+
+        let selected_streams = selected_streams.iter().collect::<Vec<_>>();
+        let _ = element.post_message(&gst::Message::new_streams_selected(&collection)
+            .streams(&selected_streams)
+            .src(Some(element))
+            .build());
+
+        true
+    }
+
+    fn stream_tags(&self, element: &Element, index: u32, mut tags: gst::TagList) {
+        tags.make_mut().set_scope(gst::TagScope::Stream);
+
+        gst_debug!(self.cat, obj: element, "Stream {} tags {:?}", index, tags);
+
+        match self.streams.lock().unwrap().streams.get_mut(&index) {
+            Some(info) => info.tags = Some(tags.clone()),
+            None => {
+                gst_warning!(self.cat, obj: element, "Tags for unknown stream {}", index);
+                return;
+            }
+        }
+
+        // Tags are sticky events that have to come after the segment
+        self.push_pending_segment(element);
+
+        let pad = self.srcpads.lock().unwrap().get(&index).cloned();
+
+        if let Some(pad) = pad {
+            pad.push_event(gst::Event::new_tag(tags).build());
+        }
+    }
+
+    fn global_tags(&self, element: &Element, mut tags: gst::TagList) {
+        tags.make_mut().set_scope(gst::TagScope::Global);
+
+        gst_debug!(self.cat, obj: element, "Global tags {:?}", tags);
+
+        self.streams.lock().unwrap().global_tags = Some(tags.clone());
+
+        self.push_pending_segment(element);
+
+        let event = gst::Event::new_tag(tags).build();
+        let srcpads = self.srcpads.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        for pad in srcpads {
+            pad.push_event(event.clone());
+        }
+    }
+
     fn stream_format_changed(&self, _element: &Element, index: u32, caps: gst::Caps) {
-        let srcpads = self.srcpads.lock().unwrap();
+        if let Some(info) = self.streams.lock().unwrap().streams.get_mut(&index) {
+            info.stream.set_caps(Some(&caps));
+            info.caps = caps.clone();
+        }
 
-        if let Some(pad) = srcpads.get(&index) {
+        let pad = self.srcpads.lock().unwrap().get(&index).cloned();
+
+        if let Some(pad) = pad {
             pad.push_event(gst::Event::new_caps(&caps).build());
         }
     }
 
-    fn stream_eos(&self, _element: &Element, index: Option<u32>) {
-        let srcpads = self.srcpads.lock().unwrap();
+    fn push_pending_segment(&self, element: &Element) {
+        let (segment, seqnum) = {
+            let mut state = self.state.lock().unwrap();
+            if !state.segment_pending {
+                return;
+            }
+            state.segment_pending = false;
+            (state.segment.clone(), state.segment_seqnum)
+        };
+
+        gst_debug!(self.cat, obj: element, "Pushing segment {:?}", segment);
+
+        let event = gst::Event::new_segment(&segment).seqnum(seqnum).build();
+        let srcpads = self.srcpads.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        for pad in srcpads {
+            pad.push_event(event.clone());
+        }
+    }
 
-        let event = gst::Event::new_eos().build();
+    fn stream_eos(&self, element: &Element, index: Option<u32>) {
+        self.push_pending_segment(element);
+
+        let seqnum = self.state.lock().unwrap().segment_seqnum;
+        let event = gst::Event::new_eos().seqnum(seqnum).build();
+
+        // Don't keep the pads locked while pushing, a seek might have to flush them
+        let srcpads = self.srcpads.lock().unwrap().clone();
         match index {
             Some(index) => if let Some(pad) = srcpads.get(&index) {
                 pad.push_event(event);
@@ -242,22 +735,46 @@ impl Demuxer {
 
     fn stream_push_buffer(
         &self,
-        _element: &Element,
+        element: &Element,
         index: u32,
         buffer: gst::Buffer,
     ) -> gst::FlowReturn {
-        let srcpads = self.srcpads.lock().unwrap();
-
-        if let Some(pad) = srcpads.get(&index) {
-            self.flow_combiner
-                .lock()
-                .unwrap()
-                .update_flow(pad.push(buffer))
+        self.push_pending_segment(element);
+
+        let pad = self.srcpads.lock().unwrap().get(&index).cloned();
+
+        if let Some(pad) = pad {
+            let flow_ret = pad.push(buffer);
+            self.flow_combiner.lock().unwrap().update_flow(flow_ret)
+        } else if self.streams
+            .lock()
+            .unwrap()
+            .streams
+            .get(&index)
+            .map(|info| !info.selected)
+            .unwrap_or(false)
+        {
+            // Deselected stream, nobody is interested in its data
+            gst::FlowReturn::Ok
         } else {
             gst::FlowReturn::Error
         }
     }
 
+    fn is_after_segment_stop(&self, buffer: &gst::Buffer) -> bool {
+        let stop = self.state.lock().unwrap().segment.get_stop();
+        let timestamp = if buffer.get_dts().is_some() {
+            buffer.get_dts()
+        } else {
+            buffer.get_pts()
+        };
+
+        match (stop.0, timestamp.0) {
+            (Some(stop), Some(timestamp)) => timestamp >= stop,
+            _ => false,
+        }
+    }
+
     fn remove_all_streams(&self, element: &Element) {
         self.flow_combiner.lock().unwrap().clear();
         let mut srcpads = self.srcpads.lock().unwrap();
@@ -265,23 +782,36 @@ impl Demuxer {
             element.remove_pad(pad).unwrap();
         }
         srcpads.clear();
+        *self.streams.lock().unwrap() = Streams::default();
     }
 
-    fn sink_activate(pad: &gst::Pad, _parent: &Option<gst::Object>) -> bool {
+    fn sink_activate(pad: &gst::Pad, parent: &Option<gst::Object>) -> bool {
+        let element = parent.as_ref().unwrap().downcast_ref::<Element>().unwrap();
+        let demuxer = element.get_impl().downcast_ref::<Demuxer>().unwrap();
+
         let mode = {
+            use gst::QueryView;
+
             let mut query = gst::Query::new_scheduling();
             if !pad.peer_query(&mut query) {
                 return false;
             }
 
-            // TODO
-            //if (gst_query_has_scheduling_mode_with_flags (query, GST_PAD_MODE_PULL, GST_SCHEDULING_FLAG_SEEKABLE)) {
-            //  GST_DEBUG_OBJECT (demuxer, "Activating in PULL mode");
-            //  mode = GST_PAD_MODE_PULL;
-            //} else {
-            //GST_DEBUG_OBJECT (demuxer, "Activating in PUSH mode");
-            //}
-            gst::PadMode::Push
+            let pull_mode = match query.view() {
+                QueryView::Scheduling(ref q) => q.has_scheduling_mode_with_flags(
+                    gst::PadMode::Pull,
+                    gst::SchedulingFlags::SEEKABLE,
+                ),
+                _ => unreachable!(),
+            };
+
+            if pull_mode {
+                gst_debug!(demuxer.cat, obj: element, "Activating in Pull mode");
+                gst::PadMode::Pull
+            } else {
+                gst_debug!(demuxer.cat, obj: element, "Activating in Push mode");
+                gst::PadMode::Push
+            }
         };
 
         match pad.activate_mode(mode, true) {
@@ -347,13 +877,19 @@ impl Demuxer {
                 .peer_query_duration::<gst::format::Bytes>()
                 .and_then(|v| v.0);
 
+            {
+                let mut state = demuxer.state.lock().unwrap();
+                *state = State::default();
+                state.mode = mode;
+            }
+
             if !demuxer.start(element, upstream_size, mode == gst::PadMode::Pull) {
                 return false;
             }
 
-            if mode == gst::PadMode::Pull {
-                // TODO
-                // demuxer.sinkpad.start_task(...)
+            if mode == gst::PadMode::Pull && !demuxer.start_task(element) {
+                demuxer.stop(element);
+                return false;
             }
 
             true
@@ -362,10 +898,77 @@ impl Demuxer {
                 let _ = demuxer.sinkpad.stop_task();
             }
 
+            demuxer.state.lock().unwrap().mode = gst::PadMode::None;
+
             demuxer.stop(element)
         }
     }
 
+    fn start_task(&self, element: &Element) -> bool {
+        let element_clone = element.clone();
+        let res = self.sinkpad.start_task(move || {
+            let demuxer = element_clone.get_impl().downcast_ref::<Demuxer>().unwrap();
+            demuxer.pull_loop(&element_clone);
+        });
+
+        if res.is_err() {
+            gst_error!(self.cat, obj: element, "Failed to start streaming task");
+            return false;
+        }
+
+        true
+    }
+
+    fn pull_loop(&self, element: &Element) {
+        let offset = self.state.lock().unwrap().offset;
+
+        gst_trace!(self.cat, obj: element, "Pulling buffer at offset {}", offset);
+
+        let flow_ret = match self.sinkpad.pull_range(offset, PULL_BLOCKSIZE) {
+            Ok(buffer) => {
+                self.state.lock().unwrap().offset = offset + buffer.get_size() as u64;
+                self.handle_buffer(element, Some(buffer))
+            }
+            Err(gst::FlowReturn::Eos) => {
+                {
+                    let demuxer_impl = &mut self.imp.lock().unwrap();
+
+                    gst_debug!(self.cat, obj: element, "End of stream");
+                    match demuxer_impl.end_of_stream(element) {
+                        Ok(_) => (),
+                        Err(ref msg) => {
+                            gst_error!(self.cat, obj: element, "Failed end of stream: {:?}", msg);
+                            element.post_error_message(msg);
+                        }
+                    }
+                }
+
+                self.stream_eos(element, None);
+                gst::FlowReturn::Eos
+            }
+            Err(flow_ret) => flow_ret,
+        };
+
+        match flow_ret {
+            gst::FlowReturn::Ok => (),
+            gst::FlowReturn::Eos | gst::FlowReturn::Flushing => {
+                gst_debug!(self.cat, obj: element, "Pausing task: {:?}", flow_ret);
+                let _ = self.sinkpad.pause_task();
+            }
+            _ => {
+                gst_debug!(self.cat, obj: element, "Pausing task: {:?}", flow_ret);
+                let _ = self.sinkpad.pause_task();
+
+                gst_element_error!(
+                    element,
+                    gst::StreamError::Failed,
+                    ["Streaming stopped, reason {:?}", flow_ret]
+                );
+                self.stream_eos(element, None);
+            }
+        }
+    }
+
     fn sink_chain(
         _pad: &gst::Pad,
         parent: &Option<gst::Object>,
@@ -374,16 +977,30 @@ impl Demuxer {
         let element = parent.as_ref().unwrap().downcast_ref::<Element>().unwrap();
         let demuxer = element.get_impl().downcast_ref::<Demuxer>().unwrap();
 
+        if demuxer.state.lock().unwrap().seeking {
+            gst_trace!(
+                demuxer.cat,
+                obj: element,
+                "Dropping buffer {:?} while seeking",
+                buffer
+            );
+            return gst::FlowReturn::Ok;
+        }
+
+        demuxer.handle_buffer(element, Some(buffer))
+    }
+
+    fn handle_buffer(&self, element: &Element, buffer: Option<gst::Buffer>) -> gst::FlowReturn {
         let mut res = {
-            let demuxer_impl = &mut demuxer.imp.lock().unwrap();
+            let demuxer_impl = &mut self.imp.lock().unwrap();
 
-            gst_trace!(demuxer.cat, obj: element, "Handling buffer {:?}", buffer);
+            gst_trace!(self.cat, obj: element, "Handling buffer {:?}", buffer);
 
-            match demuxer_impl.handle_buffer(element, Some(buffer)) {
+            match demuxer_impl.handle_buffer(element, buffer) {
                 Ok(res) => res,
                 Err(flow_error) => {
                     gst_error!(
-                        demuxer.cat,
+                        self.cat,
                         obj: element,
                         "Failed handling buffer: {:?}",
                         flow_error
@@ -401,49 +1018,101 @@ impl Demuxer {
 
         // Loop until AllEos, NeedMoreData or error when pushing downstream
         loop {
-            gst_trace!(demuxer.cat, obj: element, "Handled {:?}", res);
+            gst_trace!(self.cat, obj: element, "Handled {:?}", res);
+
+            // Results of data from before a seek would be sent with the new segment
+            if self.state.lock().unwrap().seeking {
+                gst_debug!(self.cat, obj: element, "Dropping {:?} while seeking", res);
+                return gst::FlowReturn::Ok;
+            }
 
             match res {
                 HandleBufferResult::NeedMoreData => {
                     return gst::FlowReturn::Ok;
                 }
                 HandleBufferResult::StreamAdded(stream) => {
-                    demuxer.add_stream(element, stream.index, stream.caps, &stream.stream_id);
+                    self.add_stream(element, stream.index, stream.caps, &stream.stream_id);
                 }
                 HandleBufferResult::HaveAllStreams => {
-                    demuxer.added_all_streams(element);
+                    self.added_all_streams(element);
+                }
+                HandleBufferResult::StreamsAdded(streams) => {
+                    for stream in streams {
+                        self.add_stream(element, stream.index, stream.caps, &stream.stream_id);
+                    }
+                    self.added_all_streams(element);
                 }
                 HandleBufferResult::StreamChanged(stream) => {
-                    demuxer.stream_format_changed(element, stream.index, stream.caps);
+                    self.stream_format_changed(element, stream.index, stream.caps);
                 }
                 HandleBufferResult::StreamsChanged(streams) => for stream in streams {
-                    demuxer.stream_format_changed(element, stream.index, stream.caps);
+                    self.stream_format_changed(element, stream.index, stream.caps);
                 },
+                HandleBufferResult::StreamReplaced(stream) => {
+                    self.replace_stream(element, stream.index, stream.caps, &stream.stream_id);
+                }
+                HandleBufferResult::StreamRemoved(index) => {
+                    self.remove_stream(element, index);
+                }
+                HandleBufferResult::StreamTags(index, tags) => {
+                    self.stream_tags(element, index, tags);
+                }
+                HandleBufferResult::GlobalTags(tags) => {
+                    self.global_tags(element, tags);
+                }
                 HandleBufferResult::BufferForStream(index, buffer) => {
-                    let flow_ret = demuxer.stream_push_buffer(element, index, buffer);
+                    if self.is_after_segment_stop(&buffer) {
+                        gst_debug!(self.cat, obj: element, "Reached segment stop");
+                        self.stream_eos(element, None);
+                        return gst::FlowReturn::Eos;
+                    }
+
+                    let flow_ret = self.stream_push_buffer(element, index, buffer);
 
                     if flow_ret != gst::FlowReturn::Ok {
                         return flow_ret;
                     }
                 }
                 HandleBufferResult::Eos(index) => {
-                    demuxer.stream_eos(element, index);
+                    self.stream_eos(element, index);
                     return gst::FlowReturn::Eos;
                 }
                 HandleBufferResult::Again => {
                     // nothing, just call again
                 }
+                HandleBufferResult::NeedDataFromOffset(offset) => {
+                    let mut state = self.state.lock().unwrap();
+
+                    if state.mode != gst::PadMode::Pull {
+                        gst_error!(
+                            self.cat,
+                            obj: element,
+                            "Can't get data from offset {} in push mode",
+                            offset
+                        );
+                        gst_element_error!(
+                            element,
+                            gst::CoreError::Failed,
+                            ["Random access not possible in push mode"]
+                        );
+                        return gst::FlowReturn::Error;
+                    }
+
+                    gst_debug!(self.cat, obj: element, "Continuing at offset {}", offset);
+                    state.offset = offset;
+                    return gst::FlowReturn::Ok;
+                }
             };
 
-            gst_trace!(demuxer.cat, obj: element, "Calling again");
+            gst_trace!(self.cat, obj: element, "Calling again");
 
             res = {
-                let demuxer_impl = &mut demuxer.imp.lock().unwrap();
+                let demuxer_impl = &mut self.imp.lock().unwrap();
                 match demuxer_impl.handle_buffer(element, None) {
                     Ok(res) => res,
                     Err(flow_error) => {
                         gst_error!(
-                            demuxer.cat,
+                            self.cat,
                             obj: element,
                             "Failed calling again: {:?}",
                             flow_error
@@ -481,7 +1150,20 @@ impl Demuxer {
                 }
                 pad.event_default(parent.as_ref(), event)
             }
-            EventView::Segment(..) => pad.event_default(parent.as_ref(), event),
+            EventView::Segment(..) => {
+                // We create our own segments for the source pads
+                let mut state = demuxer.state.lock().unwrap();
+                if state.seeking {
+                    gst_debug!(demuxer.cat, obj: element, "Upstream finished seeking");
+                    state.seeking = false;
+                }
+
+                true
+            }
+            EventView::FlushStop(..) => {
+                demuxer.flow_combiner.lock().unwrap().reset();
+                pad.event_default(parent.as_ref(), event)
+            }
             _ => pad.event_default(parent.as_ref(), event),
         }
     }
@@ -554,13 +1236,196 @@ impl Demuxer {
 
         match event.view() {
             EventView::Seek(..) => {
-                // TODO: Implement
-                false
+                let element = parent.as_ref().unwrap().downcast_ref::<Element>().unwrap();
+                let demuxer = element.get_impl().downcast_ref::<Demuxer>().unwrap();
+
+                demuxer.handle_seek(element, &event)
+            }
+            EventView::SelectStreams(ref ev) => {
+                let element = parent.as_ref().unwrap().downcast_ref::<Element>().unwrap();
+                let demuxer = element.get_impl().downcast_ref::<Demuxer>().unwrap();
+
+                demuxer.select_streams(element, &ev.get_streams())
             }
             _ => pad.event_default(parent.as_ref(), event),
         }
     }
 
+    fn handle_seek(&self, element: &Element, event: &gst::Event) -> bool {
+        use gst::EventView;
+
+        let mode = self.state.lock().unwrap().mode;
+
+        // Give upstream a chance to handle the seek first
+        if mode == gst::PadMode::Push && self.sinkpad.push_event(event.clone()) {
+            gst_debug!(self.cat, obj: element, "Upstream handled seek");
+            return true;
+        }
+
+        let (rate, flags, start_type, start, stop_type, stop) = match event.view() {
+            EventView::Seek(ref ev) => ev.get(),
+            _ => unreachable!(),
+        };
+        let seqnum = event.get_seqnum();
+
+        let (start, stop) = match (start, stop) {
+            (gst::GenericFormattedValue::Time(start), gst::GenericFormattedValue::Time(stop)) => {
+                (start, stop)
+            }
+            _ => {
+                gst_debug!(self.cat, obj: element, "Can only seek in TIME format");
+                return false;
+            }
+        };
+
+        if rate <= 0.0 {
+            gst_debug!(self.cat, obj: element, "Unsupported playback rate {}", rate);
+            return false;
+        }
+
+        if start_type != gst::SeekType::Set
+            || (stop_type != gst::SeekType::Set && stop_type != gst::SeekType::None)
+        {
+            gst_debug!(
+                self.cat,
+                obj: element,
+                "Unsupported seek types {:?}-{:?}",
+                start_type,
+                stop_type
+            );
+            return false;
+        }
+        let stop = if stop_type == gst::SeekType::None {
+            gst::CLOCK_TIME_NONE
+        } else {
+            stop
+        };
+
+        if !self.imp.lock().unwrap().is_seekable(element) {
+            gst_debug!(self.cat, obj: element, "Not seekable");
+            return false;
+        }
+
+        let flush = flags.contains(gst::SeekFlags::FLUSH);
+
+        gst_debug!(
+            self.cat,
+            obj: element,
+            "Handling {} seek to {}-{} with seqnum {:?}",
+            if flush { "flushing" } else { "non-flushing" },
+            start,
+            stop,
+            seqnum
+        );
+
+        // Stop the streaming thread in pull mode. In push mode upstream will flush
+        // everything once we forwarded the seek in BYTES format
+        let _stream_lock = if mode == gst::PadMode::Pull {
+            if flush {
+                let event = gst::Event::new_flush_start().seqnum(seqnum).build();
+                for pad in self.srcpads.lock().unwrap().values().cloned().collect::<Vec<_>>() {
+                    pad.push_event(event.clone());
+                }
+                self.sinkpad.push_event(event);
+            }
+
+            let _ = self.sinkpad.pause_task();
+            let stream_lock = self.sinkpad.stream_lock();
+
+            if flush {
+                let event = gst::Event::new_flush_stop(true).seqnum(seqnum).build();
+                for pad in self.srcpads.lock().unwrap().values().cloned().collect::<Vec<_>>() {
+                    pad.push_event(event.clone());
+                }
+                self.sinkpad.push_event(event);
+                self.flow_combiner.lock().unwrap().reset();
+            }
+
+            Some(stream_lock)
+        } else {
+            None
+        };
+
+        let old_segment = {
+            let mut state = self.state.lock().unwrap();
+
+            let mut segment = gst::FormattedSegment::<gst::ClockTime>::new();
+            if !flush {
+                // Continue with the running time of the current position
+                let position = self.imp.lock().unwrap().get_position(element);
+                segment.set_base(state.segment.to_running_time(position));
+            }
+            segment.set_rate(rate);
+            segment.set_start(start);
+            segment.set_stop(stop);
+            segment.set_time(start);
+            segment.set_position(start);
+
+            let old_segment = (state.segment.clone(), state.segment_seqnum);
+            state.segment = segment;
+            state.segment_seqnum = seqnum;
+            state.segment_pending = true;
+            // Buffers from before the seek must not reach the implementation once it seeked,
+            // in push mode nothing else keeps them out
+            if mode == gst::PadMode::Push {
+                state.seeking = true;
+            }
+
+            old_segment
+        };
+
+        let mut offset = 0;
+        if !self.seek(element, start, stop, &mut offset) {
+            let mut state = self.state.lock().unwrap();
+            state.segment = old_segment.0;
+            state.segment_seqnum = old_segment.1;
+            state.segment_pending = false;
+            state.seeking = false;
+            drop(state);
+
+            if mode == gst::PadMode::Pull {
+                self.start_task(element);
+            }
+
+            return false;
+        }
+
+        if offset == u64::MAX {
+            // Already EOS, nothing to restart
+            self.state.lock().unwrap().seeking = false;
+            return true;
+        }
+
+        if mode == gst::PadMode::Pull {
+            self.state.lock().unwrap().offset = offset;
+            self.start_task(element)
+        } else {
+            let event = gst::Event::new_seek(
+                1.0,
+                if flush {
+                    gst::SeekFlags::FLUSH
+                } else {
+                    gst::SeekFlags::empty()
+                },
+                gst::SeekType::Set,
+                gst::format::Bytes(Some(offset)),
+                gst::SeekType::None,
+                gst::format::Bytes(None),
+            ).seqnum(seqnum)
+                .build();
+
+            gst_debug!(self.cat, obj: element, "Seeking upstream to offset {}", offset);
+
+            if !self.sinkpad.push_event(event) {
+                gst_error!(self.cat, obj: element, "Upstream failed to seek");
+                self.state.lock().unwrap().seeking = false;
+                return false;
+            }
+
+            true
+        }
+    }
+
     fn seek(
         &self,
         element: &Element,
@@ -605,7 +1470,24 @@ impl Demuxer {
     }
 }
 
-impl ObjectImpl<Element> for Demuxer {}
+impl ObjectImpl<Element> for Demuxer {
+    fn set_property(&self, obj: &glib::Object, id: u32, value: &glib::Value) {
+        let element = obj.downcast_ref::<Element>().unwrap();
+        let prop = &self.properties[id as usize];
+
+        self.imp
+            .lock()
+            .unwrap()
+            .set_property(element, prop, value);
+    }
+
+    fn get_property(&self, obj: &glib::Object, id: u32) -> Result<glib::Value, ()> {
+        let element = obj.downcast_ref::<Element>().unwrap();
+        let prop = &self.properties[id as usize];
+
+        self.imp.lock().unwrap().get_property(element, prop)
+    }
+}
 
 impl ElementImpl<Element> for Demuxer {
     fn change_state(
@@ -632,12 +1514,7 @@ impl ElementImpl<Element> for Demuxer {
 
         match transition {
             gst::StateChange::PausedToReady => {
-                self.flow_combiner.lock().unwrap().clear();
-                let mut srcpads = self.srcpads.lock().unwrap();
-                for (_, pad) in srcpads.iter().by_ref() {
-                    element.remove_pad(pad).unwrap();
-                }
-                srcpads.clear();
+                self.remove_all_streams(element);
             }
             _ => (),
         }
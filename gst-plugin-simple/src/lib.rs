@@ -20,4 +20,26 @@ pub mod error;
 pub mod sink;
 pub mod source;
 
+use gobject_subclass::object::Property;
+
 pub type UriValidator = Fn(&url::Url) -> Result<(), error::UriError> + Send + Sync + 'static;
+
+// Interrupts blocking operations of an implementation from another thread. The
+// implementation itself is locked while blocking, so this has to be a separate object.
+pub trait Unlocker: Send + Sync + 'static {
+    fn unlock(&self);
+    fn unlock_stop(&self);
+}
+
+// Handles the properties of an implementation without locking it, so that setting or getting
+// them doesn't have to wait until a blocking operation like a read is finished
+pub trait PropertyHandler<T>: Send + Sync + 'static {
+    fn set_property(&self, element: &T, property: &Property, value: &glib::Value);
+    fn get_property(&self, element: &T, property: &Property) -> Result<glib::Value, ()>;
+
+    // Answers queries that only depend on the properties, like the latency. Returns false
+    // if the query was not handled
+    fn query(&self, _element: &T, _query: &mut gst::QueryRef) -> bool {
+        false
+    }
+}
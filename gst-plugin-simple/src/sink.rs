@@ -26,21 +26,62 @@ pub use gst_plugin::base_sink::BaseSink;
 
 use error::*;
 
-use UriValidator;
+use {Unlocker, UriValidator};
 
 pub trait SinkImpl: Send + 'static {
     fn uri_validator(&self) -> Box<UriValidator>;
 
+    fn unlocker(&self) -> Option<Box<Unlocker>> {
+        None
+    }
+
+    // Used for starting if no URI was set, for sinks that can be configured by properties
+    fn default_uri(&self) -> Option<Url> {
+        None
+    }
+
     fn start(&mut self, sink: &BaseSink, uri: Url) -> Result<(), gst::ErrorMessage>;
     fn stop(&mut self, sink: &BaseSink) -> Result<(), gst::ErrorMessage>;
     fn render(&mut self, sink: &BaseSink, buffer: &gst::BufferRef) -> Result<(), FlowError>;
+
+    fn eos(&mut self, _sink: &BaseSink) -> Result<(), gst::ErrorMessage> {
+        Ok(())
+    }
+
+    // Only called for segments in BYTES format, which can e.g. come from muxers that
+    // want to rewrite headers
+    fn segment(
+        &mut self,
+        _sink: &BaseSink,
+        _segment: &gst::FormattedSegment<gst::format::Bytes>,
+    ) -> Result<(), gst::ErrorMessage> {
+        Ok(())
+    }
+
+    fn is_seekable(&self, _sink: &BaseSink) -> bool {
+        false
+    }
+
+    fn get_position(&self, _sink: &BaseSink) -> Option<u64> {
+        None
+    }
+
+    fn set_property(&mut self, _sink: &BaseSink, _property: &Property, _value: &glib::Value) {
+        unimplemented!()
+    }
+
+    fn get_property(&self, _sink: &BaseSink, _property: &Property) -> Result<glib::Value, ()> {
+        unimplemented!()
+    }
 }
 
 struct Sink {
     cat: gst::DebugCategory,
     uri: Mutex<(Option<Url>, bool)>,
     uri_validator: Box<UriValidator>,
+    unlocker: Option<Box<Unlocker>>,
     imp: Mutex<Box<SinkImpl>>,
+    properties: &'static [Property<'static>],
 }
 
 static PROPERTIES: [Property; 1] = [Property::String(
@@ -63,7 +104,9 @@ impl Sink {
             ),
             uri: Mutex::new((None, false)),
             uri_validator: sink_impl.uri_validator(),
+            unlocker: sink_impl.unlocker(),
             imp: Mutex::new(sink_impl),
+            properties: sink_info.properties,
         }
     }
 
@@ -84,7 +127,13 @@ impl Sink {
         );
         klass.add_pad_template(pad_template);
 
-        klass.install_properties(&PROPERTIES);
+        // The URI property always comes first, followed by the ones of the implementation
+        let properties = PROPERTIES
+            .iter()
+            .chain(sink_info.properties.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        klass.install_properties(&properties);
     }
 
     fn init(element: &BaseSink, sink_info: &SinkInfo) -> Box<BaseSinkImpl<BaseSink>> {
@@ -134,9 +183,17 @@ impl Sink {
 
 impl ObjectImpl<BaseSink> for Sink {
     fn set_property(&self, obj: &glib::Object, id: u32, value: &glib::Value) {
-        let prop = &PROPERTIES[id as usize];
+        let id = id as usize;
 
-        match *prop {
+        if id >= PROPERTIES.len() {
+            let sink = obj.downcast_ref::<BaseSink>().unwrap();
+            let prop = &self.properties[id - PROPERTIES.len()];
+
+            self.imp.lock().unwrap().set_property(sink, prop, value);
+            return;
+        }
+
+        match PROPERTIES[id] {
             Property::String("uri", ..) => {
                 self.set_uri(obj, value.get()).unwrap();
             }
@@ -145,9 +202,16 @@ impl ObjectImpl<BaseSink> for Sink {
     }
 
     fn get_property(&self, obj: &glib::Object, id: u32) -> Result<glib::Value, ()> {
-        let prop = &PROPERTIES[id as usize];
+        let id = id as usize;
+
+        if id >= PROPERTIES.len() {
+            let sink = obj.downcast_ref::<BaseSink>().unwrap();
+            let prop = &self.properties[id - PROPERTIES.len()];
 
-        match *prop {
+            return self.imp.lock().unwrap().get_property(sink, prop);
+        }
+
+        match PROPERTIES[id] {
             Property::String("uri", ..) => Ok(self.get_uri(obj).to_value()),
             _ => unimplemented!(),
         }
@@ -160,20 +224,27 @@ impl BaseSinkImpl<BaseSink> for Sink {
     fn start(&self, sink: &BaseSink) -> bool {
         gst_debug!(self.cat, obj: sink, "Starting");
 
+        let sink_impl = &mut self.imp.lock().unwrap();
+
         // Don't keep the URI locked while we call start later
         let uri = match *self.uri.lock().unwrap() {
             (Some(ref uri), ref mut started) => {
                 *started = true;
                 uri.clone()
             }
-            (None, _) => {
-                gst_error!(self.cat, obj: sink, "No URI given");
-                gst_element_error!(sink, gst::ResourceError::OpenRead, ["No URI given"]);
-                return false;
-            }
+            (None, ref mut started) => match sink_impl.default_uri() {
+                Some(uri) => {
+                    *started = true;
+                    uri
+                }
+                None => {
+                    gst_error!(self.cat, obj: sink, "No URI given");
+                    gst_element_error!(sink, gst::ResourceError::OpenRead, ["No URI given"]);
+                    return false;
+                }
+            },
         };
 
-        let sink_impl = &mut self.imp.lock().unwrap();
         match sink_impl.start(sink, uri) {
             Ok(..) => {
                 gst_trace!(self.cat, obj: sink, "Started successfully");
@@ -228,6 +299,88 @@ impl BaseSinkImpl<BaseSink> for Sink {
             }
         }
     }
+
+    fn event(&self, sink: &BaseSink, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        match event.view() {
+            EventView::Eos(..) => {
+                let sink_impl = &mut self.imp.lock().unwrap();
+
+                gst_debug!(self.cat, obj: sink, "Handling EOS");
+
+                if let Err(ref msg) = sink_impl.eos(sink) {
+                    gst_error!(self.cat, obj: sink, "Failed to handle EOS: {:?}", msg);
+
+                    sink.post_error_message(msg);
+                    return false;
+                }
+            }
+            EventView::Segment(ref e) => {
+                if let Some(segment) = e.get_segment().downcast_ref::<gst::format::Bytes>() {
+                    let sink_impl = &mut self.imp.lock().unwrap();
+
+                    gst_debug!(self.cat, obj: sink, "Handling segment {:?}", segment);
+
+                    if let Err(ref msg) = sink_impl.segment(sink, segment) {
+                        gst_error!(self.cat, obj: sink, "Failed to handle segment: {:?}", msg);
+
+                        sink.post_error_message(msg);
+                        return false;
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        sink.parent_event(event)
+    }
+
+    fn query(&self, sink: &BaseSink, query: &mut gst::QueryRef) -> bool {
+        use gst::QueryView;
+
+        match query.view_mut() {
+            QueryView::Seeking(ref mut q) if q.get_format() == gst::Format::Bytes => {
+                let sink_impl = &self.imp.lock().unwrap();
+                if sink_impl.is_seekable(sink) {
+                    q.set(true, gst::format::Bytes(Some(0)), gst::format::Bytes(None));
+                } else {
+                    q.set(false, gst::format::Bytes(None), gst::format::Bytes(None));
+                }
+                return true;
+            }
+            QueryView::Position(ref mut q) if q.get_format() == gst::Format::Bytes => {
+                let sink_impl = &self.imp.lock().unwrap();
+                if let Some(position) = sink_impl.get_position(sink) {
+                    q.set(gst::format::Bytes(Some(position)));
+                    return true;
+                }
+            }
+            _ => (),
+        }
+
+        BaseSinkBase::parent_query(sink, query)
+    }
+
+    fn unlock(&self, sink: &BaseSink) -> bool {
+        gst_debug!(self.cat, obj: sink, "Unlocking");
+
+        if let Some(ref unlocker) = self.unlocker {
+            unlocker.unlock();
+        }
+
+        true
+    }
+
+    fn unlock_stop(&self, sink: &BaseSink) -> bool {
+        gst_debug!(self.cat, obj: sink, "Stopping unlock");
+
+        if let Some(ref unlocker) = self.unlocker {
+            unlocker.unlock_stop();
+        }
+
+        true
+    }
 }
 
 impl URIHandlerImpl for Sink {
@@ -249,6 +402,7 @@ pub struct SinkInfo {
     pub rank: u32,
     pub create_instance: fn(&BaseSink) -> Box<SinkImpl>,
     pub protocols: Vec<String>,
+    pub properties: &'static [Property<'static>],
 }
 
 struct SinkStatic {
@@ -28,11 +28,40 @@ pub use gst_plugin::base_src::BaseSrc;
 
 use error::*;
 
-use UriValidator;
+use {PropertyHandler, Unlocker, UriValidator};
+
+// Waits for data and answers is_seekable() and get_size() without locking the implementation.
+// The implementation is locked during fill(), so a source waiting there for data, e.g. from a
+// growing file or a pipe, would otherwise block queries from other threads until data arrives
+pub trait StreamHandler: Send + Sync + 'static {
+    // Called before fill() and returns once data is available at the offset
+    fn wait(&self, src: &BaseSrc, offset: u64) -> Result<(), FlowError>;
+    fn is_seekable(&self, src: &BaseSrc) -> bool;
+    fn get_size(&self, src: &BaseSrc) -> Option<u64>;
+}
 
 pub trait SourceImpl: Send + 'static {
     fn uri_validator(&self) -> Box<UriValidator>;
 
+    fn unlocker(&self) -> Option<Box<Unlocker>> {
+        None
+    }
+
+    // If given, the properties are handled by it instead of set_property() and get_property()
+    fn property_handler(&self) -> Option<Box<PropertyHandler<BaseSrc>>> {
+        None
+    }
+
+    // If given, it is used instead of is_seekable() and get_size() and waited on before fill()
+    fn stream_handler(&self) -> Option<Box<StreamHandler>> {
+        None
+    }
+
+    // Used for starting if no URI was set, for sources that can be configured by properties
+    fn default_uri(&self) -> Option<Url> {
+        None
+    }
+
     fn is_seekable(&self, src: &BaseSrc) -> bool;
     fn get_size(&self, src: &BaseSrc) -> Option<u64>;
 
@@ -45,20 +74,41 @@ pub trait SourceImpl: Send + 'static {
         length: u32,
         buffer: &mut gst::BufferRef,
     ) -> Result<(), FlowError>;
+    // Allows providing buffers directly, None lets the base class allocate one for fill
+    fn create(
+        &mut self,
+        _src: &BaseSrc,
+        _offset: u64,
+        _length: u32,
+    ) -> Option<Result<gst::Buffer, FlowError>> {
+        None
+    }
     fn seek(
         &mut self,
         src: &BaseSrc,
         start: u64,
         stop: Option<u64>,
     ) -> Result<(), gst::ErrorMessage>;
+
+    fn set_property(&mut self, _src: &BaseSrc, _property: &Property, _value: &glib::Value) {
+        unimplemented!()
+    }
+
+    fn get_property(&self, _src: &BaseSrc, _property: &Property) -> Result<glib::Value, ()> {
+        unimplemented!()
+    }
 }
 
 struct Source {
     cat: gst::DebugCategory,
     uri: Mutex<(Option<Url>, bool)>,
     uri_validator: Box<UriValidator>,
+    unlocker: Option<Box<Unlocker>>,
+    property_handler: Option<Box<PropertyHandler<BaseSrc>>>,
+    stream_handler: Option<Box<StreamHandler>>,
     imp: Mutex<Box<SourceImpl>>,
     push_only: bool,
+    properties: &'static [Property<'static>],
 }
 
 static PROPERTIES: [Property; 1] = [Property::String(
@@ -81,8 +131,12 @@ impl Source {
             ),
             uri: Mutex::new((None, false)),
             uri_validator: source_impl.uri_validator(),
+            unlocker: source_impl.unlocker(),
+            property_handler: source_impl.property_handler(),
+            stream_handler: source_impl.stream_handler(),
             imp: Mutex::new(source_impl),
             push_only: source_info.push_only,
+            properties: source_info.properties,
         }
     }
 
@@ -103,7 +157,13 @@ impl Source {
         );
         klass.add_pad_template(pad_template);
 
-        klass.install_properties(&PROPERTIES);
+        // The URI property always comes first, followed by the ones of the implementation
+        let properties = PROPERTIES
+            .iter()
+            .chain(source_info.properties.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        klass.install_properties(&properties);
     }
 
     fn init(element: &BaseSrc, source_info: &SourceInfo) -> Box<BaseSrcImpl<BaseSrc>> {
@@ -153,9 +213,20 @@ impl Source {
 
 impl ObjectImpl<BaseSrc> for Source {
     fn set_property(&self, obj: &glib::Object, id: u32, value: &glib::Value) {
-        let prop = &PROPERTIES[id as usize];
+        let id = id as usize;
+
+        if id >= PROPERTIES.len() {
+            let src = obj.downcast_ref::<BaseSrc>().unwrap();
+            let prop = &self.properties[id - PROPERTIES.len()];
 
-        match *prop {
+            match self.property_handler {
+                Some(ref handler) => handler.set_property(src, prop, value),
+                None => self.imp.lock().unwrap().set_property(src, prop, value),
+            }
+            return;
+        }
+
+        match PROPERTIES[id] {
             Property::String("uri", ..) => {
                 self.set_uri(obj, value.get()).unwrap();
             }
@@ -164,9 +235,19 @@ impl ObjectImpl<BaseSrc> for Source {
     }
 
     fn get_property(&self, obj: &glib::Object, id: u32) -> Result<glib::Value, ()> {
-        let prop = &PROPERTIES[id as usize];
+        let id = id as usize;
 
-        match *prop {
+        if id >= PROPERTIES.len() {
+            let src = obj.downcast_ref::<BaseSrc>().unwrap();
+            let prop = &self.properties[id - PROPERTIES.len()];
+
+            return match self.property_handler {
+                Some(ref handler) => handler.get_property(src, prop),
+                None => self.imp.lock().unwrap().get_property(src, prop),
+            };
+        }
+
+        match PROPERTIES[id] {
             Property::String("uri", ..) => Ok(self.get_uri(obj).to_value()),
             _ => unimplemented!(),
         }
@@ -179,20 +260,27 @@ impl BaseSrcImpl<BaseSrc> for Source {
     fn start(&self, src: &BaseSrc) -> bool {
         gst_debug!(self.cat, obj: src, "Starting");
 
+        let source_impl = &mut self.imp.lock().unwrap();
+
         // Don't keep the URI locked while we call start later
         let uri = match *self.uri.lock().unwrap() {
             (Some(ref uri), ref mut started) => {
                 *started = true;
                 uri.clone()
             }
-            (None, _) => {
-                gst_error!(self.cat, obj: src, "No URI given");
-                gst_element_error!(src, gst::ResourceError::OpenRead, ["No URI given"]);
-                return false;
-            }
+            (None, ref mut started) => match source_impl.default_uri() {
+                Some(uri) => {
+                    *started = true;
+                    uri
+                }
+                None => {
+                    gst_error!(self.cat, obj: src, "No URI given");
+                    gst_element_error!(src, gst::ResourceError::OpenRead, ["No URI given"]);
+                    return false;
+                }
+            },
         };
 
-        let source_impl = &mut self.imp.lock().unwrap();
         match source_impl.start(src, uri) {
             Ok(..) => {
                 gst_trace!(self.cat, obj: src, "Started successfully");
@@ -240,6 +328,12 @@ impl BaseSrcImpl<BaseSrc> for Source {
             _ => (),
         }
 
+        if let Some(ref handler) = self.property_handler {
+            if handler.query(src, query) {
+                return true;
+            }
+        }
+
         BaseSrcBase::parent_query(src, query)
     }
 
@@ -250,8 +344,6 @@ impl BaseSrcImpl<BaseSrc> for Source {
         length: u32,
         buffer: &mut gst::BufferRef,
     ) -> gst::FlowReturn {
-        let source_impl = &mut self.imp.lock().unwrap();
-
         gst_trace!(
             self.cat,
             obj: src,
@@ -261,7 +353,18 @@ impl BaseSrcImpl<BaseSrc> for Source {
             length
         );
 
-        match source_impl.fill(src, offset, length, buffer) {
+        // Waiting happens without the implementation lock
+        let res = match self.stream_handler {
+            Some(ref handler) => handler.wait(src, offset),
+            None => Ok(()),
+        };
+
+        let res = res.and_then(|_| {
+            let source_impl = &mut self.imp.lock().unwrap();
+            source_impl.fill(src, offset, length, buffer)
+        });
+
+        match res {
             Ok(()) => gst::FlowReturn::Ok,
             Err(flow_error) => {
                 gst_error!(self.cat, obj: src, "Failed to fill: {:?}", flow_error);
@@ -276,6 +379,37 @@ impl BaseSrcImpl<BaseSrc> for Source {
         }
     }
 
+    fn create(
+        &self,
+        src: &BaseSrc,
+        offset: u64,
+        length: u32,
+    ) -> Result<gst::Buffer, gst::FlowReturn> {
+        // Not locked anymore when the base class calls fill
+        let res = {
+            let source_impl = &mut self.imp.lock().unwrap();
+            source_impl.create(src, offset, length)
+        };
+
+        match res {
+            None => src.parent_create(offset, length),
+            Some(Ok(buffer)) => {
+                gst_trace!(self.cat, obj: src, "Created buffer {:?}", buffer);
+                Ok(buffer)
+            }
+            Some(Err(flow_error)) => {
+                gst_error!(self.cat, obj: src, "Failed to create: {:?}", flow_error);
+                match flow_error {
+                    FlowError::NotNegotiated(ref msg) | FlowError::Error(ref msg) => {
+                        src.post_error_message(msg);
+                    }
+                    _ => (),
+                }
+                Err(flow_error.into())
+            }
+        }
+    }
+
     fn do_seek(&self, src: &BaseSrc, segment: &mut gst::Segment) -> bool {
         let source_impl = &mut self.imp.lock().unwrap();
 
@@ -303,14 +437,43 @@ impl BaseSrcImpl<BaseSrc> for Source {
     }
 
     fn is_seekable(&self, src: &BaseSrc) -> bool {
+        if let Some(ref handler) = self.stream_handler {
+            return handler.is_seekable(src);
+        }
+
         let source_impl = &self.imp.lock().unwrap();
         source_impl.is_seekable(src)
     }
 
     fn get_size(&self, src: &BaseSrc) -> Option<u64> {
+        if let Some(ref handler) = self.stream_handler {
+            return handler.get_size(src);
+        }
+
         let source_impl = &self.imp.lock().unwrap();
         source_impl.get_size(src)
     }
+
+    // Called without the implementation lock, which is held by the blocked thread
+    fn unlock(&self, src: &BaseSrc) -> bool {
+        gst_debug!(self.cat, obj: src, "Unlocking");
+
+        if let Some(ref unlocker) = self.unlocker {
+            unlocker.unlock();
+        }
+
+        true
+    }
+
+    fn unlock_stop(&self, src: &BaseSrc) -> bool {
+        gst_debug!(self.cat, obj: src, "Stopping unlock");
+
+        if let Some(ref unlocker) = self.unlocker {
+            unlocker.unlock_stop();
+        }
+
+        true
+    }
 }
 
 impl URIHandlerImpl for Source {
@@ -333,6 +496,7 @@ pub struct SourceInfo {
     pub create_instance: fn(&BaseSrc) -> Box<SourceImpl>,
     pub protocols: Vec<String>,
     pub push_only: bool,
+    pub properties: &'static [Property<'static>],
 }
 
 struct SourceStatic {
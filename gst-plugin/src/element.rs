@@ -36,15 +36,17 @@ where
 
     fn request_new_pad(
         &self,
-        _element: &T,
-        _templ: &gst::PadTemplate,
-        _name: Option<String>,
-        _caps: Option<&gst::CapsRef>,
+        element: &T,
+        templ: &gst::PadTemplate,
+        name: Option<String>,
+        caps: Option<&gst::CapsRef>,
     ) -> Option<gst::Pad> {
-        None
+        element.parent_request_new_pad(templ, name, caps)
     }
 
-    fn release_pad(&self, _element: &T, _pad: &gst::Pad) {}
+    fn release_pad(&self, element: &T, pad: &gst::Pad) {
+        element.parent_release_pad(pad)
+    }
 
     fn send_event(&self, element: &T, event: gst::Event) -> bool {
         element.parent_send_event(event)
@@ -103,6 +105,40 @@ where
         }
     }
 
+    fn parent_request_new_pad(
+        &self,
+        templ: &gst::PadTemplate,
+        name: Option<String>,
+        caps: Option<&gst::CapsRef>,
+    ) -> Option<gst::Pad> {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass = (*klass).get_parent_class() as *const gst_ffi::GstElementClass;
+            (*parent_klass)
+                .request_new_pad
+                .map(|f| {
+                    from_glib_none(f(
+                        self.to_glib_none().0,
+                        templ.to_glib_none().0,
+                        name.to_glib_none().0,
+                        caps.map(|caps| caps.as_ptr()).unwrap_or(ptr::null()),
+                    ))
+                })
+                .unwrap_or(None)
+        }
+    }
+
+    fn parent_release_pad(&self, pad: &gst::Pad) {
+        unsafe {
+            let klass = self.get_class();
+            let parent_klass = (*klass).get_parent_class() as *const gst_ffi::GstElementClass;
+            (*parent_klass)
+                .release_pad
+                .map(|f| f(self.to_glib_none().0, pad.to_glib_none().0))
+                .unwrap_or(())
+        }
+    }
+
     fn parent_send_event(&self, event: gst::Event) -> bool {
         unsafe {
             let klass = self.get_class();